@@ -0,0 +1,249 @@
+//! Generates a function for each template below, along with the template's tree so that the tests
+//! can compare the function's output with the tree-walker's.
+
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+use ramon_templates::{Generator, Node, Operator, OwnedValue, Parser};
+
+#[path = "src/environment.rs"]
+mod environment;
+
+const TEMPLATES: &[&str] = &[
+    "Hello {{ world }}!",
+    "{{ 1 + a * (1 + 2) }} {{ a / b - 1 }} {{ -a }} {{ !a }} {{ 0.1 + 0.2 }} {{ -(-a) }}",
+    "{{ host?.port ?? 80 }} {{ host?.name }} {{ missing?.x }} {{ xs?[1] }} {{ xs?[99] ?? 'none' }}",
+    "{{ missing ?? 'default' }} {{ host.name ?? 'x' }} {{ nothing ?? 'null' }} {{ nothing?.x }}",
+    "{{ if a is defined && missing is undefined }}yes{{ end }}",
+    "{{ if 9 is divisibleby(3) }}fizz{{ else }}no{{ end }} {{ missing is divisibleby(3) }}",
+    "{{ nothing is defined }} {{ host.name is defined }} {{ host?.port is defined }}",
+    "{{ if 'web' in names }}in{{ end }}{{ if 'x' not in names }} out{{ end }}",
+    "{{ if a == 1 }}one{{ elif a != 8.2 }}other{{ else }}eight{{ end }}",
+    "{{ a && b }} {{ a && 0 }} {{ 0 || b }} {{ 0 && missing }}",
+    "{{ for x in xs if x != 0 ', ' }}{{ if x == 9 }}{{ break }}{{/if}}{{ x }}{{/for}}",
+    "{{ for x in xs ', ' }}{{ if x == 2 }}{{ continue }}{{ end }}[{{ x }}]{{ end }}",
+    "{{ for x in xs '-' }}a{{ if x == 3 }}b{{ continue }}{{ end }}{{ end }}",
+    "{{ for x in xs if x != 1 f'({x})' }}{{ if x == 9 }}{{ break }}{{ end }}{{ x }}{{ end }}",
+    "{{ for x in xs }}{{ for y in 0..3 ',' }}{{ if y == x }}{{ break }}{{ end }}{{ y }}{{ end }};{{ end }}",
+    "{{ for x in [] ', ' }}{{ x }}{{ end }}|{{ for x in xs ', ' }}{{ end }}|{{ for x in xs }}{{ continue }}{{ end }}|",
+    "{{ for x in xs }}{{ if x == 0 }}{{ else }}{{ if x == 9 }}{{ break }}{{ end }}{{ x }}{{ end }}{{ end }}",
+    "{{ for i in 0..5 }}{{ i }}{{ end }} {{ range(10, 0, -2) }} {{ for i in 0..=2 ' ' }}{{ i }}{{ end }}",
+    "{{ xs[1..=3] }} {{ xs[:2] }} {{ xs[-2:] }} {{ xs[::2] }} {{ (0..10)[2:5] }} {{ world[1:null] }}",
+    "{{ [...xs, 5] }} {{ [h.name for h in hosts if h.down] }} {{ [...0..3] }} {{ [] }}",
+    "{{ [[x + y for y in 0..2] for x in xs if x != 0] }}",
+    r#"{{ f"host {world}:{a}" }} {{ f'{xs}' }}"#,
+    "{{ map(xs, x => x * 2) }} {{ reduce(xs, 0, (sum, x) => sum + x) }} {{ filter(xs, x => x != 0) }}",
+    "{{ for y in [1, 2] }}{{ map(xs, x => x + y) }}{{ end }}",
+    "{{ [map([x], y => y + x) for x in xs] }} {{ map(xs, x => map([x], y => [x, y])) }}",
+    "{{ 2 ** 3 ** 2 }} {{ 7 mod 3 }} {{ ~world }}",
+    "Hello {{ pad(world, width = 10, fill = '-') }} {{ pad(a) }}",
+    "{{ xs[0] }}{{ hosts[1].name }} {{ host }} {{ hosts }} {{ nothing }}",
+    "{{ 'quote \" and \\\\ and \\n' }} héllo ✓\n\t{{ \"{{ }}\" }}",
+    "{{ undefined }}",
+    "{{ host.missing }}",
+    "{{ for x in 1 }}{{ end }}",
+    "{{ 1 + 'a' }}",
+    "{{ xs[99] }}",
+    "{{ unknown(1) }}",
+    "{{ 1 is unknown }}",
+    "{{ map(1, x => x) }}",
+    "before {{ for x in xs }}{{ x }}{{ if x == 9 }}{{ oops }}{{ end }}{{ end }}",
+    "{{ (0..1000000000000)[:3] }} {{ (0..1000000000000)[:] }}",
+    "{{ [...0..1000000000000] }}",
+    "{{ for x in xs 0..1000000000000000 }}{{ x }}{{ end }}",
+];
+
+/// Templates that are optimized first, so that their trees hold constants the parser never
+/// produces, like ranges and arrays.
+const OPTIMIZED: &[&str] = &[
+    "{{ 0..3 }} {{ [1, 'a', [null]] }} {{ for i in 0..3 }}{{ i }}{{ end }} {{ if 1 }}yes{{ end }}",
+    "{{ [...(0..=2), 3] }} {{ (0..10)[::3] }} {{ 1 / 0 }} {{ -1 / 0 }}",
+    "before {{ 0..1000000000000000 }}",
+];
+
+fn main() {
+    let environment = environment::environment();
+
+    let mut templates = Vec::new();
+    for input in TEMPLATES {
+        let template = Parser::parse_input_with(input, &environment).unwrap();
+        templates.push((input.to_string(), template));
+    }
+    // Chains and `elif`s that are much taller than templates may nest.
+    let ladder = (1..200)
+        .map(|n| format!("{{{{ elif b == {n} }}}}{n}"))
+        .collect::<String>();
+    let tall = [
+        format!("{{{{ a{} }}}}", " + 1".repeat(200)),
+        format!("{{{{ {}a }}}}", "missing?.x ?? ".repeat(200)),
+        format!("{{{{ {}xs }}}}", "xs is defined && ".repeat(200)),
+        format!("{{{{ if b == 0 }}}}0{ladder}{{{{ end }}}}"),
+    ];
+    for input in tall {
+        let template = Parser::parse_input_with(&input, &environment).unwrap();
+        templates.push((input, template));
+    }
+    for input in OPTIMIZED {
+        let template = Parser::parse_input_with(input, &environment).unwrap();
+        templates.push((format!("optimized {input}"), template.optimize()));
+    }
+    templates.extend(built());
+
+    let mut source = String::from("pub static TEMPLATES: &[(&str, &[u8], Render)] = &[\n");
+    let mut functions = String::new();
+    for (i, (name, template)) in templates.iter().enumerate() {
+        functions += &Generator::new(format!("render_{i}")).generate(template);
+        source += &format!(
+            "    ({name:?}, &{:?}, render_{i}::<Variables, String>),\n",
+            template.to_bytes()
+        );
+    }
+    source += "];\n\n";
+    source += &functions;
+    let path = Path::new(&env::var_os("OUT_DIR").unwrap()).join("templates.rs");
+    fs::write(path, source).unwrap();
+    println!("cargo::rerun-if-changed=build.rs");
+    println!("cargo::rerun-if-changed=src/environment.rs");
+}
+
+/// Trees that the parser doesn't produce, but that can be built by hand.
+fn built() -> Vec<(String, Node)> {
+    let text = |text: &str| Node::Value(OwnedValue::String(text.into()));
+    let variable = |name: &str| Box::new(Node::Variable(name.into()));
+    let is = |name: &str, value: f64| {
+        Box::new(Node::Operation(
+            variable(name),
+            Operator::IsEqualTo,
+            Box::new(Node::Value(OwnedValue::Number(value))),
+        ))
+    };
+    vec![
+        (
+            "break in the filter of an inner loop".into(),
+            Node::Body(vec![Node::ForIn(
+                "x".into(),
+                variable("xs"),
+                Box::new(Node::Body(vec![
+                    text("<"),
+                    Node::ForIn(
+                        "y".into(),
+                        Box::new(Node::Value(OwnedValue::Array(vec![
+                            OwnedValue::Number(1.0),
+                            OwnedValue::Number(3.0),
+                        ]))),
+                        Box::new(Node::Variable("y".into())),
+                        None,
+                        Some(Box::new(Node::IfThenElse(
+                            is("y", 3.0),
+                            Box::new(Node::Break),
+                            Some(Box::new(Node::Value(OwnedValue::Boolean(true)))),
+                        ))),
+                    ),
+                    text(">"),
+                ])),
+                Some(Box::new(text(", "))),
+                None,
+            )]),
+        ),
+        (
+            "break in a body evaluated as a value".into(),
+            Node::Body(vec![Node::ForIn(
+                "x".into(),
+                variable("xs"),
+                Box::new(Node::Body(vec![
+                    text("["),
+                    Node::Concat(vec![
+                        text("dropped"),
+                        Node::Body(vec![
+                            text("kept"),
+                            Node::Variable("x".into()),
+                            Node::IfThenElse(
+                                is("x", 2.0),
+                                Box::new(Node::Body(vec![text("!"), Node::Continue])),
+                                Some(Box::new(Node::IfThenElse(
+                                    is("x", 9.0),
+                                    Box::new(Node::Break),
+                                    None,
+                                ))),
+                            ),
+                        ]),
+                    ]),
+                    text("]"),
+                ])),
+                Some(Box::new(text(" "))),
+                None,
+            )]),
+        ),
+        (
+            "loop evaluated as a value".into(),
+            Node::Body(vec![Node::Concat(vec![
+                text("<"),
+                Node::ForIn(
+                    "x".into(),
+                    variable("xs"),
+                    variable("x"),
+                    Some(Box::new(text(","))),
+                    None,
+                ),
+                text(">"),
+            ])]),
+        ),
+        (
+            "constants".into(),
+            Node::Body(vec![
+                Node::Value(OwnedValue::Object(BTreeMap::from([
+                    ("b".into(), OwnedValue::Number(f64::INFINITY)),
+                    ("a".into(), OwnedValue::Number(f64::NAN)),
+                ]))),
+                Node::Value(OwnedValue::Number(-0.0)),
+                Node::Value(OwnedValue::Number(f64::NEG_INFINITY)),
+                Node::Value(OwnedValue::Null),
+            ]),
+        ),
+        (
+            "string root".into(),
+            Node::Concat(vec![text("a"), Node::Variable("a".into())]),
+        ),
+        ("number root".into(), Node::Value(OwnedValue::Number(1.0))),
+        (
+            "if root".into(),
+            Node::IfThenElse(variable("a"), Box::new(text("yes")), None),
+        ),
+        (
+            "loop root".into(),
+            Node::ForIn("x".into(), variable("xs"), variable("x"), None, None),
+        ),
+        (
+            "break outside of a loop".into(),
+            Node::Body(vec![text("a"), Node::Break]),
+        ),
+        (
+            "break in a lambda".into(),
+            Node::Body(vec![Node::ForIn(
+                "x".into(),
+                variable("xs"),
+                Box::new(Node::FunctionCall(
+                    "map".into(),
+                    vec![
+                        Node::Variable("xs".into()),
+                        Node::Lambda(vec!["y".into()], Box::new(Node::Break)),
+                    ],
+                    Vec::new(),
+                )),
+                None,
+                None,
+            )]),
+        ),
+        (
+            "syntax error".into(),
+            Node::Body(vec![text("a"), Node::Error]),
+        ),
+        (
+            "spread outside of an array".into(),
+            Node::Body(vec![Node::Negate(Box::new(Node::Spread(variable("xs"))))]),
+        ),
+        (
+            "lambda outside of a call".into(),
+            Node::Body(vec![Node::Lambda(Vec::new(), variable("a"))]),
+        ),
+    ]
+}
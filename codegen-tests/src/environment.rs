@@ -0,0 +1,116 @@
+//! The environment that the tests render templates with: a few host functions, a test, and custom
+//! operators. The build script parses the generated templates with it too, and the integration
+//! tests of `ramon_templates` include this file, so every test suite shares one copy.
+
+use ramon_templates::{
+    precedence, Arguments, Associativity, Environment, OwnedValue, Value, ValueError,
+};
+
+pub fn environment() -> Environment {
+    let mut environment = Environment::new();
+    environment.add_function("pad", pad);
+    environment.add_function("map", map);
+    environment.add_function("filter", filter);
+    environment.add_function("reduce", reduce);
+    environment.add_test("prime", |value, _| {
+        let n = Value::Borrowed(value).unwrap_f64()?;
+        Ok(n >= 2.0
+            && (2..)
+                .take_while(|i| (i * i) as f64 <= n)
+                .all(|i| n % i as f64 != 0.0))
+    });
+    environment.add_infix_operator(
+        "**",
+        precedence::PREFIX + 5,
+        Associativity::Right,
+        |lhs, rhs| match (lhs, rhs) {
+            (OwnedValue::Number(lhs), OwnedValue::Number(rhs)) => {
+                Ok(OwnedValue::Number(lhs.powf(*rhs)))
+            }
+            _ => Err(ValueError::OperationError("Invalid exponentiation".into())),
+        },
+    );
+    environment.add_infix_operator(
+        "mod",
+        precedence::PRODUCT,
+        Associativity::Left,
+        |lhs, rhs| match (lhs, rhs) {
+            (OwnedValue::Number(lhs), OwnedValue::Number(rhs)) => {
+                Ok(OwnedValue::Number(lhs.rem_euclid(*rhs)))
+            }
+            _ => Err(ValueError::OperationError("Invalid modulo".into())),
+        },
+    );
+    environment.add_prefix_operator("~", precedence::PREFIX, |value| match value {
+        OwnedValue::String(string) => Ok(OwnedValue::String(string.chars().rev().collect())),
+        _ => Err(ValueError::OperationError("Invalid reversal".into())),
+    });
+    environment
+}
+
+/// `pad(value, width = 10, fill = " ")`
+fn pad(args: Arguments) -> Result<OwnedValue, ValueError> {
+    let value = args.get(0).unwrap().inner().to_string();
+    let width = match args.get_named("width").map(|width| width.inner()) {
+        Some(OwnedValue::Number(width)) => *width as usize,
+        _ => 10,
+    };
+    let fill = match args.get_named("fill").map(|fill| fill.inner()) {
+        Some(OwnedValue::String(fill)) => fill.clone(),
+        _ => " ".into(),
+    };
+    let padding = width.saturating_sub(value.chars().count());
+    Ok(OwnedValue::String(fill.repeat(padding) + &value))
+}
+
+/// `map(array, x => ...)`
+fn map(args: Arguments) -> Result<OwnedValue, ValueError> {
+    let (Some(OwnedValue::Array(items)), Some(lambda)) = (
+        args.get(0).map(Value::inner),
+        args.get(1).and_then(Value::as_lambda),
+    ) else {
+        return Err(ValueError::OperationError(
+            "Invalid arguments to map".into(),
+        ));
+    };
+    let items = items
+        .iter()
+        .map(|item| lambda.call(vec![item.into()]))
+        .collect::<Result<_, _>>()?;
+    Ok(OwnedValue::Array(items))
+}
+
+/// `reduce(array, initial, (accumulator, x) => ...)`
+fn reduce(args: Arguments) -> Result<OwnedValue, ValueError> {
+    let (Some(OwnedValue::Array(items)), Some(lambda)) = (
+        args.get(0).map(Value::inner),
+        args.get(2).and_then(Value::as_lambda),
+    ) else {
+        return Err(ValueError::OperationError(
+            "Invalid arguments to reduce".into(),
+        ));
+    };
+    let initial = args.get(1).map_or(OwnedValue::Null, Value::to_owned_value);
+    items.iter().try_fold(initial, |accumulator, item| {
+        lambda.call(vec![accumulator.into(), item.into()])
+    })
+}
+
+/// `filter(array, x => ...)`
+fn filter(args: Arguments) -> Result<OwnedValue, ValueError> {
+    let (Some(OwnedValue::Array(items)), Some(lambda)) = (
+        args.get(0).map(Value::inner),
+        args.get(1).and_then(Value::as_lambda),
+    ) else {
+        return Err(ValueError::OperationError(
+            "Invalid arguments to filter".into(),
+        ));
+    };
+    let mut kept = Vec::new();
+    for item in items {
+        if lambda.call(vec![item.into()])?.is_truthy() {
+            kept.push(item.clone());
+        }
+    }
+    Ok(OwnedValue::Array(kept))
+}
@@ -0,0 +1,15 @@
+//! Functions generated by [`ramon_templates::Generator`] in the build script.
+
+mod environment;
+
+use std::collections::HashMap;
+
+use ramon_templates::{Environment, OwnedValue, ValueError};
+
+pub use environment::environment;
+
+pub type Variables = HashMap<String, OwnedValue>;
+
+pub type Render = fn(&Variables, &Environment, &mut String) -> Result<(), ValueError>;
+
+include!(concat!(env!("OUT_DIR"), "/templates.rs"));
@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, fmt};
+
+use ramon_templates::{Environment, Generator, Node, Operator, OwnedValue, ValueError};
+use ramon_templates_codegen_tests::{environment, Variables, TEMPLATES};
+
+fn variables() -> Variables {
+    let host = |name: &str, down| {
+        OwnedValue::Object(BTreeMap::from([
+            ("name".into(), OwnedValue::String(name.into())),
+            ("down".into(), OwnedValue::Boolean(down)),
+        ]))
+    };
+    let numbers = |numbers: &[f64]| {
+        OwnedValue::Array(numbers.iter().copied().map(OwnedValue::Number).collect())
+    };
+    Variables::from([
+        ("a".into(), OwnedValue::Number(8.2)),
+        ("b".into(), OwnedValue::Number(16.0)),
+        ("world".into(), OwnedValue::String("world".into())),
+        ("nothing".into(), OwnedValue::Null),
+        ("xs".into(), numbers(&[1.0, 2.0, 3.0, 0.0, 9.0, 4.0])),
+        ("host".into(), host("web", true)),
+        (
+            "hosts".into(),
+            OwnedValue::Array(vec![host("web", true), host("db", false)]),
+        ),
+        (
+            "names".into(),
+            OwnedValue::Array(vec![OwnedValue::String("web".into())]),
+        ),
+    ])
+}
+
+fn render(
+    render: ramon_templates_codegen_tests::Render,
+    variables: &Variables,
+    environment: &Environment,
+) -> Result<String, String> {
+    let mut output = String::new();
+    match render(variables, environment, &mut output) {
+        Ok(()) => Ok(output),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[test]
+fn generated_functions_render_like_the_tree_walker() {
+    let (variables, environment) = (variables(), environment());
+    for (name, bytes, function) in TEMPLATES {
+        let template = Node::from_bytes(bytes).unwrap();
+        let expected = template
+            .evaluate(&variables, &environment)
+            .map_err(|err| err.to_string());
+        assert_eq!(
+            render(*function, &variables, &environment),
+            expected,
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn streaming_renders_like_the_tree_walker() {
+    let (variables, environment) = (variables(), environment());
+    for (name, bytes, _) in TEMPLATES {
+        let template = Node::from_bytes(bytes).unwrap();
+        let expected = template
+            .evaluate(&variables, &environment)
+            .map_err(|err| err.to_string());
+        let mut output = String::new();
+        let result = template.render_to(&variables, &environment, &mut output);
+        assert_eq!(
+            result.map(|()| output).map_err(|err| err.to_string()),
+            expected,
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn generated_functions_check_the_nesting_limit() {
+    let (variables, mut environment) = (variables(), environment());
+    environment.set_max_depth(2);
+    let (_, _, function) = TEMPLATES[1];
+    assert_eq!(
+        render(function, &variables, &environment),
+        Err(ValueError::NestingTooDeep(2).to_string())
+    );
+}
+
+/// The parser limits how tall a chain gets, but a tree built by hand or decoded from a cache can
+/// be much taller.
+#[test]
+fn generating_a_tall_tree_built_by_hand() {
+    let one = || Box::new(Node::Value(OwnedValue::Number(1.0)));
+    let sum = (0..20_000).fold(Node::Value(OwnedValue::Number(0.0)), |sum, _| {
+        Node::Operation(Box::new(sum), Operator::Add, one())
+    });
+    let source = Generator::new("render_sum").generate(&sum);
+    assert!(source.contains("rt::check_depth(environment, 1)?;"));
+}
+
+#[test]
+fn generated_functions_report_write_errors() {
+    struct Full(usize);
+
+    impl fmt::Write for Full {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 = self.0.checked_sub(s.len()).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+
+    let (variables, environment) = (variables(), environment());
+    let mut out = Full(6);
+    let result = ramon_templates_codegen_tests::render_0(&variables, &environment, &mut out);
+    assert!(matches!(result, Err(ValueError::WriteError(_))));
+}
+
+/// The parser rejects `break` in a filter, but a tree built by hand can have one. It ends the
+/// outer loop and drops what the inner loop rendered.
+#[test]
+fn break_in_a_filter_ends_the_outer_loop() {
+    let (variables, environment) = (variables(), environment());
+    let (_, bytes, function) = TEMPLATES
+        .iter()
+        .find(|(name, ..)| *name == "break in the filter of an inner loop")
+        .unwrap();
+    let template = Node::from_bytes(bytes).unwrap();
+    let mut output = String::new();
+    template
+        .render_to(&variables, &environment, &mut output)
+        .unwrap();
+    assert_eq!(output, "<");
+    assert_eq!(render(*function, &variables, &environment).unwrap(), "<");
+    assert_eq!(template.evaluate(&variables, &environment).unwrap(), "<");
+}
@@ -0,0 +1,57 @@
+#![no_main]
+
+use std::collections::{BTreeMap, HashMap};
+
+use libfuzzer_sys::fuzz_target;
+use ramon_templates::{Environment, Node, OwnedValue, Parser};
+
+// Feeds arbitrary input to every entry point that can be given untrusted input: the parsers, the
+// cache decoder, and every way of rendering. Any panic is a bug; errors are fine as long as every
+// backend reports the same one.
+fuzz_target!(|data: &[u8]| {
+    let _ = Node::from_bytes(data);
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    // Partial trees render too, and fail where the syntax errors were.
+    let (recovered, _) = Parser::parse_input_recovering(input);
+    let _ = render(&recovered);
+    let (Ok(template), Ok(optimized)) = (Parser::parse_input(input), Parser::parse_input(input))
+    else {
+        return;
+    };
+    assert_eq!(render(&optimized.optimize()), render(&template));
+    let cached = Node::from_bytes(&template.to_bytes()).expect("caches decode");
+    assert_eq!(cached, template);
+});
+
+/// Renders a template with the tree-walker, compiled, and streamed, and returns what they agree on.
+fn render(template: &Node) -> Result<String, String> {
+    let vars = HashMap::from([
+        ("x".to_owned(), OwnedValue::Number(2.0)),
+        (
+            "xs".to_owned(),
+            OwnedValue::Array(vec![OwnedValue::Number(1.0), OwnedValue::Null]),
+        ),
+        (
+            "host".to_owned(),
+            OwnedValue::Object(BTreeMap::from([(
+                "name".to_owned(),
+                OwnedValue::String("web".into()),
+            )])),
+        ),
+    ]);
+    let environment = Environment::new();
+    let evaluation = template
+        .evaluate(&vars, &environment)
+        .map_err(|err| err.to_string());
+    let program = template.compile().evaluate(&vars, &environment);
+    assert_eq!(program.map_err(|err| err.to_string()), evaluation);
+    let mut out = String::new();
+    let streamed = template.render_to(&vars, &environment, &mut out);
+    assert_eq!(
+        streamed.map(|()| out).map_err(|err| err.to_string()),
+        evaluation
+    );
+    evaluation
+}
@@ -0,0 +1,91 @@
+//! Compile-time checked templates for [`ramon_templates`].
+//!
+//! [`template!`] and [`include_template!`] parse a template while the crate that uses them is
+//! being compiled. A syntax error becomes a compile error pointing at the template's literal, and
+//! a template that parses becomes code that builds its tree. Nothing is parsed at runtime, but the
+//! tree is still allocated there: each macro call expands to a `static`
+//! [`LazyLock`](std::sync::LazyLock) that builds the tree the first time it is used and keeps it
+//! for the rest of the program:
+//!
+//! ```
+//! use ramon_templates::{Environment, Node, OwnedValue};
+//! use ramon_templates_macros::template;
+//! use std::collections::HashMap;
+//!
+//! let greeting: &'static Node = template!("Hello {{ name }}!");
+//! let vars = HashMap::from([("name".to_owned(), OwnedValue::String("world".into()))]);
+//! let output = greeting.evaluate(&vars, &Environment::new()).unwrap();
+//! assert_eq!(output, "Hello world!");
+//! ```
+//!
+//! ```compile_fail
+//! // error: Unexpected end of input, expected `}}`
+//! let broken = ramon_templates_macros::template!("Hello {{ name");
+//! ```
+//!
+//! Templates are parsed with [`Parser::parse_input`], so they can't use the operators of an
+//! [`Environment`](ramon_templates::Environment), which only exists at runtime.
+
+mod tokens;
+
+use std::{env, fs, path::PathBuf};
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use ramon_templates::Parser;
+use syn::{parse_macro_input, LitStr};
+
+/// Parses a template literal at compile time and evaluates to a `&'static Node`.
+///
+/// The tree is built the first time the expression is evaluated and shared after that.
+#[proc_macro]
+pub fn template(input: TokenStream) -> TokenStream {
+    let literal = parse_macro_input!(input as LitStr);
+    expand(&literal.value(), literal.span(), None)
+}
+
+/// Reads a template from a file at compile time and evaluates to a `&'static Node`, like
+/// [`template!`].
+///
+/// The path is relative to the directory containing the crate's `Cargo.toml`. The crate is rebuilt
+/// when the file changes.
+#[proc_macro]
+pub fn include_template(input: TokenStream) -> TokenStream {
+    let literal = parse_macro_input!(input as LitStr);
+    let mut path = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default());
+    path.push(literal.value());
+    let input = match fs::read_to_string(&path) {
+        Ok(input) => input,
+        Err(err) => {
+            let message = format!("Cannot read {}: {err}", path.display());
+            return syn::Error::new(literal.span(), message)
+                .to_compile_error()
+                .into();
+        }
+    };
+    expand(&input, literal.span(), Some(path))
+}
+
+/// Expands to a block that builds the template's tree once, or to a compile error at `span`.
+/// `path` is the file the template was read from, which the block includes so that Cargo tracks
+/// it.
+fn expand(input: &str, span: Span, path: Option<PathBuf>) -> TokenStream {
+    let node = match Parser::parse_input(input) {
+        Ok(node) => tokens::node(&node),
+        Err(err) => return syn::Error::new(span, err).to_compile_error().into(),
+    };
+    let dependency = path.map(|path| {
+        let path = path.to_string_lossy();
+        quote!(
+            const _: &str = ::core::include_str!(#path);
+        )
+    });
+    quote! {{
+        #dependency
+        static TEMPLATE: ::std::sync::LazyLock<::ramon_templates::Node> =
+            ::std::sync::LazyLock::new(|| #node);
+        &*TEMPLATE
+    }}
+    .into()
+}
@@ -0,0 +1,210 @@
+//! Converts a parsed tree into the Rust expression that builds it.
+
+use proc_macro2::{Literal, TokenStream};
+use quote::quote;
+use ramon_templates::{Node, Operator, OwnedValue};
+
+pub fn node(node: &Node) -> TokenStream {
+    let node = match node {
+        Node::Body(nodes) => {
+            let nodes = nodes.iter().map(self::node);
+            quote!(Body(::std::vec![#(#nodes),*]))
+        }
+        Node::Value(value) => {
+            let value = self::value(value);
+            quote!(Value(#value))
+        }
+        Node::Variable(identifier) => quote!(Variable(#identifier.into())),
+        Node::FunctionCall(identifier, args, named_args) => {
+            let args = nodes(args);
+            let named_args = named(named_args);
+            quote!(FunctionCall(#identifier.into(), #args, #named_args))
+        }
+        Node::Array(nodes) => {
+            let nodes = self::nodes(nodes);
+            quote!(Array(#nodes))
+        }
+        Node::Concat(nodes) => {
+            let nodes = self::nodes(nodes);
+            quote!(Concat(#nodes))
+        }
+        Node::Spread(node) => {
+            let node = boxed(node);
+            quote!(Spread(#node))
+        }
+        Node::Comprehension(identifier, array, element, filter) => {
+            let array = boxed(array);
+            let element = boxed(element);
+            let filter = optional(filter);
+            quote!(Comprehension(#identifier.into(), #array, #element, #filter))
+        }
+        Node::Index(value, index) => {
+            let value = boxed(value);
+            let index = boxed(index);
+            quote!(Index(#value, #index))
+        }
+        Node::Slice(value, start, end, step) => {
+            let value = boxed(value);
+            let start = optional(start);
+            let end = optional(end);
+            let step = optional(step);
+            quote!(Slice(#value, #start, #end, #step))
+        }
+        Node::OptionalIndex(value, index) => {
+            let value = boxed(value);
+            let index = boxed(index);
+            quote!(OptionalIndex(#value, #index))
+        }
+        Node::Attribute(object, attribute) => {
+            let object = boxed(object);
+            quote!(Attribute(#object, #attribute.into()))
+        }
+        Node::OptionalAttribute(object, attribute) => {
+            let object = boxed(object);
+            quote!(OptionalAttribute(#object, #attribute.into()))
+        }
+        Node::Operation(lhs, operator, rhs) => {
+            let lhs = boxed(lhs);
+            let operator = self::operator(operator);
+            let rhs = boxed(rhs);
+            quote!(Operation(#lhs, #operator, #rhs))
+        }
+        Node::Not(node) => {
+            let node = boxed(node);
+            quote!(Not(#node))
+        }
+        Node::Negate(node) => {
+            let node = boxed(node);
+            quote!(Negate(#node))
+        }
+        Node::PrefixOperation(symbol, node) => {
+            let node = boxed(node);
+            quote!(PrefixOperation(#symbol.into(), #node))
+        }
+        Node::IfThenElse(condition, then_node, else_node) => {
+            let condition = boxed(condition);
+            let then_node = boxed(then_node);
+            let else_node = optional(else_node);
+            quote!(IfThenElse(#condition, #then_node, #else_node))
+        }
+        Node::ForIn(identifier, array, body, separator, filter) => {
+            let array = boxed(array);
+            let body = boxed(body);
+            let separator = optional(separator);
+            let filter = optional(filter);
+            quote!(ForIn(#identifier.into(), #array, #body, #separator, #filter))
+        }
+        Node::Break => quote!(Break),
+        Node::Continue => quote!(Continue),
+        Node::Lambda(params, body) => {
+            let body = boxed(body);
+            quote!(Lambda(::std::vec![#(#params.into()),*], #body))
+        }
+        Node::Test(value, test, args, named_args) => {
+            let value = boxed(value);
+            let args = nodes(args);
+            let named_args = named(named_args);
+            quote!(Test(#value, #test.into(), #args, #named_args))
+        }
+        Node::Error => quote!(Error),
+    };
+    quote!(::ramon_templates::Node::#node)
+}
+
+fn nodes(nodes: &[Node]) -> TokenStream {
+    let nodes = nodes.iter().map(node);
+    quote!(::std::vec![#(#nodes),*])
+}
+
+fn named(nodes: &[(String, Node)]) -> TokenStream {
+    let nodes = nodes.iter().map(|(name, value)| {
+        let value = node(value);
+        quote!((#name.into(), #value))
+    });
+    quote!(::std::vec![#(#nodes),*])
+}
+
+fn boxed(node: &Node) -> TokenStream {
+    let node = self::node(node);
+    quote!(::std::boxed::Box::new(#node))
+}
+
+fn optional(node: &Option<Box<Node>>) -> TokenStream {
+    match node {
+        Some(node) => {
+            let node = boxed(node);
+            quote!(::core::option::Option::Some(#node))
+        }
+        None => quote!(::core::option::Option::None),
+    }
+}
+
+fn value(value: &OwnedValue) -> TokenStream {
+    let value = match value {
+        OwnedValue::String(string) => quote!(String(#string.into())),
+        OwnedValue::Number(number) => {
+            let number = self::number(*number);
+            quote!(Number(#number))
+        }
+        OwnedValue::Boolean(boolean) => quote!(Boolean(#boolean)),
+        OwnedValue::Array(values) => {
+            let values = values.iter().map(self::value);
+            quote!(Array(::std::vec![#(#values),*]))
+        }
+        OwnedValue::Range(range) => {
+            let start = number(range.start());
+            let end = number(range.end());
+            let step = number(range.step());
+            let inclusive = range.is_inclusive();
+            quote! {
+                Range(
+                    ::ramon_templates::Range::new(#start, #end, #step, #inclusive)
+                        .expect("range was valid when the template was compiled"),
+                )
+            }
+        }
+        OwnedValue::Object(object) => {
+            let entries = object.iter().map(|(key, value)| {
+                let value = self::value(value);
+                quote!((#key.into(), #value))
+            });
+            quote!(Object(::std::collections::BTreeMap::from([#(#entries),*])))
+        }
+        OwnedValue::Null => quote!(Null),
+    };
+    quote!(::ramon_templates::OwnedValue::#value)
+}
+
+/// `Literal::f64_suffixed` panics on infinities and NaN, which have no literal.
+fn number(number: f64) -> TokenStream {
+    if number.is_finite() {
+        let number = Literal::f64_suffixed(number);
+        quote!(#number)
+    } else if number.is_nan() {
+        quote!(::core::f64::NAN)
+    } else if number > 0.0 {
+        quote!(::core::f64::INFINITY)
+    } else {
+        quote!(::core::f64::NEG_INFINITY)
+    }
+}
+
+fn operator(operator: &Operator) -> TokenStream {
+    let operator = match operator {
+        Operator::Multiply => quote!(Multiply),
+        Operator::Divide => quote!(Divide),
+        Operator::Add => quote!(Add),
+        Operator::Subtract => quote!(Subtract),
+        Operator::IsEqualTo => quote!(IsEqualTo),
+        Operator::IsNotEqualTo => quote!(IsNotEqualTo),
+        Operator::And => quote!(And),
+        Operator::Or => quote!(Or),
+        Operator::Range => quote!(Range),
+        Operator::RangeInclusive => quote!(RangeInclusive),
+        Operator::In => quote!(In),
+        Operator::NotIn => quote!(NotIn),
+        Operator::Coalesce => quote!(Coalesce),
+        Operator::Custom(symbol) => quote!(Custom(#symbol.into())),
+    };
+    quote!(::ramon_templates::Operator::#operator)
+}
@@ -0,0 +1,63 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ramon_templates::{Environment, Node, OwnedValue, Parser};
+use ramon_templates_macros::{include_template, template};
+
+#[test]
+fn template() {
+    let inputs = [
+        "Hello {{ name }}!",
+        "{{ 1 + x * (1 + 2) }}",
+        "{{ host?.port ?? 80 }} {{ items?[0] }}",
+        "{{ if x is divisibleby(3) }}fizz{{ elif 'admin' not in roles }}{{ else }}!{{ end }}",
+        "{{ for x in xs if x != 0 ', ' }}{{ if x == 9 }}{{ break }}{{/if}}{{ x }}{{/for}}",
+        "{{ items[1..=3] }} {{ hash[-8:] }} {{ items[::2] }} {{ -n }} {{ !x }}",
+        "{{ [...defaults, x] }} {{ [h.name for h in hosts if h.down] }}",
+        r#"{{ f"host {name}:{port}" }} {{ pad(world(), width = 10) }}"#,
+        "{{ reduce(xs, 0, (sum, x) => sum + x) }} {{ for i in 0..n }}{{ continue }}{{ end }}",
+    ];
+    let templates = [
+        template!("Hello {{ name }}!"),
+        template!("{{ 1 + x * (1 + 2) }}"),
+        template!("{{ host?.port ?? 80 }} {{ items?[0] }}"),
+        template!(
+            "{{ if x is divisibleby(3) }}fizz{{ elif 'admin' not in roles }}{{ else }}!{{ end }}"
+        ),
+        template!(
+            "{{ for x in xs if x != 0 ', ' }}{{ if x == 9 }}{{ break }}{{/if}}{{ x }}{{/for}}"
+        ),
+        template!("{{ items[1..=3] }} {{ hash[-8:] }} {{ items[::2] }} {{ -n }} {{ !x }}"),
+        template!("{{ [...defaults, x] }} {{ [h.name for h in hosts if h.down] }}"),
+        template!(r#"{{ f"host {name}:{port}" }} {{ pad(world(), width = 10) }}"#),
+        template!(
+            "{{ reduce(xs, 0, (sum, x) => sum + x) }} {{ for i in 0..n }}{{ continue }}{{ end }}"
+        ),
+    ];
+    for (input, template) in inputs.into_iter().zip(templates) {
+        assert_eq!(*template, Parser::parse_input(input).unwrap(), "{input}");
+    }
+
+    // The tree is built once and shared by every evaluation of the expression.
+    let first: Vec<&'static Node> = (0..2).map(|_| template!("{{ x }}")).collect();
+    assert!(std::ptr::eq(first[0], first[1]));
+}
+
+#[test]
+fn include_template() {
+    let template = include_template!("tests/templates/hosts.txt");
+    let input = include_str!("templates/hosts.txt");
+    assert_eq!(*template, Parser::parse_input(input).unwrap());
+
+    let host = |name: &str, down| {
+        OwnedValue::Object(BTreeMap::from([
+            ("name".into(), OwnedValue::String(name.into())),
+            ("down".into(), OwnedValue::Boolean(down)),
+        ]))
+    };
+    let vars = HashMap::from([(
+        "hosts".to_owned(),
+        OwnedValue::Array(vec![host("web", true), host("db", false)]),
+    )]);
+    let output = template.evaluate(&vars, &Environment::new()).unwrap();
+    assert_eq!(output, "web (down), db\n");
+}
@@ -0,0 +1,32 @@
+use crate::value::Value;
+
+/// The arguments a function was called with. `pad(name, width = 10, fill = "-")` has one
+/// positional argument and two named arguments.
+#[derive(Clone, Default)]
+pub struct Arguments<'a> {
+    pub positional: Vec<Value<'a>>,
+    /// Named arguments, in the order they were written. Names are unique.
+    pub named: Vec<(String, Value<'a>)>,
+}
+
+impl<'a> Arguments<'a> {
+    pub fn new(positional: Vec<Value<'a>>) -> Self {
+        Self {
+            positional,
+            named: Vec::new(),
+        }
+    }
+
+    /// Returns the positional argument at `index`.
+    pub fn get(&self, index: usize) -> Option<&Value<'a>> {
+        self.positional.get(index)
+    }
+
+    /// Returns the named argument called `name`.
+    pub fn get_named(&self, name: &str) -> Option<&Value<'a>> {
+        self.named
+            .iter()
+            .find(|(identifier, _)| identifier == name)
+            .map(|(_, value)| value)
+    }
+}
@@ -0,0 +1,522 @@
+//! A compact binary format for caching parsed templates. A cache starts with [`MAGIC`], the
+//! format version, and the crate version that wrote it. Numbers are LEB128 varints, except for
+//! floats, which are little-endian. Strings and lists are prefixed with their length. Nodes, values,
+//! and operators are a tag byte, their variant's index, followed by their fields in order.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    error::DecodeError,
+    lexer::Operator,
+    node::Node,
+    parser::DEFAULT_MAX_HEIGHT,
+    value::{OwnedValue, Range},
+};
+
+const MAGIC: &[u8; 4] = b"RTPL";
+
+/// Bumped whenever the encoding of an existing node changes.
+const FORMAT_VERSION: usize = 1;
+
+const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+impl Node {
+    /// Encodes the template for [`Node::from_bytes`], so that it can be stored instead of parsed
+    /// again.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder { bytes: Vec::new() };
+        encoder.bytes.extend(MAGIC);
+        encoder.usize(FORMAT_VERSION);
+        encoder.string(CRATE_VERSION);
+        encoder.node(self);
+        encoder.bytes
+    }
+
+    /// Decodes a template encoded by [`Node::to_bytes`]. Caches written by a version of this crate
+    /// that isn't semver-compatible with this one are rejected, because its templates may have
+    /// been parsed differently.
+    ///
+    /// Decoding is recursive, so caches of trees taller than the parser builds by default are
+    /// rejected, to keep a corrupt cache from overflowing the stack. Templates parsed with a
+    /// higher [`Parser::with_max_height`](crate::Parser::with_max_height) have to be decoded with
+    /// [`Node::from_bytes_with_max_height`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Node, DecodeError> {
+        Self::from_bytes_with_max_height(bytes, DEFAULT_MAX_HEIGHT)
+    }
+
+    /// Like [`Node::from_bytes`], but accepts trees up to `max_height` levels tall, which should
+    /// be the height that the template was parsed with.
+    pub fn from_bytes_with_max_height(
+        bytes: &[u8],
+        max_height: usize,
+    ) -> Result<Node, DecodeError> {
+        let bytes = bytes.strip_prefix(MAGIC).ok_or(DecodeError::NotACache)?;
+        let mut decoder = Decoder {
+            bytes,
+            depth: 0,
+            max_depth: max_height,
+        };
+        let format = decoder.usize()?;
+        let version = decoder.string()?;
+        if format != FORMAT_VERSION || compatibility(&version) != compatibility(CRATE_VERSION) {
+            return Err(DecodeError::IncompatibleVersion(version, format));
+        }
+        let node = decoder.node()?;
+        if !decoder.bytes.is_empty() {
+            return Err(DecodeError::Invalid("trailing bytes"));
+        }
+        Ok(node)
+    }
+}
+
+/// The part of a version that semver-compatible versions share: the major version, or the minor
+/// version before 1.0.
+fn compatibility(version: &str) -> &str {
+    let mut parts = version.split('.');
+    match (parts.next(), parts.next()) {
+        (Some("0"), Some(minor)) => &version[..2 + minor.len()],
+        (major, _) => major.unwrap_or_default(),
+    }
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn usize(&mut self, mut n: usize) {
+        while n >= 0x80 {
+            self.bytes.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.bytes.push(n as u8);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.usize(string.len());
+        self.bytes.extend(string.as_bytes());
+    }
+
+    fn f64(&mut self, n: f64) {
+        self.bytes.extend(n.to_le_bytes());
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        self.usize(nodes.len());
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    fn named(&mut self, nodes: &[(String, Node)]) {
+        self.usize(nodes.len());
+        for (name, node) in nodes {
+            self.string(name);
+            self.node(node);
+        }
+    }
+
+    fn strings(&mut self, strings: &[String]) {
+        self.usize(strings.len());
+        for string in strings {
+            self.string(string);
+        }
+    }
+
+    fn optional(&mut self, node: &Option<Box<Node>>) {
+        match node {
+            None => self.bytes.push(0),
+            Some(node) => {
+                self.bytes.push(1);
+                self.node(node);
+            }
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::Body(nodes) => {
+                self.bytes.push(0);
+                self.nodes(nodes);
+            }
+            Node::Value(value) => {
+                self.bytes.push(1);
+                self.value(value);
+            }
+            Node::Variable(identifier) => {
+                self.bytes.push(2);
+                self.string(identifier);
+            }
+            Node::FunctionCall(identifier, args, named_args) => {
+                self.bytes.push(3);
+                self.string(identifier);
+                self.nodes(args);
+                self.named(named_args);
+            }
+            Node::Array(nodes) => {
+                self.bytes.push(4);
+                self.nodes(nodes);
+            }
+            Node::Concat(parts) => {
+                self.bytes.push(5);
+                self.nodes(parts);
+            }
+            Node::Spread(items) => {
+                self.bytes.push(6);
+                self.node(items);
+            }
+            Node::Comprehension(identifier, array, element, filter) => {
+                self.bytes.push(7);
+                self.string(identifier);
+                self.node(array);
+                self.node(element);
+                self.optional(filter);
+            }
+            Node::Index(value, index) => {
+                self.bytes.push(8);
+                self.node(value);
+                self.node(index);
+            }
+            Node::Slice(value, start, end, step) => {
+                self.bytes.push(9);
+                self.node(value);
+                self.optional(start);
+                self.optional(end);
+                self.optional(step);
+            }
+            Node::OptionalIndex(value, index) => {
+                self.bytes.push(10);
+                self.node(value);
+                self.node(index);
+            }
+            Node::Attribute(object, attribute) => {
+                self.bytes.push(11);
+                self.node(object);
+                self.string(attribute);
+            }
+            Node::OptionalAttribute(object, attribute) => {
+                self.bytes.push(12);
+                self.node(object);
+                self.string(attribute);
+            }
+            Node::Operation(lhs, op, rhs) => {
+                self.bytes.push(13);
+                self.node(lhs);
+                self.operator(op);
+                self.node(rhs);
+            }
+            Node::Not(node) => {
+                self.bytes.push(14);
+                self.node(node);
+            }
+            Node::Negate(node) => {
+                self.bytes.push(15);
+                self.node(node);
+            }
+            Node::PrefixOperation(symbol, node) => {
+                self.bytes.push(16);
+                self.string(symbol);
+                self.node(node);
+            }
+            Node::IfThenElse(condition, then_node, else_node) => {
+                self.bytes.push(17);
+                self.node(condition);
+                self.node(then_node);
+                self.optional(else_node);
+            }
+            Node::ForIn(identifier, array, body, separator, filter) => {
+                self.bytes.push(18);
+                self.string(identifier);
+                self.node(array);
+                self.node(body);
+                self.optional(separator);
+                self.optional(filter);
+            }
+            Node::Break => self.bytes.push(19),
+            Node::Continue => self.bytes.push(20),
+            Node::Lambda(params, body) => {
+                self.bytes.push(21);
+                self.strings(params);
+                self.node(body);
+            }
+            Node::Test(value, test, args, named_args) => {
+                self.bytes.push(22);
+                self.node(value);
+                self.string(test);
+                self.nodes(args);
+                self.named(named_args);
+            }
+            Node::Error => self.bytes.push(23),
+        }
+    }
+
+    fn value(&mut self, value: &OwnedValue) {
+        match value {
+            OwnedValue::String(string) => {
+                self.bytes.push(0);
+                self.string(string);
+            }
+            OwnedValue::Number(n) => {
+                self.bytes.push(1);
+                self.f64(*n);
+            }
+            OwnedValue::Boolean(boolean) => self.bytes.extend([2, u8::from(*boolean)]),
+            OwnedValue::Array(array) => {
+                self.bytes.push(3);
+                self.usize(array.len());
+                for item in array {
+                    self.value(item);
+                }
+            }
+            OwnedValue::Range(range) => {
+                self.bytes.push(4);
+                self.f64(range.start());
+                self.f64(range.end());
+                self.f64(range.step());
+                self.bytes.push(u8::from(range.is_inclusive()));
+            }
+            OwnedValue::Object(object) => {
+                self.bytes.push(5);
+                self.usize(object.len());
+                for (key, value) in object {
+                    self.string(key);
+                    self.value(value);
+                }
+            }
+            OwnedValue::Null => self.bytes.push(6),
+        }
+    }
+
+    fn operator(&mut self, op: &Operator) {
+        let tag = match op {
+            Operator::Multiply => 0,
+            Operator::Divide => 1,
+            Operator::Add => 2,
+            Operator::Subtract => 3,
+            Operator::IsEqualTo => 4,
+            Operator::IsNotEqualTo => 5,
+            Operator::And => 6,
+            Operator::Or => 7,
+            Operator::Range => 8,
+            Operator::RangeInclusive => 9,
+            Operator::In => 10,
+            Operator::NotIn => 11,
+            Operator::Coalesce => 12,
+            Operator::Custom(symbol) => {
+                self.bytes.push(13);
+                self.string(symbol);
+                return;
+            }
+        };
+        self.bytes.push(tag);
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+        if len > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid("invalid boolean")),
+        }
+    }
+
+    fn usize(&mut self) -> Result<usize, DecodeError> {
+        let mut n = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.byte()?;
+            let bits = usize::from(byte & 0x7f);
+            if bits << shift >> shift != bits {
+                break;
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(DecodeError::Invalid("number out of range"))
+    }
+
+    /// Reads a list length. Every item takes at least a byte, so a length longer than the rest of
+    /// the cache is rejected before anything is allocated for it.
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let len = self.usize()?;
+        if len > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(len)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.usize()?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| DecodeError::Invalid("string is not UTF-8"))
+    }
+
+    fn f64(&mut self) -> Result<f64, DecodeError> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn nodes(&mut self) -> Result<Vec<Node>, DecodeError> {
+        (0..self.len()?).map(|_| self.node()).collect()
+    }
+
+    fn named(&mut self) -> Result<Vec<(String, Node)>, DecodeError> {
+        (0..self.len()?)
+            .map(|_| Ok((self.string()?, self.node()?)))
+            .collect()
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, DecodeError> {
+        (0..self.len()?).map(|_| self.string()).collect()
+    }
+
+    fn boxed(&mut self) -> Result<Box<Node>, DecodeError> {
+        Ok(Box::new(self.node()?))
+    }
+
+    fn optional(&mut self) -> Result<Option<Box<Node>>, DecodeError> {
+        match self.bool()? {
+            false => Ok(None),
+            true => Ok(Some(self.boxed()?)),
+        }
+    }
+
+    /// Goes one level deeper, failing if that exceeds the maximum depth.
+    fn descend(&mut self) -> Result<(), DecodeError> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(DecodeError::NestingTooDeep(self.max_depth));
+        }
+        Ok(())
+    }
+
+    fn node(&mut self) -> Result<Node, DecodeError> {
+        self.descend()?;
+        // Every variant is decoded by a function of its own. Otherwise the temporaries of all of
+        // them would pile up in the frame of this recursive function, which is large enough in
+        // debug builds to overflow the stack long before the parser's default height.
+        let decode: fn(&mut Self) -> Result<Node, DecodeError> = match self.byte()? {
+            0 => |d| Ok(Node::Body(d.nodes()?)),
+            1 => |d| Ok(Node::Value(d.value()?)),
+            2 => |d| Ok(Node::Variable(d.string()?)),
+            3 => |d| Ok(Node::FunctionCall(d.string()?, d.nodes()?, d.named()?)),
+            4 => |d| Ok(Node::Array(d.nodes()?)),
+            5 => |d| Ok(Node::Concat(d.nodes()?)),
+            6 => |d| Ok(Node::Spread(d.boxed()?)),
+            7 => |d| {
+                Ok(Node::Comprehension(
+                    d.string()?,
+                    d.boxed()?,
+                    d.boxed()?,
+                    d.optional()?,
+                ))
+            },
+            8 => |d| Ok(Node::Index(d.boxed()?, d.boxed()?)),
+            9 => |d| {
+                Ok(Node::Slice(
+                    d.boxed()?,
+                    d.optional()?,
+                    d.optional()?,
+                    d.optional()?,
+                ))
+            },
+            10 => |d| Ok(Node::OptionalIndex(d.boxed()?, d.boxed()?)),
+            11 => |d| Ok(Node::Attribute(d.boxed()?, d.string()?)),
+            12 => |d| Ok(Node::OptionalAttribute(d.boxed()?, d.string()?)),
+            13 => |d| Ok(Node::Operation(d.boxed()?, d.operator()?, d.boxed()?)),
+            14 => |d| Ok(Node::Not(d.boxed()?)),
+            15 => |d| Ok(Node::Negate(d.boxed()?)),
+            16 => |d| Ok(Node::PrefixOperation(d.string()?, d.boxed()?)),
+            17 => |d| Ok(Node::IfThenElse(d.boxed()?, d.boxed()?, d.optional()?)),
+            18 => |d| {
+                Ok(Node::ForIn(
+                    d.string()?,
+                    d.boxed()?,
+                    d.boxed()?,
+                    d.optional()?,
+                    d.optional()?,
+                ))
+            },
+            19 => |_| Ok(Node::Break),
+            20 => |_| Ok(Node::Continue),
+            21 => |d| Ok(Node::Lambda(d.strings()?, d.boxed()?)),
+            22 => |d| Ok(Node::Test(d.boxed()?, d.string()?, d.nodes()?, d.named()?)),
+            23 => |_| Ok(Node::Error),
+            _ => return Err(DecodeError::Invalid("unknown node")),
+        };
+        let node = decode(self);
+        self.depth -= 1;
+        node
+    }
+
+    fn value(&mut self) -> Result<OwnedValue, DecodeError> {
+        self.descend()?;
+        let value = match self.byte()? {
+            0 => OwnedValue::String(self.string()?),
+            1 => OwnedValue::Number(self.f64()?),
+            2 => OwnedValue::Boolean(self.bool()?),
+            3 => OwnedValue::Array(
+                (0..self.len()?)
+                    .map(|_| self.value())
+                    .collect::<Result<_, _>>()?,
+            ),
+            4 => {
+                let (start, end, step) = (self.f64()?, self.f64()?, self.f64()?);
+                let range = Range::new(start, end, step, self.bool()?)
+                    .map_err(|_| DecodeError::Invalid("invalid range"))?;
+                OwnedValue::Range(range)
+            }
+            5 => OwnedValue::Object(
+                (0..self.len()?)
+                    .map(|_| Ok((self.string()?, self.value()?)))
+                    .collect::<Result<BTreeMap<_, _>, _>>()?,
+            ),
+            6 => OwnedValue::Null,
+            _ => return Err(DecodeError::Invalid("unknown value")),
+        };
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    fn operator(&mut self) -> Result<Operator, DecodeError> {
+        let op = match self.byte()? {
+            0 => Operator::Multiply,
+            1 => Operator::Divide,
+            2 => Operator::Add,
+            3 => Operator::Subtract,
+            4 => Operator::IsEqualTo,
+            5 => Operator::IsNotEqualTo,
+            6 => Operator::And,
+            7 => Operator::Or,
+            8 => Operator::Range,
+            9 => Operator::RangeInclusive,
+            10 => Operator::In,
+            11 => Operator::NotIn,
+            12 => Operator::Coalesce,
+            13 => Operator::Custom(self.string()?),
+            _ => return Err(DecodeError::Invalid("unknown operator")),
+        };
+        Ok(op)
+    }
+}
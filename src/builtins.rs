@@ -0,0 +1,95 @@
+use crate::{
+    arguments::Arguments,
+    error::ValueError,
+    value::{OwnedValue, Range},
+};
+
+/// Calls the builtin function named `identifier`, if there is one. User-provided functions take
+/// precedence over builtins.
+pub(crate) fn call(identifier: &str, args: &Arguments) -> Option<Result<OwnedValue, ValueError>> {
+    let result = match identifier {
+        "range" => range(args),
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// `range(end)`, `range(start, end)`, or `range(start, end, step)`. The end is exclusive.
+fn range(args: &Arguments) -> Result<OwnedValue, ValueError> {
+    if let Some((name, _)) = args.named.first() {
+        return Err(ValueError::OperationError(format!(
+            "range() got an unexpected named argument {name:?}"
+        )));
+    }
+    let numbers = args
+        .positional
+        .iter()
+        .map(|arg| arg.clone().unwrap_f64())
+        .collect::<Result<Vec<f64>, ValueError>>()?;
+    let (start, end, step) = match numbers[..] {
+        [end] => (0.0, end, 1.0),
+        [start, end] => (start, end, 1.0),
+        [start, end, step] => (start, end, step),
+        _ => {
+            return Err(ValueError::OperationError(format!(
+                "range() takes 1 to 3 arguments, got {}",
+                numbers.len()
+            )));
+        }
+    };
+    Ok(OwnedValue::Range(Range::new(start, end, step, false)?))
+}
+
+/// Runs the builtin test named `test` on `value`, if there is one. `defined` and `undefined` are
+/// handled by the evaluator because they need to see undefined values.
+pub(crate) fn test(
+    test: &str,
+    value: &OwnedValue,
+    args: &Arguments,
+) -> Option<Result<bool, ValueError>> {
+    let passed = match test {
+        "null" => Ok(*value == OwnedValue::Null),
+        "string" => Ok(matches!(value, OwnedValue::String(_))),
+        "number" => Ok(matches!(value, OwnedValue::Number(_))),
+        "boolean" => Ok(matches!(value, OwnedValue::Boolean(_))),
+        "array" => Ok(matches!(value, OwnedValue::Array(_))),
+        "range" => Ok(matches!(value, OwnedValue::Range(_))),
+        "object" => Ok(matches!(value, OwnedValue::Object(_))),
+        "empty" => empty(value),
+        "even" => divisible_by(value, 2.0),
+        "odd" => divisible_by(value, 2.0).map(|even| !even),
+        "divisibleby" => match args.positional[..] {
+            [ref divisor] => divisor
+                .clone()
+                .unwrap_f64()
+                .and_then(|divisor| divisible_by(value, divisor)),
+            _ => Err(ValueError::OperationError(
+                "divisibleby takes 1 argument".into(),
+            )),
+        },
+        _ => return None,
+    };
+    Some(passed)
+}
+
+fn empty(value: &OwnedValue) -> Result<bool, ValueError> {
+    match value {
+        OwnedValue::String(string) => Ok(string.is_empty()),
+        OwnedValue::Array(array) => Ok(array.is_empty()),
+        OwnedValue::Range(range) => Ok(range.is_empty()),
+        OwnedValue::Object(object) => Ok(object.is_empty()),
+        OwnedValue::Null => Ok(true),
+        _ => Err(ValueError::OperationError(format!(
+            "Cannot check whether {value:?} is empty"
+        ))),
+    }
+}
+
+fn divisible_by(value: &OwnedValue, divisor: f64) -> Result<bool, ValueError> {
+    match value {
+        OwnedValue::Number(number) => Ok(number % divisor == 0.0),
+        _ => Err(ValueError::OperationError(format!(
+            "Cannot check whether {value:?} is divisible by {divisor}"
+        ))),
+    }
+}
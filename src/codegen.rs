@@ -0,0 +1,706 @@
+use crate::{
+    lexer::Operator,
+    node::{depth, interrupts, Mode, Node},
+    value::OwnedValue,
+};
+
+/// Generates the Rust source of a function that renders a template, for build scripts that want
+/// templates compiled to native code:
+///
+/// ```
+/// use ramon_templates::{Generator, Parser};
+///
+/// let template = Parser::parse_input("{{ for x in xs ', ' }}{{ x }}{{ end }}").unwrap();
+/// let source = Generator::new("render_list").generate(&template);
+/// assert!(source.contains("pub fn render_list<"));
+/// ```
+///
+/// A build script writes the source to a file in `OUT_DIR`, which the crate then `include!`s. The
+/// generated function has this signature:
+///
+/// ```text
+/// pub fn render_list<V: Variables, W: std::fmt::Write + ?Sized>(
+///     variables: &V,
+///     environment: &Environment,
+///     out: &mut W,
+/// ) -> Result<(), ValueError>
+/// ```
+///
+/// It writes the same output as [`Node::evaluate`] returns and fails with the same errors, though
+/// it may have written part of the output by then. The nesting limit is checked before anything
+/// is written, so a template nested too deeply fails even if its deepest part would not have been
+/// reached. Output is only buffered for the items of loops with separators and for bodies that
+/// are evaluated as values.
+pub struct Generator {
+    name: String,
+}
+
+impl Generator {
+    /// `name` is the name of the generated function, which must be a Rust identifier.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+
+    pub fn generate(&self, template: &Node) -> String {
+        let mut emitter = Emitter {
+            scope: Vec::new(),
+            targets: Vec::new(),
+            names: 0,
+        };
+        let body = match template {
+            Node::Body(_) | Node::ForIn(..) => emitter.write(template, "out"),
+            node => vec![format!("rt::write_string(out, {})?;", emitter.expr(node))],
+        };
+        let body = indent(&body.join("\n"));
+        format!(
+            "#[allow(unused, unreachable_code, clippy::all)]
+pub fn {name}<V: ::ramon_templates::Variables, W: ::std::fmt::Write + ?Sized>(
+    variables: &V,
+    environment: &::ramon_templates::Environment,
+    out: &mut W,
+) -> ::std::result::Result<(), ::ramon_templates::ValueError> {{
+    use ::ramon_templates::{{runtime as rt, Arguments, Operator, OwnedValue, Value}};
+    rt::check_depth(environment, {depth})?;
+{body}
+    Ok(())
+}}
+",
+            name = self.name,
+            depth = depth(template),
+        )
+    }
+}
+
+/// What `break` and `continue` unwind through, innermost last.
+enum Target {
+    /// A `for` loop, which `break` and `continue` in its body go to. `output` is where the loop
+    /// writes its items. Loops with a separator render each item into a buffer, which the
+    /// labeled block `'item{label}` ends.
+    Loop {
+        label: usize,
+        output: String,
+        item: bool,
+    },
+    /// The buffer of a body that is evaluated as a value. Unwinding carries what it rendered along,
+    /// like the tree-walker does.
+    Capture(String),
+    /// The body of a lambda, which `break` and `continue` can't leave.
+    Lambda,
+}
+
+/// Emits code in the same order that the tree-walker evaluates nodes. Expressions are Rust blocks
+/// that evaluate to a `Value`, and `?` returns their errors.
+struct Emitter {
+    /// The local variables in scope, innermost last, with the Rust variables that hold them.
+    scope: Vec<(String, String)>,
+    targets: Vec<Target>,
+    /// The number of locals, buffers, loops, and lambdas so far, which keeps their names unique.
+    names: usize,
+}
+
+impl Emitter {
+    fn name(&mut self, prefix: &str) -> String {
+        self.names += 1;
+        format!("{prefix}{}", self.names)
+    }
+
+    fn bind(&mut self, identifier: &str) -> String {
+        let local = self.name("local");
+        self.scope.push((identifier.to_owned(), local.clone()));
+        local
+    }
+
+    /// Statements that append the string form of a node to `out`, like the children of a body.
+    fn write(&mut self, node: &Node, out: &str) -> Vec<String> {
+        match node {
+            Node::Body(nodes) => nodes
+                .iter()
+                .flat_map(|node| self.write(node, out))
+                .collect(),
+            // A range with too many items to write fails when the template renders.
+            Node::Value(value) if value.to_text().is_ok() => {
+                let text = match value {
+                    OwnedValue::String(string) => string.clone(),
+                    value => value.to_string(),
+                };
+                match text.is_empty() {
+                    true => Vec::new(),
+                    false => vec![format!("rt::text({out}, {text:?})?;")],
+                }
+            }
+            Node::IfThenElse(..) => {
+                // Like the tree-walker, `elif`s don't nest, so they become an `else if` chain.
+                let mut statement = String::new();
+                let mut node = node;
+                while let Node::IfThenElse(condition, then_node, else_node) = node {
+                    let condition = self.expr(condition);
+                    let then_node = block(self.write(then_node, out), None);
+                    statement += &format!("if rt::truthy(&{condition}) {then_node}");
+                    match else_node {
+                        Some(else_node) => node = else_node,
+                        None => return vec![statement],
+                    }
+                    statement += " else ";
+                }
+                statement += &block(self.write(node, out), None);
+                vec![statement]
+            }
+            Node::ForIn(identifier, array, body, separator, filter) => {
+                // `break` in the filter or the separator goes to an outer loop and drops what this
+                // loop rendered. The parser doesn't allow it there, but trees built by hand can.
+                let dropped = [filter, separator]
+                    .into_iter()
+                    .any(|node| node.as_deref().is_some_and(interrupts));
+                let buffer = dropped.then(|| self.name("buffer"));
+                let output = match &buffer {
+                    Some(buffer) => format!("&mut {buffer}"),
+                    None => out.to_owned(),
+                };
+                let mut statements = Vec::new();
+                if let Some(buffer) = &buffer {
+                    statements.push(format!("let mut {buffer} = String::new();"));
+                }
+                let for_in = self.for_in(identifier, array, body, separator, filter, &output);
+                statements.push(block(for_in, None));
+                if let Some(buffer) = &buffer {
+                    statements.push(format!("rt::text({out}, &{buffer})?;"));
+                }
+                statements
+            }
+            Node::Break | Node::Continue => {
+                vec![format!("{};", self.interrupt(matches!(node, Node::Break)))]
+            }
+            node => vec![
+                format!("let value = {};", self.expr(node)),
+                format!("rt::write({out}, &value)?;"),
+            ],
+        }
+    }
+
+    /// Statements that run a `for` loop, writing its output to `out`.
+    fn for_in(
+        &mut self,
+        identifier: &str,
+        array: &Node,
+        body: &Node,
+        separator: &Option<Box<Node>>,
+        filter: &Option<Box<Node>>,
+        out: &str,
+    ) -> Vec<String> {
+        let mut statements = vec![format!("let items = rt::iterate({})?;", self.expr(array))];
+        if separator.is_some() {
+            statements.push("let mut is_first = true;".into());
+        }
+        self.names += 1;
+        let label = self.names;
+        let local = self.bind(identifier);
+        let mut each = Vec::new();
+        if let Some(filter) = filter {
+            each.push(format!("let condition = {};", self.expr(filter)));
+            each.push(format!(
+                "if !rt::truthy(&condition) {{\n    continue 'loop{label};\n}}"
+            ));
+        }
+        if let Some(separator) = separator {
+            each.push(format!(
+                "let separator = rt::to_string(&{})?;",
+                self.expr(separator)
+            ));
+        }
+        match separator {
+            None => {
+                self.targets.push(Target::Loop {
+                    label,
+                    output: out.to_owned(),
+                    item: false,
+                });
+                each.extend(self.write(body, out));
+            }
+            Some(_) => {
+                let buffer = self.name("buffer");
+                let item = format!("&mut {buffer}");
+                self.targets.push(Target::Loop {
+                    label,
+                    output: item.clone(),
+                    item: true,
+                });
+                let mut body = self.write(body, &item);
+                body.push("rt::Control::Done".into());
+                each.push(format!("let mut {buffer} = String::new();"));
+                each.push(format!(
+                    "let control = 'item{label}: {};",
+                    block(body, None)
+                ));
+                each.push(format!(
+                    "rt::add_item({out}, &separator, &mut is_first, &{buffer}, control)?;"
+                ));
+                each.push(format!(
+                    "if control == rt::Control::Break {{\n    break 'loop{label};\n}}"
+                ));
+            }
+        }
+        self.targets.pop();
+        self.scope.pop();
+        statements.push(format!(
+            "'loop{label}: for {local} in items {}",
+            block(each, None)
+        ));
+        statements
+    }
+
+    /// An expression that unwinds to the loop that `break` or `continue` goes to, carrying along
+    /// the output of the bodies it leaves.
+    fn interrupt(&self, is_break: bool) -> String {
+        let mut carried = Vec::new();
+        for target in self.targets.iter().rev() {
+            match target {
+                Target::Capture(buffer) => carried.push(buffer),
+                Target::Lambda => {
+                    return fail("`break` or `continue` inside of a lambda");
+                }
+                Target::Loop {
+                    label,
+                    output,
+                    item,
+                } => {
+                    let mut statements = carried
+                        .iter()
+                        .rev()
+                        .map(|buffer| format!("rt::text({output}, &{buffer})?;"))
+                        .collect::<Vec<_>>();
+                    statements.push(match (item, is_break) {
+                        (true, true) => format!("break 'item{label} rt::Control::Break"),
+                        (true, false) => format!("break 'item{label} rt::Control::Continue"),
+                        (false, true) => format!("break 'loop{label}"),
+                        (false, false) => format!("continue 'loop{label}"),
+                    });
+                    return match statements.len() {
+                        1 => statements.remove(0),
+                        _ => block(statements, None),
+                    };
+                }
+            }
+        }
+        fail("`break` or `continue` outside of a for loop")
+    }
+
+    /// An expression that evaluates a node to a `Value`.
+    fn expr(&mut self, node: &Node) -> String {
+        match node {
+            Node::Body(_) => {
+                let buffer = self.name("buffer");
+                self.targets.push(Target::Capture(buffer.clone()));
+                let mut statements = vec![format!("let mut {buffer} = String::new();")];
+                statements.extend(self.write(node, &format!("&mut {buffer}")));
+                self.targets.pop();
+                block(
+                    statements,
+                    Some(format!("Value::Owned(OwnedValue::String({buffer}))")),
+                )
+            }
+            Node::Value(value) => format!("Value::Owned({})", owned_value(value)),
+            Node::Variable(identifier) => match self.local(identifier) {
+                Some(local) => format!("{local}.clone()"),
+                None => format!("rt::global(variables, {identifier:?})?"),
+            },
+            Node::FunctionCall(identifier, args, named_args) => {
+                // Lambdas are built up front, like the tree-walker does.
+                let mut statements = Vec::new();
+                let mut lambdas = Vec::new();
+                for node in args.iter().chain(named_args.iter().map(|(_, node)| node)) {
+                    lambdas.push(match node {
+                        Node::Lambda(params, body) => {
+                            let lambda = self.name("lambda");
+                            let function = self.lambda(params, body);
+                            statements.push(format!("let {lambda} = {function};"));
+                            Some(lambda)
+                        }
+                        _ => None,
+                    });
+                }
+                let mut lambdas = lambdas.into_iter();
+                let mut arg = |emitter: &mut Self, node: &Node| match lambdas.next().flatten() {
+                    Some(lambda) => format!("rt::lambda(&{lambda})"),
+                    None => emitter.expr(node),
+                };
+                let positional = args.iter().map(|node| arg(self, node)).collect::<Vec<_>>();
+                let named = named_args
+                    .iter()
+                    .map(|(name, node)| format!("(String::from({name:?}), {})", arg(self, node)))
+                    .collect::<Vec<_>>();
+                statements.push(format!(
+                    "let args = Arguments {{\n    positional: {},\n    named: {},\n}};",
+                    indent_rest(&list(positional)),
+                    indent_rest(&list(named)),
+                ));
+                block(
+                    statements,
+                    Some(format!(
+                        "Value::Owned(rt::call({identifier:?}, args, environment)?)"
+                    )),
+                )
+            }
+            Node::Index(..)
+            | Node::Slice(..)
+            | Node::OptionalIndex(..)
+            | Node::Attribute(..)
+            | Node::OptionalAttribute(..)
+            | Node::Operation(..)
+            | Node::Test(..) => self.chain(node, Mode::Value),
+            Node::Array(nodes) => {
+                let mut statements = vec!["let mut array = Vec::new();".to_owned()];
+                for node in nodes {
+                    match node {
+                        Node::Spread(items) => {
+                            let items = self.expr(items);
+                            statements.push(format!("let items = rt::spread({items})?;"));
+                            statements.push(
+                                "for item in items {\n    array.push(item.to_owned_value());\n}"
+                                    .into(),
+                            );
+                        }
+                        node => {
+                            statements.push(format!("let item: Value = {};", self.expr(node)));
+                            statements.push("array.push(item.to_owned_value());".into());
+                        }
+                    }
+                }
+                block(
+                    statements,
+                    Some("Value::Owned(OwnedValue::Array(array))".into()),
+                )
+            }
+            Node::Concat(parts) => {
+                let mut statements = vec!["let mut string = String::new();".to_owned()];
+                for part in parts {
+                    statements.push(format!("let part = {};", self.expr(part)));
+                    statements.push("rt::write(&mut string, &part)?;".into());
+                }
+                block(
+                    statements,
+                    Some("Value::Owned(OwnedValue::String(string))".into()),
+                )
+            }
+            Node::Spread(_) => fail("Spreads can only be used in arrays"),
+            Node::Comprehension(identifier, array, element, filter) => {
+                let array = self.expr(array);
+                let label = self.name("loop");
+                let local = self.bind(identifier);
+                let mut each = Vec::new();
+                if let Some(filter) = filter {
+                    each.push(format!("let condition = {};", self.expr(filter)));
+                    each.push(format!(
+                        "if !rt::truthy(&condition) {{\n    continue '{label};\n}}"
+                    ));
+                }
+                each.push(format!("let element: Value = {};", self.expr(element)));
+                each.push("array.push(element.to_owned_value());".into());
+                self.scope.pop();
+                block(
+                    vec![
+                        format!("let items = rt::spread({array})?;"),
+                        "let mut array = Vec::new();".into(),
+                        format!("'{label}: for {local} in items {}", block(each, None)),
+                    ],
+                    Some("Value::Owned(OwnedValue::Array(array))".into()),
+                )
+            }
+            Node::Not(node) => format!("Value::from(!rt::truthy(&{}))", self.expr(node)),
+            Node::Negate(node) => block(
+                vec![format!("let value: Value = {};", self.expr(node))],
+                Some("Value::from(-value.unwrap_f64()?)".into()),
+            ),
+            Node::PrefixOperation(symbol, node) => {
+                let operand = self.expr(node);
+                block(
+                    vec![
+                        format!("let operator = rt::prefix_operator(environment, {symbol:?})?;"),
+                        format!("let operand: Value = {operand};"),
+                    ],
+                    Some("Value::Owned(operator(operand.inner())?)".into()),
+                )
+            }
+            Node::IfThenElse(..) => {
+                let mut code = String::new();
+                let mut node = node;
+                while let Node::IfThenElse(condition, then_node, else_node) = node {
+                    let condition = self.expr(condition);
+                    let then_node = indent(&self.expr(then_node));
+                    code += &format!("if rt::truthy(&{condition}) {{\n{then_node}\n}} else ");
+                    match else_node {
+                        Some(else_node) => node = else_node,
+                        None => {
+                            return code
+                                + "{\n    Value::Owned(OwnedValue::String(String::new()))\n}"
+                        }
+                    }
+                }
+                format!("{code}{{\n{}\n}}", indent(&self.expr(node)))
+            }
+            Node::ForIn(..) => {
+                let buffer = self.name("buffer");
+                let mut statements = vec![format!("let mut {buffer} = String::new();")];
+                statements.extend(self.write(node, &format!("&mut {buffer}")));
+                block(
+                    statements,
+                    Some(format!("Value::Owned(OwnedValue::String({buffer}))")),
+                )
+            }
+            Node::Break | Node::Continue => self.interrupt(matches!(node, Node::Break)),
+            Node::Error => fail("Cannot evaluate a template with syntax errors"),
+            Node::Lambda(..) => fail("Lambdas can only be passed to functions"),
+        }
+    }
+
+    /// A closure that evaluates a lambda's body with its parameters bound to its arguments.
+    fn lambda(&mut self, params: &[String], body: &Node) -> String {
+        let locals = params
+            .iter()
+            .map(|param| self.bind(param))
+            .collect::<Vec<_>>();
+        self.targets.push(Target::Lambda);
+        let body = self.expr(body);
+        self.targets.pop();
+        self.scope.truncate(self.scope.len() - params.len());
+        let function = block(
+            vec![
+                format!(
+                    "let [{}] = rt::params::<{}>(args)?;",
+                    locals.join(", "),
+                    params.len()
+                ),
+                format!("let value: Value = {body};"),
+            ],
+            Some("Ok(value.to_owned_value())".into()),
+        );
+        format!("rt::lambda_fn(|args| {function})")
+    }
+
+    /// A chain of operators, subscripts, attributes, and tests, evaluated in `mode` like the
+    /// tree-walker's `_evaluate_chain`. Each link rebinds `value` to what it evaluates to, so long
+    /// chains don't nest the generated code. In modes other than `Mode::Value`, `value` is an
+    /// `Option<Value>` that is `None` if a variable, attribute, or item in it is missing.
+    fn chain(&mut self, node: &Node, mode: Mode) -> String {
+        let mut links = Vec::new();
+        let (mut node, mut mode) = (node, mode);
+        while let Some(operand) = node.operand(mode) {
+            links.push((node, mode));
+            (node, mode) = operand;
+        }
+        let (value, defined) = match node {
+            Node::Variable(identifier) if mode != Mode::Value => match self.local(identifier) {
+                Some(local) => (format!("Some({local}.clone())"), true),
+                None => (
+                    format!("rt::global_defined(variables, {identifier:?})"),
+                    true,
+                ),
+            },
+            node => (self.expr(node), false),
+        };
+        let mut statements = vec![format!("let value = {value};")];
+        statements.extend(keep(mode, defined));
+        for (node, mode) in links.into_iter().rev() {
+            let (value, defined) = self.link(node, mode);
+            statements.push(format!("let value = {value};"));
+            statements.extend(keep(mode, defined));
+        }
+        block(statements, Some("value".into()))
+    }
+
+    /// A link of a chain, given its operand in `value`, and whether it evaluates to an
+    /// `Option<Value>` rather than a `Value`.
+    fn link(&mut self, node: &Node, mode: Mode) -> (String, bool) {
+        let value = match node {
+            Node::Operation(_, Operator::Coalesce, rhs) => format!(
+                "match value {{\n    Some(value) => value,\n    None => {},\n}}",
+                indent_rest(&self.expr(rhs))
+            ),
+            // `&&` and `||` short-circuit.
+            Node::Operation(_, op @ (Operator::And | Operator::Or), rhs) => {
+                let rhs = format!("Value::from(rt::truthy(&{}))", self.expr(rhs));
+                let (then_value, else_value) = match op {
+                    Operator::And => (rhs, "Value::from(false)".to_owned()),
+                    _ => ("Value::from(true)".to_owned(), rhs),
+                };
+                if_else("rt::truthy(&value)", &then_value, &else_value)
+            }
+            Node::Operation(_, op, rhs) => {
+                let rhs = self.expr(rhs);
+                let value = match op {
+                    Operator::Custom(symbol) => {
+                        format!("rt::custom_operation(&value, {symbol:?}, &rhs, environment)?")
+                    }
+                    op => format!("rt::operate(&value, &Operator::{op:?}, &rhs, environment)?"),
+                };
+                block(
+                    vec![format!("let rhs = {rhs};")],
+                    Some(format!("Value::Owned({value})")),
+                )
+            }
+            Node::Index(_, index) if mode == Mode::Value => block(
+                vec![format!("let index = {};", self.expr(index))],
+                Some("rt::index(value, &index)?".into()),
+            ),
+            Node::Slice(_, start, end, step) => {
+                let mut statements = Vec::new();
+                for (name, bound) in [("start", start), ("end", end), ("step", step)] {
+                    statements.push(match bound {
+                        Some(bound) => format!("let {name} = rt::bound({})?;", self.expr(bound)),
+                        None => format!("let {name} = None;"),
+                    });
+                }
+                block(
+                    statements,
+                    Some("Value::Owned(value.inner().slice(start, end, step)?)".into()),
+                )
+            }
+            Node::Attribute(_, attribute) if mode == Mode::Value => {
+                format!("rt::attribute(value, {attribute:?})?")
+            }
+            Node::Index(_, index) | Node::OptionalIndex(_, index) => {
+                let some = block(
+                    vec![format!("let index = {};", self.expr(index))],
+                    Some("rt::index_defined(value, &index)?".into()),
+                );
+                let value = format!(
+                    "match value {{\n    None => None,\n    Some(value) => {},\n}}",
+                    indent_rest(&some)
+                );
+                return (value, true);
+            }
+            Node::Attribute(_, attribute) | Node::OptionalAttribute(_, attribute) => {
+                let value = format!(
+                    "match value {{\n    None => None,\n    Some(value) => rt::attribute_defined(value, {attribute:?})?,\n}}"
+                );
+                return (value, true);
+            }
+            Node::Test(_, test, args, named_args) => {
+                let positional = args.iter().map(|node| self.expr(node)).collect();
+                let named = named_args
+                    .iter()
+                    .map(|(name, node)| format!("(String::from({name:?}), {})", self.expr(node)))
+                    .collect();
+                block(
+                    vec![format!(
+                        "let args = Arguments {{\n    positional: {},\n    named: {},\n}};",
+                        indent_rest(&list(positional)),
+                        indent_rest(&list(named)),
+                    )],
+                    Some(format!(
+                        "Value::from(rt::test({test:?}, value, args, environment)?)"
+                    )),
+                )
+            }
+            _ => unreachable!("only links of chains have operands"),
+        };
+        (value, false)
+    }
+
+    /// The Rust variable of the innermost local variable called `identifier`.
+    fn local(&self, identifier: &str) -> Option<&str> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|(name, _)| name == identifier)
+            .map(|(_, local)| local.as_str())
+    }
+}
+
+/// The statement that turns what a link of a chain evaluated to into what `mode` expects, if it
+/// needs one. `defined` is whether it evaluated to an `Option<Value>`.
+fn keep(mode: Mode, defined: bool) -> Option<String> {
+    let value = match (mode, defined) {
+        (Mode::Value, true) => "value.unwrap_or(Value::Owned(OwnedValue::Null))",
+        (Mode::Defined, false) => "Some(value)",
+        (Mode::Optional, false) => "rt::optional(Some(value))",
+        (Mode::Optional, true) => "rt::optional(value)",
+        (Mode::Value, false) | (Mode::Defined, true) => return None,
+    };
+    Some(format!("let value = {value};"))
+}
+
+/// An expression that returns an error from the generated function or lambda.
+fn fail(message: &str) -> String {
+    format!("return Err(rt::fail({message:?}))")
+}
+
+fn owned_value(value: &OwnedValue) -> String {
+    match value {
+        OwnedValue::String(string) => format!("OwnedValue::String(String::from({string:?}))"),
+        OwnedValue::Number(number) => format!("OwnedValue::Number({})", number_literal(*number)),
+        OwnedValue::Boolean(boolean) => format!("OwnedValue::Boolean({boolean})"),
+        OwnedValue::Array(values) => format!(
+            "OwnedValue::Array(vec![{}])",
+            values
+                .iter()
+                .map(owned_value)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        OwnedValue::Range(range) => format!(
+            "OwnedValue::Range(rt::range({}, {}, {}, {}))",
+            number_literal(range.start()),
+            number_literal(range.end()),
+            number_literal(range.step()),
+            range.is_inclusive()
+        ),
+        OwnedValue::Object(object) => format!(
+            "OwnedValue::Object(::std::collections::BTreeMap::from([{}]))",
+            object
+                .iter()
+                .map(|(key, value)| format!("(String::from({key:?}), {})", owned_value(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        OwnedValue::Null => "OwnedValue::Null".into(),
+    }
+}
+
+/// `Debug` prints floats so that they parse back to the same value.
+fn number_literal(number: f64) -> String {
+    if number.is_nan() {
+        "f64::NAN".into()
+    } else if number == f64::INFINITY {
+        "f64::INFINITY".into()
+    } else if number == f64::NEG_INFINITY {
+        "f64::NEG_INFINITY".into()
+    } else {
+        format!("{number:?}_f64")
+    }
+}
+
+/// A block of statements, each of which may span lines, with an optional final expression.
+fn block(mut statements: Vec<String>, tail: Option<String>) -> String {
+    statements.extend(tail);
+    format!("{{\n{}\n}}", indent(&statements.join("\n")))
+}
+
+fn if_else(condition: &str, then_value: &str, else_value: &str) -> String {
+    format!(
+        "if {condition} {{\n{}\n}} else {{\n{}\n}}",
+        indent(then_value),
+        indent(else_value)
+    )
+}
+
+fn list(items: Vec<String>) -> String {
+    match items.is_empty() {
+        true => "Vec::new()".into(),
+        false => format!("vec![\n{},\n]", indent(&items.join(",\n"))),
+    }
+}
+
+fn indent(code: &str) -> String {
+    code.lines()
+        .map(|line| match line.is_empty() {
+            true => String::new(),
+            false => format!("    {line}"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Indents every line but the first, for code that starts on a line that is already indented.
+fn indent_rest(code: &str) -> String {
+    code.replace('\n', "\n    ")
+}
@@ -0,0 +1,687 @@
+use std::collections::HashMap;
+
+use crate::{
+    lexer::Operator,
+    node::{Mode, Node},
+    value::OwnedValue,
+};
+
+/// A template compiled by [`Node::compile`] into instructions for a stack machine. Programs
+/// render the same output and errors as the tree they were compiled from. Local variables are
+/// resolved to slots ahead of time, and nothing is cloned per loop.
+///
+/// The nesting limit of [`Environment::set_max_depth`](crate::Environment::set_max_depth) is
+/// checked as the program runs, so a part nested too deeply only fails if it is reached, like it
+/// does with the tree-walker.
+#[derive(Debug)]
+pub struct Program {
+    pub(crate) instructions: Vec<Instruction>,
+    pub(crate) constants: Vec<OwnedValue>,
+    /// Variables, attributes, and operator symbols.
+    pub(crate) names: Vec<String>,
+    pub(crate) operators: Vec<Operator>,
+    pub(crate) calls: Vec<Call>,
+    pub(crate) lambdas: Vec<LambdaCode>,
+    /// The number of local variable slots.
+    pub(crate) slots: usize,
+}
+
+/// A function call or a test. Arguments are pushed in order, except for lambdas, which are built
+/// when the call is made.
+#[derive(Debug)]
+pub(crate) struct Call {
+    pub(crate) name: String,
+    /// For each argument, positional first, the lambda that it is, if any.
+    pub(crate) args: Vec<Option<usize>>,
+    /// The names of the trailing named arguments.
+    pub(crate) named: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct LambdaCode {
+    /// The slots that the arguments are stored in.
+    pub(crate) params: Vec<usize>,
+    /// Where the body starts. It ends with [`Instruction::Return`].
+    pub(crate) start: usize,
+}
+
+/// Operands are indices into the tables of the [`Program`], slots, and jump targets.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Instruction {
+    Constant(usize),
+    Null,
+    Local(usize),
+    Global(usize),
+    /// Pushes a variable, or jumps if it is undefined.
+    GlobalDefined(usize, usize),
+    /// Appends the string form of a constant to the output.
+    Text(usize),
+    /// Pops a value and appends its string form to the output.
+    Write,
+    /// Starts a new output, which nested writes go to.
+    PushBuffer,
+    /// Ends the current output and pushes it as a string.
+    PopBuffer,
+    /// Pushes an empty string for [`Instruction::Append`].
+    NewString,
+    /// Pops a value and appends its string form to the string below it.
+    Append,
+    /// Pushes an empty array for [`Instruction::Push`] and [`Instruction::Extend`].
+    NewArray,
+    /// Pops a value and pushes it onto the array below it.
+    Push,
+    /// Pops an array or range and pushes its items onto the array below it.
+    Extend,
+    Jump(usize),
+    /// Pops a value and jumps if it is falsy.
+    JumpIfFalse(usize),
+    /// Pops the value and jumps if it is null. Otherwise, leaves it.
+    JumpIfNull(usize),
+    /// `&&` if false, `||` if true. Pops the left-hand side, and if it decides the result, pushes
+    /// the result and jumps.
+    ShortCircuit(bool, usize),
+    /// Replaces the value with whether it is truthy.
+    Truthy,
+    Operation(usize),
+    Not,
+    Negate,
+    /// Fails if the prefix operator is undefined, before its operand is evaluated.
+    CheckPrefix(usize),
+    Prefix(usize),
+    Index,
+    /// Pops the index and the value, and pushes the item, or jumps if it is missing.
+    IndexDefined(usize),
+    Attribute(usize),
+    /// Pops the object and pushes the attribute, or jumps if it is missing.
+    AttributeDefined(usize, usize),
+    /// Checks that a slice bound is a number, or null for no bound.
+    Bound,
+    /// Pops the bounds that are present and the sliced value.
+    Slice(bool, bool, bool),
+    Call(usize),
+    /// Pops the arguments, whether the tested value is defined, and the tested value, which is null
+    /// if it isn't.
+    Test(usize),
+    /// Pops an array or range and starts iterating over its items. Items that are collected, like
+    /// those of a comprehension, may only come from a range as long as a spread may be.
+    Iterate(bool),
+    /// Stores the next item in a slot, or jumps once there are none left.
+    Next(usize, usize),
+    EndIterate,
+    /// Starts a `for` loop over the items that [`Instruction::Iterate`] started. The loop ends at
+    /// the target.
+    EnterLoop(usize),
+    /// Pops the separator that is written before the current item.
+    Separator,
+    /// Adds the current output to the loop's and goes to the next item.
+    EndIteration,
+    /// Ends the loop and pushes its output.
+    ExitLoop,
+    /// Unwinds to the loop at the index and ends it.
+    Break(usize),
+    /// Unwinds to the loop at the index and goes to the next item.
+    Continue(usize),
+    /// Fails if the tree-walker would be nested deeper than the environment allows at this level.
+    Depth(usize),
+    Fail(&'static str),
+    Return,
+}
+
+impl Instruction {
+    /// Whether the instruction neither fails nor jumps, so that checking the depth of the nodes
+    /// before it can wait until after it.
+    fn is_pure(self) -> bool {
+        matches!(
+            self,
+            Instruction::Constant(_)
+                | Instruction::Null
+                | Instruction::Local(_)
+                | Instruction::Text(_)
+                | Instruction::PushBuffer
+                | Instruction::PopBuffer
+                | Instruction::NewString
+                | Instruction::NewArray
+                | Instruction::Truthy
+                | Instruction::Not
+        )
+    }
+}
+
+impl Node {
+    /// Compiles the template into a [`Program`] that renders like [`Node::evaluate`].
+    pub fn compile(&self) -> Program {
+        let mut compiler = Compiler {
+            program: Program {
+                instructions: Vec::new(),
+                constants: Vec::new(),
+                names: Vec::new(),
+                operators: Vec::new(),
+                calls: Vec::new(),
+                lambdas: Vec::new(),
+                slots: 0,
+            },
+            names: HashMap::new(),
+            scope: Vec::new(),
+            loops: 0,
+            break_target: None,
+            in_lambda: false,
+            depth: 0,
+            pending: 0,
+            checked: 0,
+        };
+        compiler.compile(self);
+        compiler.emit(Instruction::Return);
+        compiler.program
+    }
+}
+
+/// Compiles nodes in the same order that the tree-walker evaluates them.
+struct Compiler {
+    program: Program,
+    names: HashMap<String, usize>,
+    /// The local variables in scope, innermost last, with their slots.
+    scope: Vec<(String, usize)>,
+    /// The number of loops running at this point of the current function.
+    loops: usize,
+    /// The loop that `break` and `continue` unwind to.
+    break_target: Option<usize>,
+    in_lambda: bool,
+    /// The level that the tree-walker evaluates the node being compiled at.
+    depth: usize,
+    /// The deepest level entered since the last [`Instruction::Depth`].
+    pending: usize,
+    /// The deepest level that has been checked on every way to the next instruction.
+    checked: usize,
+}
+
+impl Compiler {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        if !instruction.is_pure() {
+            self.flush();
+        }
+        self.program.instructions.push(instruction);
+        self.program.instructions.len() - 1
+    }
+
+    /// Enters a node at the current level. The tree-walker checks the level of every node before
+    /// it evaluates it, which only matters once something can fail, so the check waits for the
+    /// next instruction that can fail or jump, and covers every node entered until then.
+    fn enter(&mut self) {
+        self.pending = self.pending.max(self.depth);
+    }
+
+    /// Checks the levels entered since the last check.
+    fn flush(&mut self) {
+        if self.pending > self.checked {
+            self.program
+                .instructions
+                .push(Instruction::Depth(self.pending));
+            self.checked = self.pending;
+        }
+        self.pending = 0;
+    }
+
+    /// Starts instructions that are jumped to, where it isn't known what was checked before.
+    fn label(&mut self) {
+        self.flush();
+        self.checked = 0;
+    }
+
+    /// Points jumps at the next instruction.
+    fn patch(&mut self, jumps: impl IntoIterator<Item = usize>) {
+        self.label();
+        let target = self.program.instructions.len();
+        for jump in jumps {
+            match &mut self.program.instructions[jump] {
+                Instruction::GlobalDefined(_, to)
+                | Instruction::Jump(to)
+                | Instruction::JumpIfFalse(to)
+                | Instruction::JumpIfNull(to)
+                | Instruction::ShortCircuit(_, to)
+                | Instruction::IndexDefined(to)
+                | Instruction::AttributeDefined(_, to)
+                | Instruction::Next(_, to)
+                | Instruction::EnterLoop(to) => *to = target,
+                instruction => unreachable!("{instruction:?} doesn't jump"),
+            }
+        }
+    }
+
+    fn constant(&mut self, value: OwnedValue) -> usize {
+        self.program.constants.push(value);
+        self.program.constants.len() - 1
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        if let Some(&index) = self.names.get(name) {
+            return index;
+        }
+        self.program.names.push(name.to_owned());
+        self.names
+            .insert(name.to_owned(), self.program.names.len() - 1);
+        self.program.names.len() - 1
+    }
+
+    fn bind(&mut self, identifier: &str) -> usize {
+        let slot = self.program.slots;
+        self.program.slots += 1;
+        self.scope.push((identifier.to_owned(), slot));
+        slot
+    }
+
+    /// The slot of the innermost local variable called `identifier`.
+    fn local(&self, identifier: &str) -> Option<usize> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|(name, _)| name == identifier)
+            .map(|&(_, slot)| slot)
+    }
+
+    fn interrupt(&mut self, is_break: bool) {
+        match self.break_target {
+            Some(target) if is_break => self.emit(Instruction::Break(target)),
+            Some(target) => self.emit(Instruction::Continue(target)),
+            None if self.in_lambda => self.emit(Instruction::Fail(
+                "`break` or `continue` inside of a lambda",
+            )),
+            None => self.emit(Instruction::Fail(
+                "`break` or `continue` outside of a for loop",
+            )),
+        };
+    }
+
+    /// Compiles a node whose string form is appended to the output, like the children of a body.
+    fn write(&mut self, node: &Node) {
+        self.enter();
+        match node {
+            Node::Body(nodes) => {
+                self.depth += 1;
+                for node in nodes {
+                    self.write(node);
+                }
+                self.depth -= 1;
+            }
+            // A range with too many items to write fails when the template renders.
+            Node::Value(value) if value.to_text().is_ok() => {
+                let text = match value {
+                    OwnedValue::String(string) => string.clone(),
+                    value => value.to_string(),
+                };
+                let constant = self.constant(OwnedValue::String(text));
+                self.emit(Instruction::Text(constant));
+            }
+            Node::IfThenElse(..) => {
+                // An `elif` is the `else` of the branch before it, so the branches are compiled in
+                // a loop.
+                self.depth += 1;
+                let mut ends = Vec::new();
+                let mut else_node = Some(node);
+                while let Some(Node::IfThenElse(condition, then_node, next)) = else_node {
+                    self.compile(condition);
+                    let jump = self.emit(Instruction::JumpIfFalse(0));
+                    self.write(then_node);
+                    if next.is_some() {
+                        ends.push(self.emit(Instruction::Jump(0)));
+                    }
+                    self.patch([jump]);
+                    else_node = next.as_deref();
+                }
+                if let Some(else_node) = else_node {
+                    self.write(else_node);
+                }
+                self.patch(ends);
+                self.depth -= 1;
+            }
+            Node::Break | Node::Continue => {
+                self.interrupt(matches!(node, Node::Break));
+            }
+            node => {
+                self.compile(node);
+                self.emit(Instruction::Write);
+            }
+        }
+    }
+
+    /// Compiles a node that pushes its value.
+    fn compile(&mut self, node: &Node) {
+        self.enter();
+        if node.operand(Mode::Value).is_some() {
+            return self.chain(node, Mode::Value, &mut Vec::new());
+        }
+        self.depth += 1;
+        match node {
+            Node::Body(nodes) => {
+                self.emit(Instruction::PushBuffer);
+                for node in nodes {
+                    self.write(node);
+                }
+                self.emit(Instruction::PopBuffer);
+            }
+            Node::Value(value) => {
+                let constant = self.constant(value.clone());
+                self.emit(Instruction::Constant(constant));
+            }
+            Node::Variable(identifier) => {
+                let instruction = match self.local(identifier) {
+                    Some(slot) => Instruction::Local(slot),
+                    None => Instruction::Global(self.name(identifier)),
+                };
+                self.emit(instruction);
+            }
+            Node::FunctionCall(identifier, args, named_args) => {
+                let args = args.iter().chain(named_args.iter().map(|(_, node)| node));
+                let args = args
+                    .map(|node| match node {
+                        Node::Lambda(params, body) => Some(self.lambda(params, body)),
+                        node => {
+                            self.compile(node);
+                            None
+                        }
+                    })
+                    .collect();
+                let call = Call {
+                    name: identifier.clone(),
+                    args,
+                    named: named_args.iter().map(|(name, _)| name.clone()).collect(),
+                };
+                self.program.calls.push(call);
+                self.emit(Instruction::Call(self.program.calls.len() - 1));
+            }
+            Node::Array(nodes) => {
+                self.emit(Instruction::NewArray);
+                for node in nodes {
+                    match node {
+                        Node::Spread(items) => {
+                            self.compile(items);
+                            self.emit(Instruction::Extend);
+                        }
+                        node => {
+                            self.compile(node);
+                            self.emit(Instruction::Push);
+                        }
+                    }
+                }
+            }
+            Node::Concat(parts) => {
+                self.emit(Instruction::NewString);
+                for part in parts {
+                    self.compile(part);
+                    self.emit(Instruction::Append);
+                }
+            }
+            Node::Spread(_) => {
+                self.emit(Instruction::Fail("Spreads can only be used in arrays"));
+            }
+            Node::Comprehension(identifier, array, element, filter) => {
+                self.compile(array);
+                self.emit(Instruction::Iterate(true));
+                self.emit(Instruction::NewArray);
+                let slot = self.bind(identifier);
+                self.label();
+                let next = self.emit(Instruction::Next(slot, 0));
+                if let Some(filter) = filter {
+                    self.compile(filter);
+                    self.emit(Instruction::JumpIfFalse(next));
+                }
+                self.compile(element);
+                self.emit(Instruction::Push);
+                self.emit(Instruction::Jump(next));
+                self.scope.pop();
+                self.patch([next]);
+                self.emit(Instruction::EndIterate);
+            }
+            Node::Not(node) => {
+                self.compile(node);
+                self.emit(Instruction::Not);
+            }
+            Node::Negate(node) => {
+                self.compile(node);
+                self.emit(Instruction::Negate);
+            }
+            Node::PrefixOperation(symbol, node) => {
+                let symbol = self.name(symbol);
+                self.emit(Instruction::CheckPrefix(symbol));
+                self.compile(node);
+                self.emit(Instruction::Prefix(symbol));
+            }
+            Node::IfThenElse(..) => {
+                let mut ends = Vec::new();
+                let mut else_node = Some(node);
+                while let Some(Node::IfThenElse(condition, then_node, next)) = else_node {
+                    self.compile(condition);
+                    let jump = self.emit(Instruction::JumpIfFalse(0));
+                    self.compile(then_node);
+                    ends.push(self.emit(Instruction::Jump(0)));
+                    self.patch([jump]);
+                    else_node = next.as_deref();
+                }
+                match else_node {
+                    Some(else_node) => self.compile(else_node),
+                    None => {
+                        let empty = self.constant(OwnedValue::String(String::new()));
+                        self.emit(Instruction::Constant(empty));
+                    }
+                }
+                self.patch(ends);
+            }
+            Node::ForIn(identifier, array, body, separator, filter) => {
+                self.compile(array);
+                self.emit(Instruction::Iterate(false));
+                let enter = self.emit(Instruction::EnterLoop(0));
+                let slot = self.bind(identifier);
+                self.label();
+                let next = self.emit(Instruction::Next(slot, 0));
+                // The filter and the separator run inside of the loop, but `break` in them ends an
+                // outer loop.
+                self.loops += 1;
+                if let Some(filter) = filter {
+                    self.compile(filter);
+                    self.emit(Instruction::JumpIfFalse(next));
+                }
+                if let Some(separator) = separator {
+                    self.compile(separator);
+                    self.emit(Instruction::Separator);
+                }
+                let break_target = self.break_target.replace(self.loops - 1);
+                self.emit(Instruction::PushBuffer);
+                self.write(body);
+                self.emit(Instruction::EndIteration);
+                self.break_target = break_target;
+                self.loops -= 1;
+                self.scope.pop();
+                self.patch([enter, next]);
+                self.emit(Instruction::ExitLoop);
+            }
+            Node::Break | Node::Continue => self.interrupt(matches!(node, Node::Break)),
+            Node::Error => {
+                self.emit(Instruction::Fail(
+                    "Cannot evaluate a template with syntax errors",
+                ));
+            }
+            Node::Lambda(..) => {
+                self.emit(Instruction::Fail("Lambdas can only be passed to functions"));
+            }
+            Node::Index(..)
+            | Node::Slice(..)
+            | Node::OptionalIndex(..)
+            | Node::Attribute(..)
+            | Node::OptionalAttribute(..)
+            | Node::Operation(..)
+            | Node::Test(..) => unreachable!("chains are compiled by `chain`"),
+        }
+        self.depth -= 1;
+    }
+
+    /// Compiles a lambda's body out of line, with its own loops.
+    fn lambda(&mut self, params: &[String], body: &Node) -> usize {
+        let skip = self.emit(Instruction::Jump(0));
+        let start = self.program.instructions.len();
+        let params = params
+            .iter()
+            .map(|param| self.bind(param))
+            .collect::<Vec<_>>();
+        let loops = std::mem::replace(&mut self.loops, 0);
+        let break_target = self.break_target.take();
+        let in_lambda = std::mem::replace(&mut self.in_lambda, true);
+        self.compile(body);
+        self.emit(Instruction::Return);
+        self.loops = loops;
+        self.break_target = break_target;
+        self.in_lambda = in_lambda;
+        self.scope.truncate(self.scope.len() - params.len());
+        self.patch([skip]);
+        self.program.lambdas.push(LambdaCode { params, start });
+        self.program.lambdas.len() - 1
+    }
+
+    /// Compiles `node` like the tree-walker's `_evaluate_chain`: pushes its value, or, unless
+    /// `mode` wants a value, jumps to one of `missing` if a variable, attribute, or item in it is
+    /// missing. The chain is followed down to its first operand and compiled back up in a loop.
+    fn chain(&mut self, node: &Node, mode: Mode, missing: &mut Vec<usize>) {
+        // The jumps taken when something is missing, starting with `missing`. Every link that
+        // wants its operand to be defined starts a list of its own, which the links below it that
+        // don't want a value add to as well.
+        let mut lists = vec![std::mem::take(missing)];
+        let mut links = Vec::new();
+        let (mut node, mut mode, mut list) = (node, mode, 0);
+        while let Some((operand, operand_mode)) = node.operand(mode) {
+            let operand_list = match node {
+                Node::Index(..)
+                | Node::OptionalIndex(..)
+                | Node::Attribute(..)
+                | Node::OptionalAttribute(..)
+                    if mode != Mode::Value =>
+                {
+                    list
+                }
+                _ if operand_mode != Mode::Value => {
+                    lists.push(Vec::new());
+                    lists.len() - 1
+                }
+                _ => list,
+            };
+            links.push((node, mode, list, operand_list));
+            (node, mode, list) = (operand, operand_mode, operand_list);
+        }
+        match node {
+            Node::Variable(identifier) if mode != Mode::Value => match self.local(identifier) {
+                Some(slot) => {
+                    self.emit(Instruction::Local(slot));
+                }
+                None => {
+                    let name = self.name(identifier);
+                    lists[list].push(self.emit(Instruction::GlobalDefined(name, 0)));
+                }
+            },
+            node => self.compile(node),
+        }
+        if mode == Mode::Optional {
+            lists[list].push(self.emit(Instruction::JumpIfNull(0)));
+        }
+        // Like the tree-walker, the operands are at the level of the chain, and only the other
+        // parts of the links are a level deeper.
+        self.depth += 1;
+        for (node, mode, list, operand_list) in links.into_iter().rev() {
+            self.link(node, mode, &mut lists, list, operand_list);
+        }
+        self.depth -= 1;
+        *missing = lists.swap_remove(0);
+    }
+
+    /// Compiles the rest of a link of a chain, once its operand has been pushed. A missing value
+    /// jumps to `lists[list]`, and the jumps in `lists[operand_list]` lead here if the operand was
+    /// missing.
+    fn link(
+        &mut self,
+        node: &Node,
+        mode: Mode,
+        lists: &mut [Vec<usize>],
+        list: usize,
+        operand_list: usize,
+    ) {
+        match node {
+            Node::Attribute(_, attribute) | Node::OptionalAttribute(_, attribute)
+                if mode != Mode::Value =>
+            {
+                let attribute = self.name(attribute);
+                lists[list].push(self.emit(Instruction::AttributeDefined(attribute, 0)));
+            }
+            Node::Index(_, index) | Node::OptionalIndex(_, index) if mode != Mode::Value => {
+                self.compile(index);
+                lists[list].push(self.emit(Instruction::IndexDefined(0)));
+            }
+            Node::Index(_, index) => {
+                self.compile(index);
+                self.emit(Instruction::Index);
+            }
+            Node::Slice(_, start, end, step) => {
+                for bound in [start, end, step].into_iter().flatten() {
+                    self.compile(bound);
+                    self.emit(Instruction::Bound);
+                }
+                self.emit(Instruction::Slice(
+                    start.is_some(),
+                    end.is_some(),
+                    step.is_some(),
+                ));
+            }
+            Node::Attribute(_, attribute) => {
+                let attribute = self.name(attribute);
+                self.emit(Instruction::Attribute(attribute));
+            }
+            // `?.` and `?[]` are compiled as if their value were optional, and then anything
+            // missing is null.
+            Node::OptionalIndex(..) | Node::OptionalAttribute(..) => {
+                self.link(node, Mode::Optional, lists, operand_list, operand_list);
+                let end = self.emit(Instruction::Jump(0));
+                self.patch(std::mem::take(&mut lists[operand_list]));
+                self.emit(Instruction::Null);
+                self.patch([end]);
+            }
+            Node::Operation(_, Operator::Coalesce, rhs) => {
+                let end = self.emit(Instruction::Jump(0));
+                self.patch(std::mem::take(&mut lists[operand_list]));
+                self.compile(rhs);
+                self.patch([end]);
+            }
+            Node::Operation(_, op @ (Operator::And | Operator::Or), rhs) => {
+                let end = self.emit(Instruction::ShortCircuit(*op == Operator::Or, 0));
+                self.compile(rhs);
+                self.emit(Instruction::Truthy);
+                self.patch([end]);
+            }
+            Node::Operation(_, op, rhs) => {
+                self.compile(rhs);
+                self.program.operators.push(op.clone());
+                self.emit(Instruction::Operation(self.program.operators.len() - 1));
+            }
+            Node::Test(_, test, args, named_args) => {
+                self.program.calls.push(Call {
+                    name: test.clone(),
+                    args: vec![None; args.len() + named_args.len()],
+                    named: named_args.iter().map(|(name, _)| name.clone()).collect(),
+                });
+                let call = self.program.calls.len() - 1;
+                // Whether the value is defined is kept on the stack, so that the arguments are
+                // only compiled once.
+                let defined = self.constant(OwnedValue::Boolean(true));
+                self.emit(Instruction::Constant(defined));
+                let skip = self.emit(Instruction::Jump(0));
+                self.patch(std::mem::take(&mut lists[operand_list]));
+                self.emit(Instruction::Null);
+                let undefined = self.constant(OwnedValue::Boolean(false));
+                self.emit(Instruction::Constant(undefined));
+                self.patch([skip]);
+                for node in args.iter().chain(named_args.iter().map(|(_, node)| node)) {
+                    self.compile(node);
+                }
+                self.emit(Instruction::Test(call));
+            }
+            node => unreachable!("{node:?} doesn't continue a chain"),
+        }
+        if mode == Mode::Optional {
+            lists[list].push(self.emit(Instruction::JumpIfNull(0)));
+        }
+    }
+}
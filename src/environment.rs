@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use crate::{
+    arguments::Arguments,
+    error::ValueError,
+    operators::{self, Associativity, InfixOperator, PrefixOperator},
+    parser::DEFAULT_MAX_DEPTH,
+    value::OwnedValue,
+};
+
+type Function = dyn Fn(Arguments) -> Result<OwnedValue, ValueError>;
+type Test = dyn Fn(&OwnedValue, Arguments) -> Result<bool, ValueError>;
+
+/// Everything besides variables that templates can call into: functions (`{{ pad(x) }}`), tests
+/// (`{{ if x is prime }}`), and operators (`{{ x ** 2 }}`). Functions and tests registered here
+/// take precedence over the builtin ones with the same name.
+pub struct Environment {
+    functions: HashMap<String, Box<Function>>,
+    tests: HashMap<String, Box<Test>>,
+    infix_operators: HashMap<String, InfixOperator>,
+    prefix_operators: HashMap<String, PrefixOperator>,
+    max_depth: usize,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self {
+            functions: HashMap::new(),
+            tests: HashMap::new(),
+            infix_operators: HashMap::new(),
+            prefix_operators: HashMap::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a function. Errors it returns, such as those of the lambdas passed to it, fail
+    /// the render.
+    pub fn add_function(
+        &mut self,
+        name: impl Into<String>,
+        function: impl Fn(Arguments) -> Result<OwnedValue, ValueError> + 'static,
+    ) {
+        self.functions.insert(name.into(), Box::new(function));
+    }
+
+    /// Registers a test, which is called with the tested value and the test's arguments. Tests
+    /// are never called with undefined values; those fail every test but `undefined`. Errors it
+    /// returns, such as for values it can't check, fail the render.
+    pub fn add_test(
+        &mut self,
+        name: impl Into<String>,
+        test: impl Fn(&OwnedValue, Arguments) -> Result<bool, ValueError> + 'static,
+    ) {
+        self.tests.insert(name.into(), Box::new(test));
+    }
+
+    /// Registers an infix operator such as `a ** b` or `a mod b`. The precedence is relative to
+    /// the builtin operators in [`precedence`](crate::precedence). Symbols made of punctuation
+    /// take precedence over builtin ones that start the same way, so `**` is never lexed as `*`.
+    /// Templates using custom operators must be parsed with this environment.
+    pub fn add_infix_operator(
+        &mut self,
+        symbol: impl Into<String>,
+        precedence: u8,
+        associativity: Associativity,
+        evaluate: impl Fn(&OwnedValue, &OwnedValue) -> Result<OwnedValue, ValueError> + 'static,
+    ) {
+        let operator = InfixOperator {
+            precedence,
+            associativity,
+            evaluate: Box::new(evaluate),
+        };
+        self.infix_operators.insert(symbol.into(), operator);
+    }
+
+    /// Registers a prefix operator such as `~a`. Its operand is parsed with the given precedence,
+    /// so infix operators with a higher precedence bind tighter than the prefix operator.
+    pub fn add_prefix_operator(
+        &mut self,
+        symbol: impl Into<String>,
+        precedence: u8,
+        evaluate: impl Fn(&OwnedValue) -> Result<OwnedValue, ValueError> + 'static,
+    ) {
+        let operator = PrefixOperator {
+            precedence,
+            evaluate: Box::new(evaluate),
+        };
+        self.prefix_operators.insert(symbol.into(), operator);
+    }
+
+    /// Sets how deeply evaluation may recurse before failing with
+    /// [`ValueError::NestingTooDeep`]. Defaults to 64. Lambdas called from functions count
+    /// from where they were created.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub(crate) fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub(crate) fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.get(name).map(|function| &**function)
+    }
+
+    pub(crate) fn test(&self, name: &str) -> Option<&Test> {
+        self.tests.get(name).map(|test| &**test)
+    }
+
+    pub(crate) fn infix_operator(&self, symbol: &str) -> Option<&InfixOperator> {
+        self.infix_operators.get(symbol)
+    }
+
+    pub(crate) fn prefix_operator(&self, symbol: &str) -> Option<&PrefixOperator> {
+        self.prefix_operators.get(symbol)
+    }
+
+    /// The custom operators the lexer has to recognize, longest first so that `**` wins over `*`.
+    pub(crate) fn operator_symbols(&self) -> Vec<String> {
+        let mut symbols: Vec<String> = self
+            .infix_operators
+            .keys()
+            .chain(self.prefix_operators.keys())
+            .filter(|symbol| !symbol.is_empty() && !operators::is_word(symbol))
+            .cloned()
+            .collect();
+        symbols.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        symbols.dedup();
+        symbols
+    }
+}
@@ -1,8 +1,11 @@
-use std::num::ParseFloatError;
+use std::{fmt, io, num::ParseFloatError};
 
 use thiserror::Error;
 
-use crate::{lexer::Token, value::OwnedValue};
+use crate::{
+    lexer::{Keyword, Token},
+    value::OwnedValue,
+};
 
 #[derive(Debug, Error)]
 pub enum LexerError {
@@ -24,17 +27,62 @@ pub enum ParseError {
     #[error("Lexer error: {0}")]
     LexerError(LexerError),
 
-    #[error("Unexpected EOF")]
-    UnexpectedEOF,
+    /// The field is what the parser would have accepted instead, such as `` "`)`" `` or
+    /// `"an expression"`.
+    #[error("Unexpected end of input{}", expected(.0))]
+    UnexpectedEOF(Vec<&'static str>),
+
+    /// The second field is what the parser would have accepted instead.
+    #[error("Unexpected {0}{}", expected(.1))]
+    UnexpectedToken(Token, Vec<&'static str>),
+
+    /// `a..b..c`
+    #[error("{0} cannot be chained without parentheses")]
+    NonAssociative(Token),
+
+    #[error("Positional argument after named arguments in call to {0:?}")]
+    PositionalAfterNamed(String),
+
+    #[error("Duplicate named argument {1:?} in call to {0:?}")]
+    DuplicateNamedArgument(String, String),
+
+    #[error("{0} outside of a for loop")]
+    OutsideLoop(Keyword),
 
-    #[error("Unexpected token: {0:?} while parsing {1:?}")]
-    UnexpectedToken(Token, &'static str),
+    #[error("Template is nested more than {0} levels deep")]
+    NestingTooDeep(usize),
+
+    /// Long chains of operators or `elif`s make the tree taller without nesting the template.
+    #[error("Template's syntax tree is more than {0} levels tall")]
+    TooTall(usize),
+
+    /// The first field is the closing tag as written. The second field is the block it was
+    /// expected to close. The third field is the line that block was opened on.
+    #[error("`{0}` does not close the `{1}` opened at line {2}")]
+    MismatchedEndTag(&'static str, &'static str, usize),
+
+    #[error("The `{0}` opened at line {1} is never closed")]
+    UnclosedBlock(&'static str, usize),
+
+    // Like `ExpectedToken`, this ends the block being parsed, and is only an error outside of one.
+    #[error("`{0}` does not close any block")]
+    EndTag(&'static str),
 
     // Sometimes we expect an unexpected token. (See `parse_if` and `parse_for`.)
-    #[error("Unexpected token: {0:?}")]
+    #[error("Unexpected {0}")]
     ExpectedToken(Token),
 }
 
+/// Formats the tokens a parser would have accepted as ", expected `a`, `b`, or `c`".
+fn expected(expected: &[&str]) -> String {
+    match expected {
+        [] => String::new(),
+        [only] => format!(", expected {only}"),
+        [first, second] => format!(", expected {first} or {second}"),
+        [rest @ .., last] => format!(", expected {}, or {last}", rest.join(", ")),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ValueError {
     #[error("{0}")]
@@ -43,6 +91,52 @@ pub enum ValueError {
     #[error("Undefined variable: {0:?}")]
     UndefinedVariable(String),
 
+    #[error("Undefined test: {0:?}")]
+    UndefinedTest(String),
+
+    #[error("Undefined operator: {0:?}")]
+    UndefinedOperator(String),
+
+    #[error("Evaluation is nested more than {0} levels deep")]
+    NestingTooDeep(usize),
+
     #[error("Cannot iterate over {0:?}")]
     IterateError(OwnedValue),
+
+    /// The writer that a template was being rendered into failed.
+    #[error("Cannot write output: {0}")]
+    WriteError(fmt::Error),
+
+    /// The `io::Write` that a template was being rendered into failed.
+    #[error("Cannot write output: {0}")]
+    IoError(io::Error),
+}
+
+/// Why [`Printer::print`](crate::Printer::print) couldn't print a template.
+#[derive(Debug, Error)]
+pub enum PrintError {
+    /// The tree has [`Node::Error`](crate::Node::Error)s where a recovering parse found syntax
+    /// errors, which have no source to print.
+    #[error("Cannot print a template with syntax errors")]
+    SyntaxError,
+}
+
+/// Why [`Node::from_bytes`](crate::Node::from_bytes) rejected a template cache.
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("Not a template cache")]
+    NotACache,
+
+    /// The fields are the crate version and the format version that wrote the cache.
+    #[error("Template cache was written by version {0} in format {1}, which is incompatible")]
+    IncompatibleVersion(String, usize),
+
+    #[error("Template cache ends unexpectedly")]
+    UnexpectedEnd,
+
+    #[error("Template cache is nested more than {0} levels deep")]
+    NestingTooDeep(usize),
+
+    #[error("Invalid template cache: {0}")]
+    Invalid(&'static str),
 }
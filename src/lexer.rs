@@ -1,4 +1,6 @@
-use crate::{error::LexerError, value::OwnedValue};
+use std::fmt;
+
+use crate::{environment::Environment, error::LexerError, value::OwnedValue};
 
 /// The lexer (a.k.a tokenizer) is responsible for converting the input into a one-dimensional
 /// series of tokens. For example, the input `3 + (4/2)` into the lexer would yield:
@@ -12,10 +14,14 @@ pub struct Lexer<'a> {
     src: &'a str,
     token_start_byte: usize,
     cursor: usize,
+    /// The line the cursor is on, starting at 1.
+    line: usize,
     is_inside_template: bool,
+    /// Custom operator symbols, longest first.
+    operators: Vec<String>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Text(String),
 
@@ -29,15 +35,37 @@ pub enum Token {
     ClosingSqBracket,
 
     Comma,
+    Colon,
     Exclamation,
+    Assign,
+    Arrow,
+    Dot,
+    /// `...`
+    Ellipsis,
+    /// `?.`
+    QuestionDot,
+    /// `?[`
+    QuestionSqBracket,
 
     Keyword(Keyword),
     Identifier(String),
     Literal(OwnedValue),
+    /// An operator registered with [`Environment::add_infix_operator`] or
+    /// [`Environment::add_prefix_operator`] that isn't spelled like an identifier.
+    CustomOperator(String),
+    /// `f"host {name}:{port}"`
+    InterpolatedString(Vec<StringPart>),
     Operator(Operator),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum StringPart {
+    Text(String),
+    /// The source code of an interpolated expression, without the braces.
+    Expression(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Keyword {
     If,
     Elif,
@@ -45,9 +73,14 @@ pub enum Keyword {
 
     For,
     In,
+    Not,
+    Is,
+    Break,
+    Continue,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Operator {
     Multiply,
     Divide,
@@ -57,6 +90,15 @@ pub enum Operator {
     IsNotEqualTo,
     And,
     Or,
+    Range,
+    RangeInclusive,
+    /// `in` isn't lexed as an operator because it's also part of the `for` syntax.
+    In,
+    NotIn,
+    /// `??`
+    Coalesce,
+    /// An operator registered with [`Environment::add_infix_operator`].
+    Custom(String),
 }
 
 impl<'a> Lexer<'a> {
@@ -65,22 +107,47 @@ impl<'a> Lexer<'a> {
             src,
             token_start_byte: 0,
             cursor: 0,
+            line: 1,
             is_inside_template: false,
+            operators: Vec::new(),
+        }
+    }
+
+    /// Makes the lexer recognize the custom operators registered in the environment.
+    pub fn with_environment(mut self, environment: &Environment) -> Self {
+        self.operators = environment.operator_symbols();
+        self
+    }
+
+    /// Creates a lexer for a bare expression, as if it were inside of `{{ }}`.
+    pub(crate) fn new_expression(src: &'a str) -> Self {
+        Self {
+            is_inside_template: true,
+            ..Self::new(src)
         }
     }
 
     pub fn yield_token(&mut self) -> Result<Option<Token>, LexerError> {
+        if self.is_inside_template {
+            let rest = &self.src[self.cursor..];
+            if let Some(symbol) = self
+                .operators
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+            {
+                let token = Token::CustomOperator(symbol.clone());
+                self.cursor += symbol.len();
+                self.end_token();
+                return Ok(Some(token));
+            }
+        }
         let next_char = match self.get_next_char() {
             None => return Ok(None),
             Some(next_char) => next_char,
         };
 
         let token = match next_char {
-            '{' if !self.is_inside_template => {
-                if self.get_if_is('{').is_none() {
-                    // False alarm; return next string.
-                    return self.yield_token();
-                }
+            '{' if !self.is_inside_template && self.get_if_is('{').is_some() => {
                 self.is_inside_template = true;
                 Token::TemplateOpen
             }
@@ -89,9 +156,29 @@ impl<'a> Lexer<'a> {
                 self.is_inside_template = false;
                 Token::TemplateClose
             }
-            'a'..='z' | 'A'..='Z' | '_' if self.is_inside_template => self.yield_identifier(),
+            'a'..='z' | 'A'..='Z' | '_' if self.is_inside_template => self.yield_identifier()?,
+            '.' if self.is_inside_template && self.get_if_is('.').is_some() => match self.peek() {
+                Some('=') => {
+                    self.get_next_char();
+                    Token::Operator(Operator::RangeInclusive)
+                }
+                Some('.') => {
+                    self.get_next_char();
+                    Token::Ellipsis
+                }
+                _ => Token::Operator(Operator::Range),
+            },
+            '.' if self.is_inside_template && !self.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                Token::Dot
+            }
             '0'..='9' | '.' if self.is_inside_template => self.yield_number()?,
-            '"' | '\'' => self.yield_string(next_char)?,
+            '?' if self.is_inside_template => match self.expect_next_char()? {
+                '?' => Token::Operator(Operator::Coalesce),
+                '.' => Token::QuestionDot,
+                '[' => Token::QuestionSqBracket,
+                c => return Err(LexerError::UnexpectedCharacter(c)),
+            },
+            '"' | '\'' if self.is_inside_template => self.yield_string(next_char)?,
             '(' if self.is_inside_template => Token::OpeningParen,
             ')' if self.is_inside_template => Token::ClosingParen,
             '[' if self.is_inside_template => Token::OpeningSqBracket,
@@ -101,10 +188,18 @@ impl<'a> Lexer<'a> {
             '+' if self.is_inside_template => Token::Operator(Operator::Add),
             '-' if self.is_inside_template => Token::Operator(Operator::Subtract),
             ',' if self.is_inside_template => Token::Comma,
-            '=' if self.is_inside_template => {
-                self.expect_char('=')?;
-                Token::Operator(Operator::IsEqualTo)
-            }
+            ':' if self.is_inside_template => Token::Colon,
+            '=' if self.is_inside_template => match self.peek() {
+                Some('=') => {
+                    self.get_next_char();
+                    Token::Operator(Operator::IsEqualTo)
+                }
+                Some('>') => {
+                    self.get_next_char();
+                    Token::Arrow
+                }
+                _ => Token::Assign,
+            },
             '!' if self.is_inside_template => match self.expect_next_char()? {
                 '=' => Token::Operator(Operator::IsNotEqualTo),
                 _ => {
@@ -125,10 +220,11 @@ impl<'a> Lexer<'a> {
                 return self.yield_token();
             }
             c if self.is_inside_template => return Err(LexerError::UnexpectedCharacter(c)),
+            // Text runs up to the next `{{`, so single braces are kept in the same text node, and
+            // text can be printed back exactly.
             _ => {
-                // Note: This will cause any single curly brace to start its own text node. This
-                // currently has no side effects.
-                self.advance_while(|c| c != '{');
+                while !self.src[self.cursor..].starts_with("{{") && self.get_next_char().is_some() {
+                }
                 Token::Text(self.get_slice().to_owned())
             }
         };
@@ -136,10 +232,18 @@ impl<'a> Lexer<'a> {
         Ok(Some(token))
     }
 
+    /// The line that the last token ended on.
+    pub(crate) fn line(&self) -> usize {
+        self.line
+    }
+
     fn get_next_char(&mut self) -> Option<char> {
         let next_char = self.peek();
         if let Some(next_char) = next_char {
             self.cursor += next_char.len_utf8();
+            if next_char == '\n' {
+                self.line += 1;
+            }
         }
         next_char
     }
@@ -175,21 +279,37 @@ impl<'a> Lexer<'a> {
         self.src[self.cursor..].chars().next()
     }
 
-    fn yield_identifier(&mut self) -> Token {
+    fn yield_identifier(&mut self) -> Result<Token, LexerError> {
         self.advance_while(|c| c == '_' || c.is_alphanumeric());
         let identifier = self.get_slice();
-        match identifier {
+        if identifier == "f" {
+            if let Some(quote @ ('"' | '\'')) = self.peek() {
+                self.get_next_char();
+                return self.yield_interpolated_string(quote);
+            }
+        }
+        let token = match identifier {
             "if" => Token::Keyword(Keyword::If),
             "elif" => Token::Keyword(Keyword::Elif),
             "else" => Token::Keyword(Keyword::Else),
             "for" => Token::Keyword(Keyword::For),
             "in" => Token::Keyword(Keyword::In),
+            "not" => Token::Keyword(Keyword::Not),
+            "is" => Token::Keyword(Keyword::Is),
+            "null" => Token::Literal(OwnedValue::Null),
+            "break" => Token::Keyword(Keyword::Break),
+            "continue" => Token::Keyword(Keyword::Continue),
             _ => Token::Identifier(identifier.to_owned()),
-        }
+        };
+        Ok(token)
     }
 
     fn yield_number(&mut self) -> Result<Token, LexerError> {
-        self.advance_while(|c| c == '.' || c.is_ascii_digit());
+        self.advance_while(|c| c.is_ascii_digit());
+        // Don't swallow the first dot of a range operator (`0..10`).
+        if !self.src[self.cursor..].starts_with("..") && self.get_if_is('.').is_some() {
+            self.advance_while(|c| c.is_ascii_digit());
+        }
         let slice = self.get_slice();
         let number = match slice.parse() {
             Err(err) => return Err(LexerError::NumberParseError(err)),
@@ -207,17 +327,11 @@ impl<'a> Lexer<'a> {
             match self.expect_next_char()? {
                 '\\' => {
                     let next = self.expect_next_char()?;
-                    let escape = match next {
-                        'n' => "\n",
-                        '\\' => "\\",
-                        '\n' => "",
-                        _ if next == quote => &quote.to_string(),
-                        _ => return Err(LexerError::UnrecognizedEscape(next)),
-                    };
+                    let escape = Self::unescape(next, quote)?;
 
                     self.cursor -= 2;
                     string += self.get_slice();
-                    string += escape;
+                    string.extend(escape);
                     self.cursor += 2;
 
                     self.end_token();
@@ -233,6 +347,74 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Lexes `f"host {name}:{port}"` into text and the source code of the interpolated
+    /// expressions, which the parser parses separately. `{{` and `}}` are literal braces.
+    fn yield_interpolated_string(&mut self, quote: char) -> Result<Token, LexerError> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        loop {
+            match self.expect_next_char()? {
+                '\\' => {
+                    let next = self.expect_next_char()?;
+                    text.extend(Self::unescape(next, quote)?);
+                }
+                '{' if self.get_if_is('{').is_none() => {
+                    if !text.is_empty() {
+                        parts.push(StringPart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(StringPart::Expression(self.take_interpolated_expression()?));
+                }
+                '}' => {
+                    self.expect_char('}')?;
+                    text.push('}');
+                }
+                c if c == quote => break,
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(StringPart::Text(text));
+        }
+        Ok(Token::InterpolatedString(parts))
+    }
+
+    /// Returns the source of an interpolated expression, consuming the closing brace. Braces and
+    /// strings inside of the expression are skipped over.
+    fn take_interpolated_expression(&mut self) -> Result<String, LexerError> {
+        let start = self.cursor;
+        let mut depth = 0;
+        loop {
+            match self.expect_next_char()? {
+                '{' => depth += 1,
+                '}' if depth == 0 => break,
+                '}' => depth -= 1,
+                quote @ ('"' | '\'') => loop {
+                    match self.expect_next_char()? {
+                        '\\' => {
+                            self.expect_next_char()?;
+                        }
+                        c if c == quote => break,
+                        _ => {}
+                    }
+                },
+                _ => {}
+            }
+        }
+        Ok(self.src[start..self.cursor - 1].to_owned())
+    }
+
+    /// Returns the character that the escape sequence `\{next}` stands for. Escaped newlines
+    /// stand for nothing.
+    fn unescape(next: char, quote: char) -> Result<Option<char>, LexerError> {
+        match next {
+            'n' => Ok(Some('\n')),
+            '\\' => Ok(Some('\\')),
+            '\n' => Ok(None),
+            _ if next == quote => Ok(Some(quote)),
+            _ => Err(LexerError::UnrecognizedEscape(next)),
+        }
+    }
+
     fn advance_while(&mut self, func: fn(char) -> bool) {
         while let Some(c) = self.get_next_char() {
             if !func(c) {
@@ -248,6 +430,9 @@ impl<'a> Lexer<'a> {
             .next_back()
             .unwrap();
         self.cursor -= last_char.len_utf8();
+        if last_char == '\n' {
+            self.line -= 1;
+        }
     }
 
     fn get_slice(&mut self) -> &'a str {
@@ -263,3 +448,111 @@ impl<'a> Lexer<'a> {
         self.end_token();
     }
 }
+
+impl Token {
+    /// How the token is written, in backticks, if it is always written the same way.
+    pub(crate) fn symbol(&self) -> Option<&'static str> {
+        let symbol = match self {
+            Token::TemplateOpen => "`{{`",
+            Token::TemplateClose => "`}}`",
+            Token::OpeningParen => "`(`",
+            Token::ClosingParen => "`)`",
+            Token::OpeningSqBracket => "`[`",
+            Token::ClosingSqBracket => "`]`",
+            Token::Comma => "`,`",
+            Token::Colon => "`:`",
+            Token::Exclamation => "`!`",
+            Token::Assign => "`=`",
+            Token::Arrow => "`=>`",
+            Token::Dot => "`.`",
+            Token::Ellipsis => "`...`",
+            Token::QuestionDot => "`?.`",
+            Token::QuestionSqBracket => "`?[`",
+            Token::Keyword(keyword) => keyword.symbol(),
+            Token::Literal(OwnedValue::Null) => "`null`",
+            Token::Operator(operator) => operator.symbol()?,
+            _ => return None,
+        };
+        Some(symbol)
+    }
+}
+
+/// Tokens are written as they appear in the source, so that errors can quote them.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(symbol) = self.symbol() {
+            return f.write_str(symbol);
+        }
+        match self {
+            Token::Text(text) => write!(f, "text {text:?}"),
+            Token::Identifier(identifier) => write!(f, "identifier {identifier:?}"),
+            Token::Literal(OwnedValue::String(string)) => write!(f, "string {string:?}"),
+            Token::Literal(OwnedValue::Number(number)) => write!(f, "number {number}"),
+            Token::Literal(value) => write!(f, "`{value}`"),
+            Token::CustomOperator(symbol) | Token::Operator(Operator::Custom(symbol)) => {
+                write!(f, "`{symbol}`")
+            }
+            Token::InterpolatedString(_) => f.write_str("interpolated string"),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+impl Keyword {
+    pub(crate) fn symbol(&self) -> &'static str {
+        match self {
+            Keyword::If => "`if`",
+            Keyword::Elif => "`elif`",
+            Keyword::Else => "`else`",
+            Keyword::For => "`for`",
+            Keyword::In => "`in`",
+            Keyword::Not => "`not`",
+            Keyword::Is => "`is`",
+            Keyword::Break => "`break`",
+            Keyword::Continue => "`continue`",
+        }
+    }
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+impl Operator {
+    /// How the operator is written, in backticks. Custom operators have no static symbol.
+    pub(crate) fn symbol(&self) -> Option<&'static str> {
+        let symbol = match self {
+            Operator::Multiply => "`*`",
+            Operator::Divide => "`/`",
+            Operator::Add => "`+`",
+            Operator::Subtract => "`-`",
+            Operator::IsEqualTo => "`==`",
+            Operator::IsNotEqualTo => "`!=`",
+            Operator::And => "`&&`",
+            Operator::Or => "`||`",
+            Operator::Range => "`..`",
+            Operator::RangeInclusive => "`..=`",
+            Operator::In => "`in`",
+            Operator::NotIn => "`not in`",
+            Operator::Coalesce => "`??`",
+            Operator::Custom(_) => return None,
+        };
+        Some(symbol)
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, LexerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.yield_token();
+        if result.is_err() {
+            // Every error consumes at least one character. Dropping the rest of the broken token
+            // lets lexing resume after it.
+            self.end_token();
+        }
+        result.transpose()
+    }
+}
@@ -1,14 +1,36 @@
+mod arguments;
+mod binary;
+mod builtins;
+mod codegen;
+mod compile;
+mod environment;
 mod error;
 mod lexer;
 mod node;
+mod operators;
+mod optimize;
 mod parse_expression;
 mod parser;
 mod parser_helpers;
+mod printer;
+mod render;
+#[doc(hidden)]
+pub mod runtime;
 mod value;
 mod variables;
+pub mod visit;
+mod vm;
 
-pub use lexer::Lexer;
+pub use arguments::Arguments;
+pub use codegen::Generator;
+pub use compile::Program;
+pub use environment::Environment;
+pub use error::{DecodeError, LexerError, ParseError, PrintError, ValueError};
+pub use lexer::{Lexer, Operator};
 pub use node::Node;
+pub use operators::{precedence, Associativity};
 pub use parser::Parser;
-pub use value::{OwnedValue, Value};
+pub use printer::Printer;
+pub use value::{Lambda, OwnedValue, Range, Value};
 pub use variables::Variables;
+pub use visit::{Fold, Visitor, VisitorMut};
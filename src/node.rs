@@ -1,49 +1,255 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::{error::ValueError, lexer::Operator, value::OwnedValue, variables::Variables, Value};
+use crate::{
+    arguments::Arguments,
+    builtins,
+    environment::Environment,
+    error::ValueError,
+    lexer::Operator,
+    value::{check_range_len, Lambda, OwnedValue},
+    variables::Variables,
+    visit::{self, Visitor},
+    Value,
+};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Node {
     Body(Vec<Node>),
     Value(OwnedValue),
     Variable(String),
-    FunctionCall(String, Vec<Node>),
+    /// The first field is the function's identifier. The second field is the positional
+    /// arguments. The third field is the named arguments.
+    FunctionCall(String, Vec<Node>, Vec<(String, Node)>),
     Array(Vec<Node>),
+    /// Concatenates the string representations of its parts. `f"host {name}"` is parsed into
+    /// `Concat([Value("host "), Variable("name")])`.
+    Concat(Vec<Node>),
+    /// `...items` inside of an array literal.
+    Spread(Box<Node>),
+    /// `[element for identifier in array if filter]`. The first field is the identifier. The
+    /// second field is the array. The third field is the element. The fourth field is the filter.
+    Comprehension(String, Box<Node>, Box<Node>, Option<Box<Node>>),
+    /// The first field is the indexed value. The second field is the index.
+    Index(Box<Node>, Box<Node>),
+    /// `value[start:end:step]`, where every bound is optional.
+    Slice(
+        Box<Node>,
+        Option<Box<Node>>,
+        Option<Box<Node>>,
+        Option<Box<Node>>,
+    ),
+    /// `value?[index]`, which is null if the value or the item is missing.
+    OptionalIndex(Box<Node>, Box<Node>),
+    /// `object.attribute`
+    Attribute(Box<Node>, String),
+    /// `object?.attribute`, which is null if the object or the attribute is missing.
+    OptionalAttribute(Box<Node>, String),
     Operation(Box<Node>, Operator, Box<Node>),
     Not(Box<Node>),
     Negate(Box<Node>),
+    /// A prefix operator registered with [`Environment::add_prefix_operator`].
+    PrefixOperation(String, Box<Node>),
     IfThenElse(Box<Node>, Box<Node>, Option<Box<Node>>),
     /// The first field is the identifier. The second field is the array. The third field is the
-    /// body. The fourth field is the separator.
-    ForIn(String, Box<Node>, Box<Node>, Option<Box<Node>>),
+    /// body. The fourth field is the separator. The fifth field is the filter condition.
+    ///
+    /// The filter and the separator are evaluated for every item, in that order and before the
+    /// body, with the identifier bound to the item. The separator is written before every item
+    /// but the first.
+    ForIn(
+        String,
+        Box<Node>,
+        Box<Node>,
+        Option<Box<Node>>,
+        Option<Box<Node>>,
+    ),
+    Break,
+    Continue,
+    /// The first field is the parameters. The second field is the body.
+    Lambda(Vec<String>, Box<Node>),
+    /// `value is test(args)`. The first field is the tested value. The second field is the test's
+    /// name. The third and fourth fields are the positional and named arguments.
+    Test(Box<Node>, String, Vec<Node>, Vec<(String, Node)>),
+    /// A tag that failed to parse, left behind by
+    /// [`Parser::parse_all_recovering`](crate::Parser::parse_all_recovering).
+    Error,
+}
+
+pub(crate) type LambdaFn<'a> =
+    dyn for<'b> Fn(Vec<Value<'b>>) -> Result<OwnedValue, ValueError> + 'a;
+
+/// Everything that can cut the evaluation of a node short. `break` and `continue` unwind through
+/// bodies and `if` nodes until they reach the enclosing `for` loop, carrying the output that was
+/// rendered before them.
+pub(crate) enum Interrupt {
+    Error(ValueError),
+    Break(String),
+    Continue(String),
+}
+
+/// How a node in a chain is evaluated.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Mode {
+    /// For its value, failing if a variable, attribute, or item in it is missing.
+    Value,
+    /// For its value, or `None` if a variable, attribute, or item in it is missing, like the
+    /// operand of `is`.
+    Defined,
+    /// Like `Defined`, but null counts as missing too, like the operand of `??`, `?.`, and `?[]`.
+    Optional,
+}
+
+impl Mode {
+    /// Turns what a node evaluated to into what this mode expects. Missing values are null if a
+    /// value is expected, which only `?.` and `?[]` leave behind.
+    fn keep(self, value: Option<Value>) -> Option<Value> {
+        match self {
+            Mode::Value => Some(required(value)),
+            Mode::Defined => value,
+            Mode::Optional => value.filter(|value| *value.inner() != OwnedValue::Null),
+        }
+    }
+}
+
+/// The value of an operand evaluated for its value, which is never missing.
+fn required(value: Option<Value>) -> Value {
+    value.unwrap_or(Value::Owned(OwnedValue::Null))
+}
+
+impl From<ValueError> for Interrupt {
+    fn from(err: ValueError) -> Self {
+        Interrupt::Error(err)
+    }
+}
+
+/// Applies an eager infix operator. `&&`, `||`, and `??` are lazy, so they are evaluated by the
+/// callers instead.
+pub(crate) fn operate(
+    lhs: &OwnedValue,
+    op: &Operator,
+    rhs: &OwnedValue,
+    environment: &Environment,
+) -> Result<OwnedValue, ValueError> {
+    let value = match op {
+        Operator::Multiply => (lhs * rhs)?,
+        Operator::Divide => (lhs / rhs)?,
+        Operator::Add => (lhs + rhs)?,
+        Operator::Subtract => (lhs - rhs)?,
+        Operator::IsEqualTo => OwnedValue::Boolean(lhs == rhs),
+        Operator::IsNotEqualTo => OwnedValue::Boolean(lhs != rhs),
+        Operator::Range => lhs.range_to(rhs, false)?,
+        Operator::RangeInclusive => lhs.range_to(rhs, true)?,
+        Operator::In => OwnedValue::Boolean(rhs.contains(lhs)?),
+        Operator::NotIn => OwnedValue::Boolean(!rhs.contains(lhs)?),
+        Operator::Custom(symbol) => match environment.infix_operator(symbol) {
+            Some(operator) => (operator.evaluate)(lhs, rhs)?,
+            None => return Err(ValueError::UndefinedOperator(symbol.clone())),
+        },
+        Operator::And | Operator::Or | Operator::Coalesce => unreachable!(),
+    };
+    Ok(value)
+}
+
+/// Calls a function from the environment, or the builtin one with the same name.
+pub(crate) fn call_function(
+    identifier: &str,
+    args: Arguments,
+    environment: &Environment,
+) -> Result<OwnedValue, ValueError> {
+    match environment.function(identifier) {
+        Some(function) => function(args),
+        None => builtins::call(identifier, &args)
+            .ok_or_else(|| ValueError::UndefinedVariable(identifier.to_owned()))?,
+    }
+}
+
+/// Runs `value is test(args)`. The value is `None` if it is undefined, which only passes
+/// `undefined`.
+pub(crate) fn run_test(
+    test: &str,
+    value: Option<Value>,
+    args: Arguments,
+    environment: &Environment,
+) -> Result<bool, ValueError> {
+    let passed = match (test, value) {
+        ("defined", value) => value.is_some(),
+        ("undefined", value) => value.is_none(),
+        (_, None) => false,
+        (_, Some(value)) => match environment.test(test) {
+            Some(function) => function(value.inner(), args)?,
+            None => builtins::test(test, value.inner(), &args)
+                .ok_or_else(|| ValueError::UndefinedTest(test.to_owned()))??,
+        },
+    };
+    Ok(passed)
+}
+
+/// Iterates over the items of an array or range, as `for` loops, comprehensions, and spreads do.
+pub(crate) fn iterate(
+    value: &OwnedValue,
+) -> Result<Box<dyn Iterator<Item = Value<'_>> + '_>, ValueError> {
+    match value {
+        OwnedValue::Array(array) => Ok(Box::new(array.iter().map(Value::Borrowed))),
+        OwnedValue::Range(range) => {
+            Ok(Box::new(range.iter().map(|n| OwnedValue::Number(n).into())))
+        }
+        value => Err(ValueError::IterateError(value.clone())),
+    }
 }
 
 impl Node {
     pub fn evaluate<V: Variables>(
         &self,
         variables: &V,
-        functions: &HashMap<String, impl Fn(Vec<Value>) -> OwnedValue>,
+        environment: &Environment,
     ) -> Result<String, ValueError> {
-        let body = self._evaluate(variables, functions, &HashMap::new())?;
-        Ok(body.unwrap_string())
+        match self._evaluate(variables, environment, &HashMap::new(), 0) {
+            Ok(body) => body.unwrap_string(),
+            Err(Interrupt::Error(err)) => Err(err),
+            // The parser rejects these, but nodes can also be built by hand.
+            Err(Interrupt::Break(_) | Interrupt::Continue(_)) => Err(ValueError::OperationError(
+                "`break` or `continue` outside of a for loop".into(),
+            )),
+        }
     }
 
-    fn _evaluate<'a, V: Variables>(
+    pub(crate) fn _evaluate<'a, V: Variables>(
         &'a self,
         variables: &'a V,
-        functions: &HashMap<String, impl Fn(Vec<Value>) -> OwnedValue>,
+        environment: &Environment,
         local_vars: &HashMap<String, Value<'a>>,
-    ) -> Result<Value, ValueError> {
+        depth: usize,
+    ) -> Result<Value<'a>, Interrupt> {
+        if depth > environment.max_depth() {
+            return Err(ValueError::NestingTooDeep(environment.max_depth()).into());
+        }
+        if self.operand(Mode::Value).is_some() {
+            let value =
+                self._evaluate_chain(Mode::Value, variables, environment, local_vars, depth)?;
+            return Ok(required(value));
+        }
+        let depth = depth + 1;
         match self {
             Node::Body(nodes) => {
                 let mut buffer = String::new();
                 for node in &**nodes {
-                    let eval_value = node._evaluate(variables, functions, local_vars)?;
+                    let eval_value = match node._evaluate(variables, environment, local_vars, depth)
+                    {
+                        Ok(eval_value) => eval_value,
+                        Err(Interrupt::Break(rest)) => {
+                            return Err(Interrupt::Break(buffer + &rest));
+                        }
+                        Err(Interrupt::Continue(rest)) => {
+                            return Err(Interrupt::Continue(buffer + &rest));
+                        }
+                        Err(err) => return Err(err),
+                    };
                     let value = eval_value.inner();
                     // This cannot be turned into a method: Reference to temporary value dropped.
                     let string = match value {
                         OwnedValue::String(string) => string,
-                        a => &a.to_string(),
+                        a => &a.to_text()?,
                     };
                     buffer += string;
                 }
@@ -57,160 +263,552 @@ impl Node {
                             .get(identifier)
                             .ok_or_else(|| ValueError::UndefinedVariable(identifier.clone()))?,
                     ),
-                    Some(Value::Borrowed(value)) => Value::Borrowed(*value),
-                    // Data within `local_vars` will always be borrowed.
-                    _ => panic!(),
+                    Some(value) => value.clone(),
                 };
                 Ok(variable)
             }
-            Node::FunctionCall(identifier, args) => {
-                let function = functions
-                    .get(identifier)
-                    .ok_or_else(|| ValueError::UndefinedVariable(identifier.clone()))?;
-                let args = args
+            Node::FunctionCall(identifier, args, named_args) => {
+                let arg_nodes = || args.iter().chain(named_args.iter().map(|(_, node)| node));
+                // Lambdas borrow the current scope, so they are built up front to outlive the
+                // arguments that refer to them.
+                let lambdas = arg_nodes()
+                    .map(|node| match node {
+                        Node::Lambda(params, body) => Some(Self::make_lambda(
+                            params,
+                            body,
+                            variables,
+                            environment,
+                            local_vars,
+                            depth,
+                        )),
+                        _ => None,
+                    })
+                    .collect::<Vec<Option<Box<LambdaFn>>>>();
+                let mut positional = arg_nodes()
+                    .zip(&lambdas)
+                    .map(|(node, lambda)| match lambda {
+                        Some(function) => Ok(Value::Lambda(Lambda {
+                            function: &**function,
+                        })),
+                        None => node._evaluate(variables, environment, local_vars, depth),
+                    })
+                    .collect::<Result<Vec<Value>, Interrupt>>()?;
+                let named = named_args
                     .iter()
-                    .map(|node| node._evaluate(variables, functions, local_vars))
-                    .collect::<Result<Vec<Value>, ValueError>>()?;
-                let result = (*function)(args);
-                Ok(result.into())
+                    .map(|(name, _)| name.clone())
+                    .zip(positional.split_off(args.len()))
+                    .collect();
+                let args = Arguments { positional, named };
+                Ok(call_function(identifier, args, environment)?.into())
             }
-
             Node::Array(nodes) => {
-                let array = nodes
-                    .iter()
-                    .map(|node| {
-                        node._evaluate(variables, functions, local_vars)
-                            .map(|value| value.to_owned_value())
-                    })
-                    .collect::<Result<Vec<OwnedValue>, ValueError>>()?;
+                let mut array = Vec::with_capacity(nodes.len());
+                for node in nodes {
+                    match node {
+                        Node::Spread(items) => {
+                            let evaluation =
+                                items._evaluate(variables, environment, local_vars, depth)?;
+                            if let OwnedValue::Range(range) = evaluation.inner() {
+                                check_range_len(range.len())?;
+                            }
+                            for item in iterate(evaluation.inner())? {
+                                array.push(item.to_owned_value());
+                            }
+                        }
+                        node => array.push(
+                            node._evaluate(variables, environment, local_vars, depth)?
+                                .to_owned_value(),
+                        ),
+                    }
+                }
                 Ok(array.into())
             }
-            Node::Operation(lhs, op, rhs) => {
-                let lhs = lhs._evaluate(variables, functions, local_vars)?;
-                let rhs = rhs._evaluate(variables, functions, local_vars)?;
-                let lhs = lhs.inner();
-                let rhs = rhs.inner();
-                match op {
-                    Operator::Multiply => (lhs * rhs).map(Into::into),
-                    Operator::Divide => (lhs / rhs).map(Into::into),
-                    Operator::Add => (lhs + rhs).map(Into::into),
-                    Operator::Subtract => (lhs - rhs).map(Into::into),
-                    Operator::IsEqualTo => Ok((lhs == rhs).into()),
-                    Operator::IsNotEqualTo => Ok((lhs != rhs).into()),
-                    Operator::And => Ok((lhs.is_truthy() && rhs.is_truthy()).into()),
-                    Operator::Or => Ok((lhs.is_truthy() || rhs.is_truthy()).into()),
+            Node::Concat(parts) => {
+                let mut buffer = String::new();
+                for part in parts {
+                    let evaluation = part._evaluate(variables, environment, local_vars, depth)?;
+                    match evaluation.inner() {
+                        OwnedValue::String(string) => buffer += string,
+                        value => buffer += &value.to_text()?,
+                    }
+                }
+                Ok(buffer.into())
+            }
+            Node::Spread(_) => {
+                Err(ValueError::OperationError("Spreads can only be used in arrays".into()).into())
+            }
+            Node::Comprehension(identifier, array, element, filter) => {
+                let evaluation = array._evaluate(variables, environment, local_vars, depth)?;
+                // The items are collected, so a range is limited like a spread is.
+                if let OwnedValue::Range(range) = evaluation.inner() {
+                    check_range_len(range.len())?;
                 }
+                let mut local_vars = local_vars.clone();
+                let mut array = Vec::new();
+                for item in iterate(evaluation.inner())? {
+                    local_vars.insert(identifier.to_owned(), item);
+                    if let Some(filter) = filter {
+                        let evaluation =
+                            filter._evaluate(variables, environment, &local_vars, depth)?;
+                        if !evaluation.inner().is_truthy() {
+                            continue;
+                        }
+                    }
+                    let evaluation =
+                        element._evaluate(variables, environment, &local_vars, depth)?;
+                    array.push(evaluation.to_owned_value());
+                }
+                Ok(array.into())
             }
             Node::Not(node) => Ok((!node
-                ._evaluate(variables, functions, local_vars)?
+                ._evaluate(variables, environment, local_vars, depth)?
                 .inner()
                 .is_truthy())
             .into()),
             Node::Negate(node) => Ok((-node
-                ._evaluate(variables, functions, local_vars)?
+                ._evaluate(variables, environment, local_vars, depth)?
                 .unwrap_f64()?)
             .into()),
-            Node::IfThenElse(condition, then_node, else_node) => {
-                let evaluation = condition._evaluate(variables, functions, local_vars)?;
-                let condition_value = evaluation.inner();
-                if condition_value.is_truthy() {
-                    then_node._evaluate(variables, functions, local_vars)
-                } else if let Some(else_node) = else_node {
-                    else_node._evaluate(variables, functions, local_vars)
-                } else {
-                    Ok(String::new().into())
-                }
+            Node::PrefixOperation(symbol, node) => {
+                let operator = environment
+                    .prefix_operator(symbol)
+                    .ok_or_else(|| ValueError::UndefinedOperator(symbol.clone()))?;
+                let evaluation = node._evaluate(variables, environment, local_vars, depth)?;
+                Ok((operator.evaluate)(evaluation.inner())?.into())
             }
-            Node::ForIn(identifier, array, body, separator) => {
-                let evaluation = array._evaluate(variables, functions, local_vars)?;
-                let array = match evaluation.inner() {
-                    OwnedValue::Array(array) => array,
-                    value => return Err(ValueError::IterateError(value.clone())),
-                };
-                let separator = match separator {
-                    None => "",
-                    Some(separator) => {
-                        match separator._evaluate(variables, functions, local_vars)? {
-                            Value::Borrowed(value) => match value {
-                                OwnedValue::String(string) => string,
-                                _ => {
-                                    return Err(ValueError::OperationError(
-                                        "Invalid separator.".into(),
-                                    ));
-                                }
-                            },
-                            _ => panic!(),
-                        }
+            Node::IfThenElse(..) => {
+                // An `elif` is the `else` of the branch before it, but it doesn't nest the
+                // template, so the branches are tried in a loop.
+                let mut node = self;
+                while let Node::IfThenElse(condition, then_node, else_node) = node {
+                    let evaluation =
+                        condition._evaluate(variables, environment, local_vars, depth)?;
+                    if evaluation.inner().is_truthy() {
+                        return then_node._evaluate(variables, environment, local_vars, depth);
                     }
-                };
+                    match else_node {
+                        Some(else_node) => node = else_node,
+                        None => return Ok(String::new().into()),
+                    }
+                }
+                node._evaluate(variables, environment, local_vars, depth)
+            }
+            Node::ForIn(identifier, array, body, separator, filter) => {
+                let evaluation = array._evaluate(variables, environment, local_vars, depth)?;
+                let items = iterate(evaluation.inner())?;
                 let mut local_vars = local_vars.clone();
                 let mut buffer = String::new();
-                for (i, item) in array.iter().enumerate() {
-                    local_vars.insert(identifier.to_owned(), Value::Borrowed(item));
-                    let evaluation = body._evaluate(variables, functions, &local_vars)?;
+                let mut is_first = true;
+                for item in items {
+                    local_vars.insert(identifier.to_owned(), item);
+                    // Filtered items are dropped before any separator is written, so they never
+                    // leave a dangling separator behind.
+                    if let Some(filter) = filter {
+                        let evaluation =
+                            filter._evaluate(variables, environment, &local_vars, depth)?;
+                        if !evaluation.inner().is_truthy() {
+                            continue;
+                        }
+                    }
+                    // The separator can use the loop variable, so every item evaluates its own.
+                    let separator = match separator {
+                        None => None,
+                        Some(separator) => {
+                            Some(separator._evaluate(variables, environment, &local_vars, depth)?)
+                        }
+                    };
+                    let separator = match separator.as_ref().map(Value::inner) {
+                        None => "",
+                        Some(OwnedValue::String(string)) => string,
+                        Some(value) => &value.to_text()?,
+                    };
+                    let (evaluation, interrupted, is_break) =
+                        match body._evaluate(variables, environment, &local_vars, depth) {
+                            Ok(evaluation) => (evaluation, false, false),
+                            Err(Interrupt::Break(rest)) => (rest.into(), true, true),
+                            Err(Interrupt::Continue(rest)) => (rest.into(), true, false),
+                            Err(err) => return Err(err),
+                        };
                     let body = evaluation.inner();
                     let string = match body {
                         OwnedValue::String(string) => string,
-                        value => &value.to_string(),
+                        value => &value.to_text()?,
                     };
-                    buffer += string;
-                    if i != array.len() - 1 {
-                        buffer += &separator;
+                    // An item that was skipped before it rendered anything doesn't get a
+                    // separator either.
+                    if !(interrupted && string.is_empty()) {
+                        if !is_first {
+                            buffer += separator;
+                        }
+                        buffer += string;
+                        is_first = false;
+                    }
+                    if is_break {
+                        break;
                     }
                 }
                 Ok(buffer.into())
             }
+            Node::Break => Err(Interrupt::Break(String::new())),
+            Node::Continue => Err(Interrupt::Continue(String::new())),
+            Node::Error => Err(ValueError::OperationError(
+                "Cannot evaluate a template with syntax errors".into(),
+            )
+            .into()),
+            Node::Index(..)
+            | Node::Slice(..)
+            | Node::OptionalIndex(..)
+            | Node::Attribute(..)
+            | Node::OptionalAttribute(..)
+            | Node::Operation(..)
+            | Node::Test(..) => unreachable!("chains are evaluated by `_evaluate_chain`"),
+            Node::Lambda(..) => Err(ValueError::OperationError(
+                "Lambdas can only be passed to functions".into(),
+            )
+            .into()),
         }
     }
 
-    pub fn referenced_vars(&self) -> HashSet<&String> {
-        let mut references = HashSet::new();
-        match self {
-            Node::Body(nodes) => {
-                for node in &**nodes {
-                    references.extend(node.referenced_vars());
-                }
+    /// Evaluates a node that doesn't read any variables, or returns `None` if it fails.
+    pub(crate) fn evaluate_constant(&self) -> Option<OwnedValue> {
+        let variables = HashMap::<String, OwnedValue>::new();
+        let value = self
+            ._evaluate(&variables, &Environment::default(), &HashMap::new(), 0)
+            .ok()?;
+        Some(value.to_owned_value())
+    }
+
+    /// The operand that an operator, subscript, attribute, or test continues, and how it is
+    /// evaluated when this node is evaluated in `mode`.
+    pub(crate) fn operand(&self, mode: Mode) -> Option<(&Node, Mode)> {
+        let operand = match self {
+            Node::Operation(lhs, Operator::Coalesce, _) => (lhs, Mode::Optional),
+            Node::Operation(lhs, ..) | Node::Slice(lhs, ..) => (lhs, Mode::Value),
+            Node::Index(lhs, _) | Node::Attribute(lhs, _) if mode == Mode::Value => {
+                (lhs, Mode::Value)
             }
-            Node::FunctionCall(_identifier, args) => {
-                for node in &**args {
-                    references.extend(node.referenced_vars());
+            Node::Index(lhs, _)
+            | Node::OptionalIndex(lhs, _)
+            | Node::Attribute(lhs, _)
+            | Node::OptionalAttribute(lhs, _) => (lhs, Mode::Optional),
+            Node::Test(lhs, ..) => (lhs, Mode::Defined),
+            _ => return None,
+        };
+        Some((&**operand.0, operand.1))
+    }
+
+    /// Evaluates a chain of operators, subscripts, attributes, and tests, which the parser lets
+    /// grow much longer than templates may nest. The chain is followed down to its first operand
+    /// and then evaluated back up in a loop, so every operand is at the depth of the chain, and
+    /// only the other parts of the links are a level deeper.
+    fn _evaluate_chain<'a, V: Variables>(
+        &'a self,
+        mode: Mode,
+        variables: &'a V,
+        environment: &Environment,
+        local_vars: &HashMap<String, Value<'a>>,
+        depth: usize,
+    ) -> Result<Option<Value<'a>>, Interrupt> {
+        let mut links = Vec::new();
+        let (mut node, mut mode) = (self, mode);
+        while let Some(operand) = node.operand(mode) {
+            links.push((node, mode));
+            (node, mode) = operand;
+        }
+        let value = match node {
+            Node::Variable(identifier) if mode != Mode::Value => match local_vars.get(identifier) {
+                None => variables.get(identifier).map(Value::Borrowed),
+                Some(value) => Some(value.clone()),
+            },
+            node => Some(node._evaluate(variables, environment, local_vars, depth)?),
+        };
+        let mut value = mode.keep(value);
+        for (node, mode) in links.into_iter().rev() {
+            let link =
+                node._evaluate_link(value, mode, variables, environment, local_vars, depth + 1)?;
+            value = mode.keep(link);
+        }
+        Ok(value)
+    }
+
+    /// Evaluates a link of a chain, given what its operand evaluated to. Undefined variables,
+    /// missing attributes and items are `None` instead of an error unless `mode` wants a value.
+    fn _evaluate_link<'a, V: Variables>(
+        &'a self,
+        operand: Option<Value<'a>>,
+        mode: Mode,
+        variables: &'a V,
+        environment: &Environment,
+        local_vars: &HashMap<String, Value<'a>>,
+        depth: usize,
+    ) -> Result<Option<Value<'a>>, Interrupt> {
+        let value = match self {
+            Node::Operation(_, Operator::Coalesce, rhs) => match operand {
+                Some(value) => value,
+                None => rhs._evaluate(variables, environment, local_vars, depth)?,
+            },
+            // `&&` and `||` short-circuit so that `x is defined && x.y` is safe.
+            Node::Operation(_, op @ (Operator::And | Operator::Or), rhs) => {
+                let lhs = required(operand).inner().is_truthy();
+                if lhs == (*op == Operator::Or) {
+                    return Ok(Some(lhs.into()));
                 }
+                let rhs = rhs._evaluate(variables, environment, local_vars, depth)?;
+                rhs.inner().is_truthy().into()
             }
-            Node::Array(array) => {
-                for node in array {
-                    references.extend(node.referenced_vars());
+            Node::Operation(_, op, rhs) => {
+                let lhs = required(operand);
+                let rhs = rhs._evaluate(variables, environment, local_vars, depth)?;
+                operate(lhs.inner(), op, rhs.inner(), environment)?.into()
+            }
+            Node::Index(_, index) if mode == Mode::Value => {
+                let value = required(operand);
+                let index = index._evaluate(variables, environment, local_vars, depth)?;
+                match value {
+                    Value::Borrowed(value) => value.index(index.inner())?,
+                    value => value.inner().index(index.inner())?.to_owned_value().into(),
                 }
             }
-            Node::Operation(lhs, _, rhs) => {
-                references.extend(lhs.referenced_vars());
-                references.extend(rhs.referenced_vars());
+            Node::Slice(_, start, end, step) => {
+                let value = required(operand);
+                let bounds = [start, end, step]
+                    .iter()
+                    .map(|bound| match bound {
+                        None => Ok(None),
+                        Some(bound) => {
+                            match bound._evaluate(variables, environment, local_vars, depth)? {
+                                bound if *bound.inner() == OwnedValue::Null => Ok(None),
+                                bound => Ok(Some(bound.unwrap_f64()?)),
+                            }
+                        }
+                    })
+                    .collect::<Result<Vec<Option<f64>>, Interrupt>>()?;
+                value.inner().slice(bounds[0], bounds[1], bounds[2])?.into()
+            }
+            Node::Attribute(_, attribute) if mode == Mode::Value => {
+                let value = match required(operand) {
+                    Value::Borrowed(object) => {
+                        object.get_attribute(attribute)?.map(Value::Borrowed)
+                    }
+                    object => object
+                        .inner()
+                        .get_attribute(attribute)?
+                        .map(|value| value.clone().into()),
+                };
+                value.ok_or_else(|| {
+                    ValueError::OperationError(format!("Missing attribute {attribute:?}"))
+                })?
             }
-            Node::IfThenElse(condition, then_node, else_node) => {
-                references.extend(condition.referenced_vars());
-                references.extend(then_node.referenced_vars());
-                if let Some(else_node) = else_node {
-                    references.extend(else_node.referenced_vars());
-                }
+            Node::Index(_, index) | Node::OptionalIndex(_, index) => {
+                let Some(value) = operand else {
+                    return Ok(None);
+                };
+                let index = index._evaluate(variables, environment, local_vars, depth)?;
+                return Ok(match value {
+                    Value::Borrowed(value) => value.get_index(index.inner())?,
+                    value => value
+                        .inner()
+                        .get_index(index.inner())?
+                        .map(|item| item.to_owned_value().into()),
+                });
             }
-            Node::ForIn(identifier, array, body, separator) => {
-                references.extend(body.referenced_vars());
-                if let Some(separator) = separator {
-                    references.extend(separator.referenced_vars());
-                }
-                references.remove(identifier);
-                references.extend(array.referenced_vars());
+            Node::Attribute(_, attribute) | Node::OptionalAttribute(_, attribute) => {
+                return Ok(match operand {
+                    None => None,
+                    Some(Value::Borrowed(object)) => {
+                        object.get_attribute(attribute)?.map(Value::Borrowed)
+                    }
+                    Some(object) => object
+                        .inner()
+                        .get_attribute(attribute)?
+                        .map(|value| value.clone().into()),
+                });
+            }
+            Node::Test(_, test, args, named_args) => {
+                let positional = args
+                    .iter()
+                    .map(|node| node._evaluate(variables, environment, local_vars, depth))
+                    .collect::<Result<Vec<Value>, Interrupt>>()?;
+                let named = named_args
+                    .iter()
+                    .map(|(name, node)| {
+                        node._evaluate(variables, environment, local_vars, depth)
+                            .map(|value| (name.clone(), value))
+                    })
+                    .collect::<Result<Vec<(String, Value)>, Interrupt>>()?;
+                let args = Arguments { positional, named };
+                run_test(test, operand, args, environment)?.into()
             }
+            _ => unreachable!("only links of chains have operands"),
+        };
+        Ok(Some(value))
+    }
+
+    /// Turns a lambda into a closure that evaluates its body in the scope it was written in.
+    fn make_lambda<'a, V: Variables>(
+        params: &'a [String],
+        body: &'a Node,
+        variables: &'a V,
+        environment: &'a Environment,
+        local_vars: &'a HashMap<String, Value<'a>>,
+        depth: usize,
+    ) -> Box<LambdaFn<'a>> {
+        Box::new(move |args: Vec<Value>| {
+            if args.len() != params.len() {
+                return Err(ValueError::OperationError(format!(
+                    "Lambda takes {} arguments, got {}",
+                    params.len(),
+                    args.len()
+                )));
+            }
+            let mut scope = local_vars.clone();
+            scope.extend(params.iter().cloned().zip(args));
+            match body._evaluate(variables, environment, &scope, depth) {
+                Ok(value) => Ok(value.to_owned_value()),
+                Err(Interrupt::Error(err)) => Err(err),
+                // The parser never puts `break` or `continue` in lambda bodies.
+                Err(Interrupt::Break(_) | Interrupt::Continue(_)) => Err(
+                    ValueError::OperationError("`break` or `continue` inside of a lambda".into()),
+                ),
+            }
+        })
+    }
+
+    /// The variables that a node reads from its [`Variables`], leaving out the identifiers bound by
+    /// loops, comprehensions, and lambdas inside of it.
+    pub fn referenced_vars(&self) -> HashSet<&String> {
+        let mut visitor = ReferencedVars {
+            references: HashSet::new(),
+            bound: Vec::new(),
+        };
+        visitor.visit_node(self);
+        visitor.references
+    }
+}
+
+/// Whether a node contains `break` or `continue`.
+pub(crate) fn interrupts(node: &Node) -> bool {
+    let mut visitor = Interrupts(false);
+    visitor.visit_node(node);
+    visitor.0
+}
+
+struct Interrupts(bool);
+
+impl Visitor<'_> for Interrupts {
+    fn visit_node(&mut self, node: &Node) {
+        match node {
+            Node::Break | Node::Continue => self.0 = true,
+            node => visit::walk(self, node),
+        }
+    }
+}
+
+/// The deepest level that the tree-walker evaluates a node at, which
+/// [`Environment::set_max_depth`] limits. Each node is a level deeper than the node it is in,
+/// except for the operand that a chain continues, an `elif`, and the body of a lambda or spread.
+/// The nodes are visited with a stack of their own, since trees built by hand or decoded from a
+/// cache haven't been limited yet.
+pub(crate) fn depth(node: &Node) -> usize {
+    let mut deepest = 0;
+    let mut stack = vec![(node, 0)];
+    while let Some((node, level)) = stack.pop() {
+        deepest = deepest.max(level);
+        let mut children = Children(Vec::new());
+        visit::walk(&mut children, node);
+        let operand = node.operand(Mode::Value).map(|(operand, _)| operand);
+        for child in children.0 {
+            let nested = match node {
+                Node::Lambda(..) | Node::Spread(..) => false,
+                Node::IfThenElse(.., Some(else_node)) if std::ptr::eq(child, &**else_node) => {
+                    !matches!(child, Node::IfThenElse(..))
+                }
+                _ => !operand.is_some_and(|operand| std::ptr::eq(child, operand)),
+            };
+            stack.push((child, level + usize::from(nested)));
+        }
+    }
+    deepest
+}
+
+/// Collects the children of a node.
+pub(crate) struct Children<'a>(pub(crate) Vec<&'a Node>);
+
+impl<'a> Visitor<'a> for Children<'a> {
+    fn visit_node(&mut self, node: &'a Node) {
+        self.0.push(node);
+    }
+}
+
+struct ReferencedVars<'a> {
+    references: HashSet<&'a String>,
+    /// The identifiers bound by the enclosing loops, comprehensions, and lambdas.
+    bound: Vec<&'a String>,
+}
+
+impl<'a> ReferencedVars<'a> {
+    /// Visits nodes with more identifiers in scope.
+    fn visit_scoped<const N: usize>(&mut self, scope: &'a [String], nodes: [Option<&'a Node>; N]) {
+        let len = self.bound.len();
+        self.bound.extend(scope);
+        for node in nodes.into_iter().flatten() {
+            self.visit_node(node);
+        }
+        self.bound.truncate(len);
+    }
+}
+
+impl<'a> Visitor<'a> for ReferencedVars<'a> {
+    fn visit_node(&mut self, node: &'a Node) {
+        match node {
             Node::Variable(identifier) => {
-                references.insert(identifier);
+                if !self.bound.contains(&identifier) {
+                    self.references.insert(identifier);
+                }
+            }
+            // The loop identifier isn't in scope in the array.
+            Node::ForIn(identifier, array, body, separator, filter) => {
+                self.visit_node(array);
+                self.visit_scoped(
+                    std::slice::from_ref(identifier),
+                    [Some(body), separator.as_deref(), filter.as_deref()],
+                );
             }
-            Node::Not(node) => {
-                references.extend(node.referenced_vars());
+            Node::Comprehension(identifier, array, element, filter) => {
+                self.visit_node(array);
+                self.visit_scoped(
+                    std::slice::from_ref(identifier),
+                    [Some(element), filter.as_deref()],
+                );
             }
-            Node::Negate(node) => {
-                references.extend(node.referenced_vars());
+            Node::Lambda(params, body) => self.visit_scoped(params, [Some(body)]),
+            // `elif`s and chains are followed in a loop, so that long ones don't recurse.
+            Node::IfThenElse(..) => {
+                let mut else_node = Some(node);
+                while let Some(Node::IfThenElse(condition, then_node, next)) = else_node {
+                    self.visit_node(condition);
+                    self.visit_node(then_node);
+                    else_node = next.as_deref();
+                }
+                if let Some(else_node) = else_node {
+                    self.visit_node(else_node);
+                }
+            }
+            node if node.operand(Mode::Value).is_some() => {
+                let mut node = node;
+                while let Some((operand, _)) = node.operand(Mode::Value) {
+                    let mut children = Children(Vec::new());
+                    visit::walk(&mut children, node);
+                    for child in children.0 {
+                        if !std::ptr::eq(child, operand) {
+                            self.visit_node(child);
+                        }
+                    }
+                    node = operand;
+                }
+                self.visit_node(node);
             }
-            Node::Value(_) => {}
+            node => visit::walk(self, node),
         }
-        references
     }
 }
@@ -0,0 +1,86 @@
+use crate::{error::ValueError, lexer::Operator, value::OwnedValue};
+
+/// The precedences of the builtin operators, from loosest to tightest. They are spaced out so that
+/// custom operators can be placed between them.
+pub mod precedence {
+    /// `??`
+    pub const COALESCE: u8 = 10;
+    /// `||`
+    pub const OR: u8 = 20;
+    /// `&&`
+    pub const AND: u8 = 30;
+    /// `==`, `!=`, `in`, `not in`, and `is`
+    pub const COMPARISON: u8 = 40;
+    /// `..` and `..=`
+    pub const RANGE: u8 = 50;
+    /// `+` and `-`
+    pub const SUM: u8 = 60;
+    /// `*` and `/`
+    pub const PRODUCT: u8 = 70;
+    /// `!x` and `-x`
+    pub const PREFIX: u8 = 80;
+    /// `x[i]`, `x?[i]`, `x.a`, and `x?.a`
+    pub const POSTFIX: u8 = 90;
+}
+
+/// How a chain of operators with the same precedence is grouped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Associativity {
+    /// `a - b - c` is `(a - b) - c`.
+    Left,
+    /// `a ** b ** c` is `a ** (b ** c)`.
+    Right,
+    /// `a .. b .. c` is a syntax error.
+    None,
+}
+
+type InfixFn = dyn Fn(&OwnedValue, &OwnedValue) -> Result<OwnedValue, ValueError>;
+pub(crate) type PrefixFn = dyn Fn(&OwnedValue) -> Result<OwnedValue, ValueError>;
+
+pub(crate) struct InfixOperator {
+    pub(crate) precedence: u8,
+    pub(crate) associativity: Associativity,
+    pub(crate) evaluate: Box<InfixFn>,
+}
+
+pub(crate) struct PrefixOperator {
+    pub(crate) precedence: u8,
+    pub(crate) evaluate: Box<PrefixFn>,
+}
+
+/// The precedence and associativity of a builtin infix operator. Custom operators are looked up
+/// in the [`Environment`](crate::Environment) instead.
+pub(crate) fn builtin_precedence(operator: &Operator) -> Option<(u8, Associativity)> {
+    use precedence::*;
+    let precedence = match operator {
+        Operator::Coalesce => COALESCE,
+        Operator::Or => OR,
+        Operator::And => AND,
+        Operator::IsEqualTo | Operator::IsNotEqualTo | Operator::In | Operator::NotIn => COMPARISON,
+        Operator::Range | Operator::RangeInclusive => return Some((RANGE, Associativity::None)),
+        Operator::Add | Operator::Subtract => SUM,
+        Operator::Multiply | Operator::Divide => PRODUCT,
+        Operator::Custom(_) => return None,
+    };
+    Some((precedence, Associativity::Left))
+}
+
+/// The binding powers of an infix operator in the Pratt parser: how tightly it binds the
+/// expression to its left, and the minimum binding power of the operators in its right-hand side.
+/// Operators with the same precedence end the right-hand side unless they are right-associative.
+pub(crate) fn binding_powers(precedence: u8, associativity: Associativity) -> (u16, u16) {
+    let right = u16::from(precedence) * 2 + 1;
+    match associativity {
+        Associativity::Right => (right, right),
+        Associativity::Left | Associativity::None => (right - 1, right),
+    }
+}
+
+/// Returns whether an operator is spelled like an identifier (`mod`) rather than with punctuation
+/// (`**`). Word operators are lexed as identifiers.
+pub(crate) fn is_word(symbol: &str) -> bool {
+    symbol
+        .chars()
+        .next()
+        .is_some_and(|c| c == '_' || c.is_alphabetic())
+}
@@ -0,0 +1,157 @@
+use std::mem;
+
+use crate::{
+    node::{Mode, Node},
+    value::OwnedValue,
+    visit::{self, Fold},
+};
+
+impl Node {
+    /// Precomputes the parts of a template that don't depend on any variables. Operations,
+    /// negations, and arrays of constants are folded into values, `if` nodes with constant
+    /// conditions are replaced with the branch they would take before that branch is optimized,
+    /// and adjacent text is merged.
+    ///
+    /// Expressions that would fail are left as they are, so they still fail when the template is
+    /// rendered. Custom operators are never folded because they are looked up in the
+    /// [`Environment`](crate::Environment) that the template is rendered with.
+    pub fn optimize(self) -> Node {
+        Optimizer.fold_node(self)
+    }
+}
+
+struct Optimizer;
+
+impl Fold for Optimizer {
+    fn fold_node(&mut self, node: Node) -> Node {
+        match node {
+            Node::IfThenElse(..) => self.fold_if(node),
+            node if node.operand(Mode::Value).is_some() => self.fold_chain(node),
+            node => fold_constant(visit::walk_fold(self, node)),
+        }
+    }
+}
+
+impl Optimizer {
+    /// Folds an `if` and its `elif`s in a loop. Each condition is folded first, so that a branch
+    /// that is never taken isn't folded, and whatever constants it computes aren't either.
+    fn fold_if(&mut self, node: Node) -> Node {
+        let mut branches = Vec::new();
+        let mut next = Some(node);
+        let rest = loop {
+            let Some(Node::IfThenElse(condition, then_node, else_node)) = next else {
+                break next.map(|node| self.fold_node(node));
+            };
+            match self.fold_node(*condition) {
+                Node::Value(condition) if condition.is_truthy() => {
+                    break Some(self.fold_node(*then_node));
+                }
+                Node::Value(_) if else_node.is_none() => {
+                    break Some(Node::Value(OwnedValue::String(String::new())));
+                }
+                Node::Value(_) => {}
+                condition => branches.push((condition, self.fold_node(*then_node))),
+            }
+            next = else_node.map(|else_node| *else_node);
+        };
+        let mut node = rest;
+        for (condition, then_node) in branches.into_iter().rev() {
+            node = Some(Node::IfThenElse(
+                condition.into(),
+                then_node.into(),
+                node.map(Into::into),
+            ));
+        }
+        node.expect("an `if` either keeps a branch or is replaced by one")
+    }
+
+    /// Folds a chain of operators, subscripts, attributes, and tests from its first operand up in
+    /// a loop, so that long chains don't recurse.
+    fn fold_chain(&mut self, node: Node) -> Node {
+        let mut links = Vec::new();
+        let mut node = node;
+        while let Some(operand) = operand_mut(&mut node) {
+            let operand = mem::replace(&mut **operand, Node::Error);
+            links.push(node);
+            node = operand;
+        }
+        let mut folded = self.fold_node(node);
+        for link in links.into_iter().rev() {
+            let mut link = visit::walk_fold(self, link);
+            **operand_mut(&mut link).expect("a link keeps its operand") = folded;
+            folded = fold_constant(link);
+        }
+        folded
+    }
+}
+
+/// The operand that a chain continues: the left-hand side of an operator, subscript, attribute,
+/// or test.
+fn operand_mut(node: &mut Node) -> Option<&mut Box<Node>> {
+    match node {
+        Node::Index(value, _)
+        | Node::Slice(value, ..)
+        | Node::OptionalIndex(value, _)
+        | Node::Attribute(value, _)
+        | Node::OptionalAttribute(value, _)
+        | Node::Operation(value, ..)
+        | Node::Test(value, ..) => Some(value),
+        _ => None,
+    }
+}
+
+/// Finishes folding a node whose children have been folded.
+fn fold_constant(node: Node) -> Node {
+    match node {
+        Node::Body(nodes) => Node::Body(merge_text(nodes)),
+        node if is_constant(&node) => match node.evaluate_constant() {
+            Some(value) => Node::Value(value),
+            None => node,
+        },
+        node => node,
+    }
+}
+
+/// Returns whether every operand of an operation, `!`, `-`, or array is a value.
+fn is_constant(node: &Node) -> bool {
+    let is_value = |node: &Node| matches!(node, Node::Value(_));
+    match node {
+        Node::Operation(lhs, _, rhs) => is_value(lhs) && is_value(rhs),
+        Node::Not(node) | Node::Negate(node) => is_value(node),
+        Node::Array(items) => items.iter().all(|item| match item {
+            // Ranges are lazy, so spreading a long one is only paid for if it is rendered.
+            Node::Spread(items) => matches!(**items, Node::Value(OwnedValue::Array(_))),
+            item => is_value(item),
+        }),
+        _ => false,
+    }
+}
+
+/// Splices nested bodies into their parent, which renders them the same way, and joins runs of
+/// values into a single string.
+fn merge_text(nodes: Vec<Node>) -> Vec<Node> {
+    let mut merged = Vec::with_capacity(nodes.len());
+    let mut text = String::new();
+    let mut stack = vec![nodes.into_iter()];
+    while let Some(nodes) = stack.last_mut() {
+        match nodes.next() {
+            None => {
+                stack.pop();
+            }
+            Some(Node::Body(nodes)) => stack.push(nodes.into_iter()),
+            Some(Node::Value(OwnedValue::String(string))) => text += &string,
+            // A range with too many items to write is left to fail when the template renders.
+            Some(Node::Value(value)) if value.to_text().is_ok() => text += &value.to_string(),
+            Some(node) => {
+                if !text.is_empty() {
+                    merged.push(Node::Value(OwnedValue::String(mem::take(&mut text))));
+                }
+                merged.push(node);
+            }
+        }
+    }
+    if !text.is_empty() {
+        merged.push(Node::Value(OwnedValue::String(text)));
+    }
+    merged
+}
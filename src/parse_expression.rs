@@ -1,166 +1,474 @@
 use crate::{
     error::ParseError,
-    lexer::{Operator, Token},
+    lexer::{Keyword, Lexer, Operator, StringPart, Token},
     node::Node,
+    operators::{self, precedence, Associativity},
     parser::Parser,
+    value::OwnedValue,
 };
 
-impl<'a> Parser<'a> {
-    pub(crate) fn parse_expr(&mut self) -> Result<Node, ParseError> {
-        self.parse_or()
+/// The positional and named arguments of a function call or test.
+type ArgumentNodes = (Vec<Node>, Vec<(String, Node)>);
+
+/// How an infix or postfix operator combines with the expression to its left.
+enum Infix {
+    /// `lhs op rhs`
+    Operator(Operator),
+    /// `lhs is test`
+    Test,
+    /// `lhs[index]` or `lhs[start:end:step]`
+    Subscript,
+    /// `lhs?[index]`
+    OptionalSubscript,
+    /// `lhs.attribute`
+    Attribute,
+    /// `lhs?.attribute`
+    OptionalAttribute,
+}
+
+struct InfixRule {
+    precedence: u8,
+    associativity: Associativity,
+    infix: Infix,
+}
+
+impl InfixRule {
+    fn left_binding_power(&self) -> u16 {
+        operators::binding_powers(self.precedence, self.associativity).0
     }
 
-    fn parse_or(&mut self) -> Result<Node, ParseError> {
-        let mut expression = self.parse_and()?;
-        while let Some(token) = self.next_token()? {
-            match token {
-                Token::Operator(Operator::Or) => {
-                    let rhs = self.parse_and()?;
-                    expression = Node::Operation(expression.into(), Operator::Or, rhs.into());
-                    continue;
+    /// The minimum binding power of the operators in the right-hand side.
+    fn right_binding_power(&self) -> u16 {
+        operators::binding_powers(self.precedence, self.associativity).1
+    }
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn parse_expr(&mut self) -> Result<Node, ParseError> {
+        let expression = self.parse_operation(0)?;
+        match self.next_token()? {
+            // `x => x.name`
+            Some(Token::Arrow) => match expression {
+                Node::Variable(param) => {
+                    // The body can be another lambda, which would recurse without going
+                    // through `parse_operation`.
+                    let body = self.nested(|parser| {
+                        parser.descend()?;
+                        parser.parse_expr()
+                    })?;
+                    Ok(Node::Lambda(vec![param], body.into()))
                 }
-                _ => self.restore(token),
+                // Only a lone variable can be a parameter.
+                _ => Err(self.unexpected(Some(Token::Arrow), &[])),
+            },
+            Some(token) => {
+                self.restore(token);
+                Ok(expression)
             }
-            break;
+            None => Ok(expression),
         }
-        Ok(expression)
     }
 
-    fn parse_and(&mut self) -> Result<Node, ParseError> {
-        let mut expression = self.parse_comparisons()?;
+    /// This is a Pratt parser: it parses a prefix expression, then keeps folding infix and postfix
+    /// operators into it for as long as they bind at least as tightly as `min_binding_power`.
+    /// Operators are looked up in [`Parser::infix_rule`], so custom operators are parsed exactly
+    /// like builtin ones.
+    fn parse_operation(&mut self, min_binding_power: u16) -> Result<Node, ParseError> {
+        self.nested(|parser| parser.parse_operation_chain(min_binding_power))
+    }
+
+    fn parse_operation_chain(&mut self, min_binding_power: u16) -> Result<Node, ParseError> {
+        self.descend()?;
+        let mut expression = self.parse_primary()?;
+        // The precedence of the last non-associative operator in this chain, so that `a..b..c`
+        // is rejected.
+        let mut non_associative = None;
         while let Some(token) = self.next_token()? {
-            match token {
-                Token::Operator(Operator::And) => {
-                    let rhs = self.parse_comparisons()?;
-                    expression = Node::Operation(expression.into(), Operator::And, rhs.into());
-                    continue;
+            let rule = match self.infix_rule(&token) {
+                Some(rule) if rule.left_binding_power() >= min_binding_power => rule,
+                rule => {
+                    self.restore(token);
+                    if rule.is_none() {
+                        // An operator would have continued the expression, whatever the caller
+                        // expects next.
+                        self.expecting("an operator");
+                    }
+                    break;
+                }
+            };
+            if rule.associativity == Associativity::None {
+                if non_associative == Some(rule.precedence) {
+                    return Err(ParseError::NonAssociative(token));
                 }
-                _ => self.restore(token),
+                non_associative = Some(rule.precedence);
             }
-            break;
+            // Every operator wraps the expression so far in another node, which makes the tree
+            // taller without nesting the expression.
+            self.lengthen()?;
+            let right_binding_power = rule.right_binding_power();
+            expression = match rule.infix {
+                Infix::Operator(operator) => {
+                    // `x not in xs`
+                    if operator == Operator::NotIn {
+                        self.expect(Token::Keyword(Keyword::In))?;
+                    }
+                    let rhs = self.parse_operation(right_binding_power)?;
+                    Node::Operation(expression.into(), operator, rhs.into())
+                }
+                // `x is defined`
+                Infix::Test => self.parse_test(expression)?,
+                Infix::Subscript => self.parse_subscript(expression)?,
+                Infix::OptionalSubscript => {
+                    let index = self.parse_expr()?;
+                    self.expect(Token::ClosingSqBracket)?;
+                    Node::OptionalIndex(expression.into(), index.into())
+                }
+                Infix::Attribute => {
+                    Node::Attribute(expression.into(), self.parse_attribute_name()?)
+                }
+                Infix::OptionalAttribute => {
+                    Node::OptionalAttribute(expression.into(), self.parse_attribute_name()?)
+                }
+            };
         }
         Ok(expression)
     }
 
-    fn parse_comparisons(&mut self) -> Result<Node, ParseError> {
-        let mut expression = self.parse_polynomial()?;
-        while let Some(token) = self.next_token()? {
-            match token {
-                Token::Operator(operator) => match operator {
-                    Operator::IsEqualTo | Operator::IsNotEqualTo => {
-                        let rhs = self.parse_polynomial()?;
-                        expression = Node::Operation(expression.into(), operator, rhs.into());
-                        continue;
-                    }
-                    _ => self.restore(Token::Operator(operator)),
-                },
-                _ => self.restore(token),
+    /// The operator table. Returns how the token combines with the expression to its left, or
+    /// `None` if it ends the expression.
+    fn infix_rule(&self, token: &Token) -> Option<InfixRule> {
+        use precedence::*;
+        use Associativity::Left;
+        let (precedence, associativity, infix) = match token {
+            Token::Operator(operator) => {
+                let (precedence, associativity) = operators::builtin_precedence(operator)?;
+                (precedence, associativity, Infix::Operator(operator.clone()))
             }
-            break;
-        }
-        Ok(expression)
+            Token::Keyword(Keyword::In) => (COMPARISON, Left, Infix::Operator(Operator::In)),
+            Token::Keyword(Keyword::Not) => (COMPARISON, Left, Infix::Operator(Operator::NotIn)),
+            Token::Keyword(Keyword::Is) => (COMPARISON, Left, Infix::Test),
+            Token::OpeningSqBracket => (POSTFIX, Left, Infix::Subscript),
+            Token::QuestionSqBracket => (POSTFIX, Left, Infix::OptionalSubscript),
+            Token::Dot => (POSTFIX, Left, Infix::Attribute),
+            Token::QuestionDot => (POSTFIX, Left, Infix::OptionalAttribute),
+            // `a ** b` or `a mod b`
+            Token::CustomOperator(symbol) | Token::Identifier(symbol) => {
+                let operator = self.environment?.infix_operator(symbol)?;
+                (
+                    operator.precedence,
+                    operator.associativity,
+                    Infix::Operator(Operator::Custom(symbol.clone())),
+                )
+            }
+            _ => return None,
+        };
+        Some(InfixRule {
+            precedence,
+            associativity,
+            infix,
+        })
     }
 
-    /// This functions handles the lowest-precedence number operations (plus and minus).
-    fn parse_polynomial(&mut self) -> Result<Node, ParseError> {
-        let mut expression = self.parse_term()?;
-        while let Some(token) = self.next_token()? {
-            match token {
-                Token::Operator(operator) => match operator {
-                    Operator::Add | Operator::Subtract => {
-                        let term = self.parse_term()?;
-                        expression = Node::Operation(expression.into(), operator, term.into());
-                        continue;
-                    }
-                    _ => self.restore(Token::Operator(operator)),
-                },
-                _ => self.restore(token),
+    /// Parses the operand of a prefix operator with the given precedence.
+    fn parse_operand(&mut self, precedence: u8) -> Result<Node, ParseError> {
+        self.parse_operation(u16::from(precedence) * 2 + 1)
+    }
+
+    /// Parses the part of `value[index]` or `value[start:end:step]` after the opening bracket.
+    /// Every part of a slice is optional.
+    fn parse_subscript(&mut self, value: Node) -> Result<Node, ParseError> {
+        let start = self.parse_slice_bound()?;
+        match self.next_token()? {
+            Some(Token::ClosingSqBracket) => match start {
+                Some(index) => return Ok(Node::Index(value.into(), index.into())),
+                None => {
+                    let token = Some(Token::ClosingSqBracket);
+                    return Err(self.unexpected(token, &["an expression", "`:`"]));
+                }
+            },
+            Some(Token::Colon) => {}
+            token => return Err(self.unexpected(token, &["`]`", "`:`"])),
+        }
+        let end = self.parse_slice_bound()?;
+        let step = match self.next_token()? {
+            Some(Token::ClosingSqBracket) => None,
+            Some(Token::Colon) => {
+                let step = self.parse_slice_bound()?;
+                self.expect(Token::ClosingSqBracket)?;
+                step
             }
-            break;
+            token => return Err(self.unexpected(token, &["`]`", "`:`"])),
+        };
+        Ok(Node::Slice(
+            value.into(),
+            start.map(Into::into),
+            end.map(Into::into),
+            step.map(Into::into),
+        ))
+    }
+
+    fn parse_slice_bound(&mut self) -> Result<Option<Node>, ParseError> {
+        match self.peek_nth_token(0)? {
+            None => Err(self.unexpected(None, &["an expression", "`:`", "`]`"])),
+            Some(Token::Colon | Token::ClosingSqBracket) => Ok(None),
+            Some(_) => Ok(Some(self.parse_expr()?)),
         }
-        Ok(expression)
     }
 
-    /// This functions parses a term and handles the higher-precedence operations (multiply and divide).
-    fn parse_term(&mut self) -> Result<Node, ParseError> {
-        let mut term = self.parse_factor()?;
-        while let Some(token) = self.next_token()? {
-            match token {
-                Token::Operator(operator) => match operator {
-                    Operator::Multiply | Operator::Divide => {
-                        let factor = self.parse_factor()?;
-                        term = Node::Operation(term.into(), operator, factor.into());
-                        continue;
+    fn parse_interpolated_string(&mut self, parts: Vec<StringPart>) -> Result<Node, ParseError> {
+        let parts = parts
+            .into_iter()
+            .map(|part| match part {
+                StringPart::Text(text) => Ok(Node::Value(OwnedValue::String(text))),
+                StringPart::Expression(src) => {
+                    let mut lexer = Lexer::new_expression(&src);
+                    let mut parser = match self.environment {
+                        Some(environment) => {
+                            lexer = lexer.with_environment(environment);
+                            Parser::new(&mut lexer).with_environment(environment)
+                        }
+                        None => Parser::new(&mut lexer),
+                    };
+                    parser.depth = self.depth;
+                    parser.max_depth = self.max_depth;
+                    parser.height = self.height;
+                    parser.max_height = self.max_height;
+                    let expression = parser.parse_expr()?;
+                    match parser.next_token()? {
+                        None => Ok(expression),
+                        token => Err(parser.unexpected(token, &["`}`"])),
                     }
-                    _ => self.restore(Token::Operator(operator)),
-                },
-                _ => self.restore(token),
-            }
-            break;
+                }
+            })
+            .collect::<Result<Vec<Node>, ParseError>>()?;
+        Ok(Node::Concat(parts))
+    }
+
+    fn parse_attribute_name(&mut self) -> Result<String, ParseError> {
+        match self.next_token()? {
+            Some(Token::Identifier(identifier)) => Ok(identifier),
+            token => Err(self.unexpected(token, &["an attribute name"])),
         }
-        Ok(term)
     }
 
-    /// This function parses parentheses, literals, and mono-operations.
-    fn parse_factor(&mut self) -> Result<Node, ParseError> {
-        let token = self.expect_next_token()?;
+    fn parse_primary(&mut self) -> Result<Node, ParseError> {
+        let token = match self.next_token()? {
+            Some(token) => token,
+            None => return Err(self.unexpected(None, &["an expression"])),
+        };
         let factor = match token {
             Token::Literal(value) => Node::Value(value),
+            Token::InterpolatedString(parts) => self.parse_interpolated_string(parts)?,
             Token::OpeningSqBracket => self.parse_array()?,
-            Token::Exclamation => Node::Not(self.parse_factor()?.into()),
-            Token::Operator(Operator::Subtract) => Node::Negate(self.parse_factor()?.into()),
-            Token::OpeningParen => {
-                let expr = self.parse_expr()?;
-                self.expect(Token::ClosingParen, "parentheses")?;
-                expr
-            }
-            Token::Identifier(identifier) => match self.expect_next_token()? {
-                Token::OpeningParen => self.parse_function_call(identifier)?,
-                next_token => {
-                    self.restore(next_token);
-                    Node::Variable(identifier)
+            Token::Exclamation => Node::Not(self.parse_operand(precedence::PREFIX)?.into()),
+            Token::Operator(Operator::Subtract) => {
+                Node::Negate(self.parse_operand(precedence::PREFIX)?.into())
+            }
+            Token::OpeningParen => match self.parse_lambda_params()? {
+                // `(a, b) => a + b`
+                Some(params) => Node::Lambda(params, self.parse_expr()?.into()),
+                None => {
+                    let expr = self.parse_expr()?;
+                    self.expect(Token::ClosingParen)?;
+                    expr
+                }
+            },
+            // `~a` or `neg a`
+            Token::CustomOperator(symbol) => match self.prefix_precedence(&symbol) {
+                Some(precedence) => {
+                    Node::PrefixOperation(symbol, self.parse_operand(precedence)?.into())
+                }
+                None => {
+                    let token = Some(Token::CustomOperator(symbol));
+                    return Err(self.unexpected(token, &["an expression"]));
                 }
             },
-            token => return Err(ParseError::UnexpectedToken(token, "factor")),
+            Token::Identifier(identifier) => match self.prefix_precedence(&identifier) {
+                Some(precedence) => {
+                    Node::PrefixOperation(identifier, self.parse_operand(precedence)?.into())
+                }
+                None => match self.next_token()? {
+                    Some(Token::OpeningParen) => self.parse_function_call(identifier)?,
+                    Some(next_token) => {
+                        self.restore(next_token);
+                        Node::Variable(identifier)
+                    }
+                    None => Node::Variable(identifier),
+                },
+            },
+            token => return Err(self.unexpected(Some(token), &["an expression"])),
         };
         Ok(factor)
     }
 
+    /// Parses the part of `(a, b) =>` after the opening parenthesis. If the parentheses turn out
+    /// not to be a parameter list, nothing is consumed.
+    fn parse_lambda_params(&mut self) -> Result<Option<Vec<String>>, ParseError> {
+        let checkpoint = self.checkpoint();
+        match self.parse_lambda_params_until_arrow() {
+            Ok(None) => {
+                self.rewind(checkpoint);
+                Ok(None)
+            }
+            result => {
+                self.release(checkpoint);
+                result
+            }
+        }
+    }
+
+    /// Returns `None` as soon as the tokens can't be a parameter list.
+    fn parse_lambda_params_until_arrow(&mut self) -> Result<Option<Vec<String>>, ParseError> {
+        let mut params = Vec::new();
+        loop {
+            match self.next_token()? {
+                Some(Token::ClosingParen) if params.is_empty() => break,
+                Some(Token::Identifier(param)) => params.push(param),
+                _ => return Ok(None),
+            }
+            match self.next_token()? {
+                Some(Token::ClosingParen) => break,
+                Some(Token::Comma) => continue,
+                _ => return Ok(None),
+            }
+        }
+        match self.next_token()? {
+            Some(Token::Arrow) => Ok(Some(params)),
+            _ => Ok(None),
+        }
+    }
+
+    fn prefix_precedence(&self, symbol: &str) -> Option<u8> {
+        Some(self.environment?.prefix_operator(symbol)?.precedence)
+    }
+
     fn parse_function_call(&mut self, identifier: String) -> Result<Node, ParseError> {
+        let (args, named_args) = self.parse_arguments(&identifier)?;
+        Ok(Node::FunctionCall(identifier, args, named_args))
+    }
+
+    /// Parses the arguments of a function or test up to and including the closing parenthesis.
+    fn parse_arguments(&mut self, identifier: &str) -> Result<ArgumentNodes, ParseError> {
         let mut args = Vec::new();
+        let mut named_args: Vec<(String, Node)> = Vec::new();
         loop {
-            match self.expect_next_token()? {
-                Token::ClosingParen => break,
-                token => {
+            match self.next_token()? {
+                Some(Token::ClosingParen) => break,
+                None => return Err(self.unexpected(None, &["`)`", "an expression"])),
+                Some(token) => {
                     self.restore(token);
-                    args.push(self.parse_expr()?);
+                    let arg = self.parse_expr()?;
+                    match (arg, self.expect_next_token()?) {
+                        // `name = value`
+                        (Node::Variable(name), Token::Assign) => {
+                            if named_args.iter().any(|(other, _)| *other == name) {
+                                return Err(ParseError::DuplicateNamedArgument(
+                                    identifier.to_owned(),
+                                    name,
+                                ));
+                            }
+                            named_args.push((name, self.parse_expr()?));
+                        }
+                        (arg, token) => {
+                            self.restore(token);
+                            if !named_args.is_empty() {
+                                return Err(ParseError::PositionalAfterNamed(
+                                    identifier.to_owned(),
+                                ));
+                            }
+                            args.push(arg);
+                        }
+                    }
                 }
             }
-            match self.expect_next_token()? {
-                Token::ClosingParen => break,
-                Token::Comma => continue,
-                token => return Err(ParseError::UnexpectedToken(token, "function call")),
+            match self.next_token()? {
+                Some(Token::ClosingParen) => break,
+                Some(Token::Comma) => continue,
+                token => return Err(self.unexpected(token, &["`)`", "`,`"])),
             }
         }
-        Ok(Node::FunctionCall(identifier, args))
+        Ok((args, named_args))
+    }
+
+    /// Parses the part of `value is not test(args)` after `is`.
+    fn parse_test(&mut self, value: Node) -> Result<Node, ParseError> {
+        let (negated, token) = match self.next_token()? {
+            Some(Token::Keyword(Keyword::Not)) => (true, self.next_token()?),
+            token => (false, token),
+        };
+        let test = match token {
+            Some(Token::Identifier(test)) => test,
+            // `null` is lexed as a literal.
+            Some(Token::Literal(OwnedValue::Null)) => "null".to_owned(),
+            token => return Err(self.unexpected(token, &["a test name"])),
+        };
+        let (args, named_args) = match self.next_token()? {
+            Some(Token::OpeningParen) => self.parse_arguments(&test)?,
+            Some(token) => {
+                self.restore(token);
+                (Vec::new(), Vec::new())
+            }
+            None => (Vec::new(), Vec::new()),
+        };
+        let node = Node::Test(value.into(), test, args, named_args);
+        Ok(match negated {
+            true => Node::Not(node.into()),
+            false => node,
+        })
     }
 
     fn parse_array(&mut self) -> Result<Node, ParseError> {
         let mut array = Vec::new();
         loop {
-            match self.expect_next_token()? {
-                Token::ClosingSqBracket => break,
-                token => {
+            match self.next_token()? {
+                Some(Token::ClosingSqBracket) => break,
+                // `...items`
+                Some(Token::Ellipsis) => array.push(Node::Spread(self.parse_expr()?.into())),
+                None => return Err(self.unexpected(None, &["`]`", "an expression"])),
+                Some(token) => {
                     self.restore(token);
                     array.push(self.parse_expr()?);
                 }
             }
-            match self.expect_next_token()? {
-                Token::ClosingSqBracket => break,
-                Token::Comma => continue,
-                token => return Err(ParseError::UnexpectedToken(token, "array")),
+            match self.next_token()? {
+                Some(Token::ClosingSqBracket) => break,
+                Some(Token::Comma) => continue,
+                // `[x.name for x in hosts if x.down]`
+                Some(Token::Keyword(Keyword::For))
+                    if array.len() == 1 && !matches!(array[0], Node::Spread(_)) =>
+                {
+                    let element = array.pop().unwrap();
+                    return self.parse_comprehension(element);
+                }
+                token => return Err(self.unexpected(token, &["`]`", "`,`"])),
             }
         }
         Ok(Node::Array(array))
     }
+
+    /// Parses the part of a list comprehension after `for`, up to and including the closing
+    /// bracket.
+    fn parse_comprehension(&mut self, element: Node) -> Result<Node, ParseError> {
+        let identifier = match self.next_token()? {
+            Some(Token::Identifier(identifier)) => identifier,
+            token => return Err(self.unexpected(token, &["an identifier"])),
+        };
+        self.expect(Token::Keyword(Keyword::In))?;
+        let array = self.parse_expr()?;
+        let filter = match self.next_token()? {
+            Some(Token::Keyword(Keyword::If)) => {
+                let filter = self.parse_expr()?;
+                self.expect(Token::ClosingSqBracket)?;
+                Some(filter.into())
+            }
+            Some(Token::ClosingSqBracket) => None,
+            token => return Err(self.unexpected(token, &["`if`", "`]`"])),
+        };
+        Ok(Node::Comprehension(
+            identifier,
+            array.into(),
+            element.into(),
+            filter,
+        ))
+    }
 }
@@ -1,14 +1,65 @@
+use std::collections::VecDeque;
+
 use crate::{
+    environment::Environment,
     error::ParseError,
     lexer::{Keyword, Lexer, Operator, Token},
     node::Node,
     value::OwnedValue,
 };
 
+/// The maximum nesting depth of templates and their evaluation, unless configured otherwise.
+/// Deeper templates would risk overflowing the stack.
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// The maximum height of the tree, unless configured otherwise. Chains of operators and `elif`s
+/// make the tree taller without nesting the template, and most passes over the tree recurse once
+/// per level, so they are bounded by this instead of the nesting limit.
+pub(crate) const DEFAULT_MAX_HEIGHT: usize = 1024;
+
+/// Closing tags besides `/if` and `/for`. `end` closes any block.
+const END_TAGS: [&str; 3] = ["end", "endif", "endfor"];
+
+/// The identifier, array, filter, and separator of a `for` tag.
+type ForHeader = (String, Node, Option<Box<Node>>, Option<Box<Node>>);
+
 /// The parser converts the tokens produced by the lexer into an abstract syntax tree.
 pub struct Parser<'a> {
     pub(crate) lexer: &'a mut Lexer<'a>,
-    pub(crate) buffer: Option<Token>,
+    /// The tokens that have been lexed but not consumed yet, with the line each ended on.
+    pub(crate) lookahead: VecDeque<(Token, usize)>,
+    /// Consumed tokens are handed out, and only kept while there is a checkpoint to rewind to.
+    /// `kept` starts at the token at position `kept_from`.
+    pub(crate) kept: Vec<(Token, usize)>,
+    pub(crate) kept_from: usize,
+    /// How many checkpoints haven't been rewound to or released yet.
+    pub(crate) checkpoints: usize,
+    /// How many tokens have been consumed.
+    pub(crate) position: usize,
+    /// The lines of the last two consumed tokens, so that a restored token gets its line back.
+    pub(crate) line: usize,
+    pub(crate) previous_line: usize,
+    /// Whether the last consumed token was a `}}`, which errors at the end of a tag give back.
+    pub(crate) after_close: bool,
+    /// How many `for` bodies we are currently inside of. Used to reject `break` and `continue`
+    /// outside of a loop.
+    loop_depth: usize,
+    /// How deeply nested the node being parsed is. Every template tag, block, and operand counts
+    /// as a level, but operators that continue a chain and `elif`s don't.
+    pub(crate) depth: usize,
+    pub(crate) max_depth: usize,
+    /// How tall the tree being built is, which chains of operators and `elif`s add to as well.
+    pub(crate) height: usize,
+    pub(crate) max_height: usize,
+    /// Where custom operators are looked up.
+    pub(crate) environment: Option<&'a Environment>,
+    /// Whether errors are collected into `errors` instead of ending the parse.
+    pub(crate) recovering: bool,
+    pub(crate) errors: Vec<ParseError>,
+    /// What else would have been accepted as the token at `expected_position`. See
+    /// [`Parser::expecting`].
+    pub(crate) expected: Vec<&'static str>,
+    pub(crate) expected_position: usize,
 }
 
 impl<'a> Parser<'a> {
@@ -18,13 +69,69 @@ impl<'a> Parser<'a> {
         parser.parse_all()
     }
 
+    /// Parses a template that may use the custom operators registered in the environment.
+    pub fn parse_input_with(input: &str, environment: &Environment) -> Result<Node, ParseError> {
+        let mut lexer = Lexer::new(input).with_environment(environment);
+        let parser = Parser::new(&mut lexer).with_environment(environment);
+        parser.parse_all()
+    }
+
+    /// Parses a template, collecting every error instead of stopping at the first one. See
+    /// [`Parser::parse_all_recovering`].
+    pub fn parse_input_recovering(input: &str) -> (Node, Vec<ParseError>) {
+        let mut lexer = Lexer::new(input);
+        let parser = Parser::new(&mut lexer);
+        parser.parse_all_recovering()
+    }
+
     pub fn new(lexer: &'a mut Lexer<'a>) -> Self {
         Self {
             lexer,
-            buffer: None,
+            lookahead: VecDeque::new(),
+            kept: Vec::new(),
+            kept_from: 0,
+            checkpoints: 0,
+            position: 0,
+            line: 1,
+            previous_line: 1,
+            after_close: false,
+            loop_depth: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            height: 0,
+            max_height: DEFAULT_MAX_HEIGHT,
+            environment: None,
+            recovering: false,
+            errors: Vec::new(),
+            expected: Vec::new(),
+            expected_position: 0,
         }
     }
 
+    /// Makes the parser recognize the custom operators registered in the environment. The lexer
+    /// should be created with [`Lexer::with_environment`] as well.
+    pub fn with_environment(mut self, environment: &'a Environment) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    /// Sets how deeply tags and expressions may be nested before parsing fails with
+    /// [`ParseError::NestingTooDeep`]. Defaults to 64. Chains of operators and `elif`s don't nest;
+    /// see [`Parser::with_max_height`] for what limits them.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets how tall the tree may grow before parsing fails with [`ParseError::TooTall`].
+    /// Defaults to 1024. Every operator in a chain, `elif`, and level of nesting makes the tree a
+    /// level taller. Caches of taller trees have to be decoded with
+    /// [`Node::from_bytes_with_max_height`].
+    pub fn with_max_height(mut self, max_height: usize) -> Self {
+        self.max_height = max_height;
+        self
+    }
+
     pub fn parse_all(mut self) -> Result<Node, ParseError> {
         let mut nodes = Vec::new();
         while let Some(node) = self.next_node()? {
@@ -33,115 +140,236 @@ impl<'a> Parser<'a> {
         Ok(Node::Body(nodes))
     }
 
+    /// Parses the whole template even if it is broken, returning every lexer and parser error
+    /// along with a partial tree. Tags that fail to parse become [`Node::Error`], and parsing
+    /// resumes after their `}}`. Unclosed and mismatched blocks are closed where the error is
+    /// found. The tree can be inspected, but evaluating any [`Node::Error`] in it fails.
+    pub fn parse_all_recovering(mut self) -> (Node, Vec<ParseError>) {
+        self.recovering = true;
+        let mut nodes = Vec::new();
+        loop {
+            match self.next_node() {
+                Ok(Some(node)) => nodes.push(node),
+                Ok(None) => break,
+                // A `{{ else }}` or `{{ end }}` outside of a block. Recording can't fail here.
+                Err(err) => {
+                    let _ = self.skip_tag(err);
+                }
+            }
+        }
+        (Node::Body(nodes), self.errors)
+    }
+
     pub fn next_node(&mut self) -> Result<Option<Node>, ParseError> {
+        match self.parse_node() {
+            // These are the end of a block, not errors, if the caller is parsing one.
+            Err(err @ (ParseError::ExpectedToken(_) | ParseError::EndTag(_))) => Err(err),
+            Err(err) if self.recovering => {
+                self.skip_tag(err)?;
+                Ok(Some(Node::Error))
+            }
+            result => result,
+        }
+    }
+
+    fn parse_node(&mut self) -> Result<Option<Node>, ParseError> {
         let token = match self.next_token()? {
             None => return Ok(None),
             Some(token) => token,
         };
         let node = match token {
             Token::Text(string) => Node::Value(OwnedValue::String(string)),
-            Token::TemplateOpen => self.parse_template()?,
-            token => panic!("Something went horribly wrong: {token:?}"),
+            Token::TemplateOpen => self.nested(|parser| {
+                parser.descend()?;
+                parser.parse_template()
+            })?,
+            token => return Err(self.unexpected(Some(token), &["text", "`{{`"])),
         };
         Ok(Some(node))
     }
 
     fn parse_template(&mut self) -> Result<Node, ParseError> {
-        let node = match self.expect_next_token()? {
-            Token::Keyword(Keyword::If) => self.parse_if()?,
-            Token::Keyword(Keyword::For) => self.parse_for()?,
-            // These next two cases are returning early for expected "unexpected" tokens.
+        let token = self.expect_next_token()?;
+        if let Some(tag) = self.parse_end_tag(&token)? {
+            return Err(ParseError::EndTag(tag));
+        }
+        let node = match token {
+            Token::Keyword(Keyword::If) => {
+                let line = self.line();
+                self.parse_if(line)?
+            }
+            // The loop consumes the `}}` of its closing tag itself.
+            Token::Keyword(Keyword::For) => return self.parse_for(),
+            Token::Keyword(keyword @ (Keyword::Break | Keyword::Continue)) => {
+                if self.loop_depth == 0 {
+                    return Err(ParseError::OutsideLoop(keyword));
+                }
+                match keyword {
+                    Keyword::Break => Node::Break,
+                    _ => Node::Continue,
+                }
+            }
+            // This case is returning early for expected "unexpected" tokens.
             Token::Keyword(keyword) => {
                 return Err(ParseError::ExpectedToken(Token::Keyword(keyword)));
             }
-            Token::Operator(Operator::Divide) => {
-                return Err(ParseError::ExpectedToken(Token::Operator(Operator::Divide)));
-            }
             token => {
                 self.restore(token);
                 self.parse_expr()?
             }
         };
-        self.expect(Token::TemplateClose, "template")?;
+        self.parse_tag(|_| Ok(()))?;
         Ok(node)
     }
 
-    fn parse_if(&mut self) -> Result<Node, ParseError> {
-        let condition = self.parse_expr()?;
-        self.expect(Token::TemplateClose, "template if")?;
-        let mut then_nodes = Vec::new();
-        let else_node = loop {
-            match self.next_node() {
-                Ok(node) => then_nodes.push(node.ok_or(ParseError::UnexpectedEOF)?),
-                Err(ParseError::ExpectedToken(Token::Keyword(Keyword::Elif))) => {
-                    // This is so beautiful. We just consumed the elif keyword, so this single call
-                    // will parse the rest of this "if" tree up to the {{/if tokens, leaving the }}
-                    // token to be consumed by `parse_template`. Beautiful recursion.
-                    break Some(self.parse_if()?);
-                }
-                Err(ParseError::ExpectedToken(Token::Keyword(Keyword::Else))) => {
-                    break Some(self.parse_else()?);
-                }
-                Err(ParseError::ExpectedToken(Token::Operator(Operator::Divide))) => {
-                    self.expect(Token::Keyword(Keyword::If), "end if")?;
-                    break None;
-                }
-                Err(err) => return Err(err),
+    /// Returns the closing tag that `token` starts, if any: `/if`, `/for`, or one of
+    /// [`END_TAGS`]. The rest of the tag up to the `}}` is consumed.
+    fn parse_end_tag(&mut self, token: &Token) -> Result<Option<&'static str>, ParseError> {
+        match token {
+            Token::Operator(Operator::Divide) => match self.next_token()? {
+                Some(Token::Keyword(Keyword::If)) => Ok(Some("/if")),
+                Some(Token::Keyword(Keyword::For)) => Ok(Some("/for")),
+                token => Err(self.unexpected(token, &["`if`", "`for`"])),
+            },
+            // Only a lone identifier is a closing tag, so `{{ end + 1 }}` still works.
+            Token::Identifier(identifier)
+                if matches!(self.peek_nth_token(0)?, Some(Token::TemplateClose)) =>
+            {
+                Ok(END_TAGS.iter().copied().find(|tag| tag == identifier))
             }
+            _ => Ok(None),
+        }
+    }
+
+    /// Checks that the closing tag closes the block opened at `line`. A mismatched tag still
+    /// closes the block while recovering.
+    fn close_block(
+        &mut self,
+        tag: &'static str,
+        block: &'static str,
+        line: usize,
+    ) -> Result<(), ParseError> {
+        let closes = match tag {
+            "end" => true,
+            "/if" | "endif" => block == "if",
+            _ => block == "for",
         };
-        let then_node = Node::Body(then_nodes);
-        Ok(Node::IfThenElse(
+        if !closes {
+            self.record(ParseError::MismatchedEndTag(tag, block, line))?;
+        }
+        Ok(())
+    }
+
+    /// `line` is the line of the `if` tag, even when parsing an `elif`.
+    fn parse_if(&mut self, line: usize) -> Result<Node, ParseError> {
+        let mut condition = self.parse_tag(Self::parse_expr)?.unwrap_or(Node::Error);
+        // The body is a level deeper. Each `elif` is nested in the `else` of the branch before it,
+        // which makes the tree taller, but it doesn't nest the template.
+        self.descend()?;
+        let mut branches = Vec::new();
+        let mut else_node = None;
+        loop {
+            let mut then_nodes = Vec::new();
+            let is_elif = loop {
+                match self.next_node() {
+                    Ok(Some(node)) => then_nodes.push(node),
+                    Ok(None) => {
+                        self.record(ParseError::UnclosedBlock("if", line))?;
+                        break false;
+                    }
+                    Err(ParseError::ExpectedToken(Token::Keyword(Keyword::Elif))) => break true,
+                    Err(ParseError::ExpectedToken(Token::Keyword(Keyword::Else))) => {
+                        else_node = Some(self.parse_else(line)?);
+                        break false;
+                    }
+                    Err(ParseError::EndTag(tag)) => {
+                        self.close_block(tag, "if", line)?;
+                        break false;
+                    }
+                    Err(err) => self.skip_tag(err)?,
+                }
+            };
+            branches.push((condition, Node::Body(then_nodes)));
+            if !is_elif {
+                break;
+            }
+            self.lengthen()?;
+            condition = self.parse_tag(Self::parse_expr)?.unwrap_or(Node::Error);
+        }
+        let (condition, then_node) = branches.pop().expect("every `if` has a branch");
+        let mut node = Node::IfThenElse(
             condition.into(),
             then_node.into(),
             else_node.map(Into::into),
-        ))
+        );
+        for (condition, then_node) in branches.into_iter().rev() {
+            node = Node::IfThenElse(condition.into(), then_node.into(), Some(node.into()));
+        }
+        Ok(node)
     }
 
-    fn parse_else(&mut self) -> Result<Node, ParseError> {
-        self.expect(Token::TemplateClose, "template else")?;
+    fn parse_else(&mut self, line: usize) -> Result<Node, ParseError> {
+        self.parse_tag(|_| Ok(()))?;
+        self.descend()?;
         let mut body = Vec::new();
         loop {
             match self.next_node() {
-                Ok(node) => body.push(node.ok_or(ParseError::UnexpectedEOF)?),
+                Ok(Some(node)) => body.push(node),
+                Ok(None) => {
+                    self.record(ParseError::UnclosedBlock("if", line))?;
+                    break;
+                }
                 // {{ /if }}
-                Err(ParseError::ExpectedToken(Token::Operator(Operator::Divide))) => {
-                    self.expect(Token::Keyword(Keyword::If), "else end if")?;
+                Err(ParseError::EndTag(tag)) => {
+                    self.close_block(tag, "if", line)?;
                     // We leave the CloseTemplate token for `parse_template` to consume.
                     break;
                 }
-                Err(err) => return Err(err),
+                Err(err) => self.skip_tag(err)?,
             }
         }
         Ok(Node::Body(body))
     }
 
     fn parse_for(&mut self) -> Result<Node, ParseError> {
-        let identifier = match self.expect_next_token()? {
-            Token::Identifier(identifier) => identifier,
-            token => return Err(ParseError::UnexpectedToken(token, "for identifier")),
-        };
-        self.expect(Token::Keyword(Keyword::In), "for in")?;
-        let array = self.parse_expr()?;
-        let separator = match self.expect_next_token()? {
-            Token::TemplateClose => None,
-            token => {
-                self.restore(token);
-                let separator = self.parse_expr()?;
-                self.expect(Token::TemplateClose, "for array")?;
-                Some(separator.into())
-            }
-        };
+        let line = self.line();
+        let (identifier, array, filter, separator) = self
+            .parse_tag(Self::parse_for_header)?
+            .unwrap_or_else(|| (String::new(), Node::Error, None, None));
 
-        let mut body = Vec::new();
-        loop {
-            match self.next_node() {
-                Ok(node) => body.push(node.ok_or(ParseError::UnexpectedEOF)?),
-                Err(ParseError::ExpectedToken(Token::Operator(Operator::Divide))) => {
-                    self.expect(Token::Keyword(Keyword::For), "for")?;
-                    break;
+        self.descend()?;
+        let (body, is_closed) = self.in_loop(|parser| {
+            let mut body = Vec::new();
+            loop {
+                match parser.next_node() {
+                    Ok(Some(node)) => body.push(node),
+                    Ok(None) => {
+                        parser.record(ParseError::UnclosedBlock("for", line))?;
+                        break;
+                    }
+                    // An `else` or `elif` belongs to an `if` that the loop is in, so the loop
+                    // wasn't closed. While recovering, the loop is closed and the `if` gets the
+                    // tag back.
+                    Err(ParseError::ExpectedToken(
+                        keyword @ Token::Keyword(Keyword::Else | Keyword::Elif),
+                    )) => {
+                        parser.record(ParseError::UnclosedBlock("for", line))?;
+                        parser.restore(keyword);
+                        parser.restore(Token::TemplateOpen);
+                        return Ok((body, false));
+                    }
+                    Err(ParseError::EndTag(tag)) => {
+                        parser.close_block(tag, "for", line)?;
+                        break;
+                    }
+                    Err(err) => parser.skip_tag(err)?,
                 }
-                Err(err) => return Err(err),
             }
+            Ok((body, true))
+        })?;
+        // The `}}` of the closing tag, which a given back `else` doesn't have yet.
+        if is_closed {
+            self.parse_tag(|_| Ok(()))?;
         }
         let body_node = Node::Body(body);
         Ok(Node::ForIn(
@@ -149,6 +377,43 @@ impl<'a> Parser<'a> {
             array.into(),
             body_node.into(),
             separator,
+            filter,
         ))
     }
+
+    /// Parses `identifier in array if filter separator`, up to the `}}`.
+    fn parse_for_header(&mut self) -> Result<ForHeader, ParseError> {
+        let identifier = match self.next_token()? {
+            Some(Token::Identifier(identifier)) => identifier,
+            token => return Err(self.unexpected(token, &["an identifier"])),
+        };
+        self.expect(Token::Keyword(Keyword::In))?;
+        let array = self.parse_expr()?;
+        let filter = match self.next_token()? {
+            Some(Token::Keyword(Keyword::If)) => Some(self.parse_expr()?.into()),
+            Some(token) => {
+                self.restore(token);
+                None
+            }
+            None => None,
+        };
+        // A missing `}}` is reported by the caller.
+        let separator = match self.peek_nth_token(0)? {
+            Some(Token::TemplateClose) | None => None,
+            _ => Some(self.parse_expr()?.into()),
+        };
+        Ok((identifier, array, filter, separator))
+    }
+
+    /// Runs `parse` inside of a `for` body, where `break` and `continue` are allowed, and then
+    /// leaves it again, even if `parse` failed.
+    fn in_loop<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        self.loop_depth += 1;
+        let result = parse(self);
+        self.loop_depth -= 1;
+        result
+    }
 }
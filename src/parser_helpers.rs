@@ -1,34 +1,251 @@
 use crate::{error::ParseError, lexer::Token, parser::Parser};
 
+/// A position in the token stream that the parser can rewind to with [`Parser::rewind`]. Until it
+/// is rewound to or given up with [`Parser::release`], the tokens consumed after it are kept.
+#[must_use]
+pub(crate) struct Checkpoint {
+    position: usize,
+    line: usize,
+    previous_line: usize,
+    after_close: bool,
+}
+
 impl<'a> Parser<'a> {
     pub(crate) fn next_token(&mut self) -> Result<Option<Token>, ParseError> {
-        match &mut self.buffer {
-            None => self.lexer.yield_token().map_err(ParseError::LexerError),
-            buffer => Ok(buffer.take()),
+        self.peek_nth_token(0)?;
+        let Some((token, line)) = self.lookahead.pop_front() else {
+            return Ok(None);
+        };
+        self.position += 1;
+        self.previous_line = self.line;
+        self.line = line;
+        self.after_close = token == Token::TemplateClose;
+        // Only a checkpoint needs the token again.
+        if self.checkpoints > 0 {
+            self.kept.push((token.clone(), line));
         }
+        Ok(Some(token))
     }
 
     pub(crate) fn expect_next_token(&mut self) -> Result<Token, ParseError> {
-        self.next_token()?.ok_or(ParseError::UnexpectedEOF)
+        match self.next_token()? {
+            Some(token) => Ok(token),
+            None => Err(self.unexpected(None, &[])),
+        }
     }
 
-    pub(crate) fn expect(
-        &mut self,
-        expected_token: Token,
-        parsing: &'static str,
-    ) -> Result<(), ParseError> {
-        let next_token = self.expect_next_token()?;
-        if expected_token == next_token {
+    /// Returns the token `n` tokens ahead of the next one without consuming anything, lexing as
+    /// many tokens as needed.
+    pub(crate) fn peek_nth_token(&mut self, n: usize) -> Result<Option<&Token>, ParseError> {
+        while self.lookahead.len() <= n {
+            match self.lexer.next() {
+                Some(token) => {
+                    let token = token.map_err(ParseError::LexerError)?;
+                    self.lookahead.push_back((token, self.lexer.line()));
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(self.lookahead.get(n).map(|(token, _)| token))
+    }
+
+    /// The line of the last consumed token.
+    pub(crate) fn line(&self) -> usize {
+        self.line
+    }
+
+    pub(crate) fn expect(&mut self, expected_token: Token) -> Result<(), ParseError> {
+        let next_token = self.next_token()?;
+        if next_token.as_ref() == Some(&expected_token) {
             Ok(())
         } else {
-            Err(ParseError::UnexpectedToken(next_token, parsing))
+            let expected: Vec<_> = expected_token.symbol().into_iter().collect();
+            Err(self.unexpected(next_token, &expected))
         }
     }
 
-    /// Call this function when you get a token you don't need. Panics if you restore multiple
-    /// tokens in a row.
+    /// Notes that `description` would have been accepted as the next token, so that an error at
+    /// that token can list it.
+    pub(crate) fn expecting(&mut self, description: &'static str) {
+        if self.expected_position != self.position {
+            self.expected_position = self.position;
+            self.expected.clear();
+        }
+        if !self.expected.contains(&description) {
+            self.expected.push(description);
+        }
+    }
+
+    /// The error for the token that was just consumed, or for the end of the input if `token`
+    /// is `None`. Whatever else [`Parser::expecting`] noted for that token is added to
+    /// `expected`.
+    pub(crate) fn unexpected(&self, token: Option<Token>, expected: &[&'static str]) -> ParseError {
+        let position = match token {
+            Some(_) => self.position.saturating_sub(1),
+            None => self.position,
+        };
+        let mut expected = expected.to_vec();
+        if position == self.expected_position {
+            for description in &self.expected {
+                if !expected.contains(description) {
+                    expected.push(description);
+                }
+            }
+        }
+        match token {
+            Some(token) => ParseError::UnexpectedToken(token, expected),
+            None => ParseError::UnexpectedEOF(expected),
+        }
+    }
+
+    /// Call this function when you get a token you don't need. Tokens must be restored in the
+    /// reverse order they were taken in, and at most two can be restored before the next one is
+    /// consumed.
     pub(crate) fn restore(&mut self, token: Token) {
-        assert_eq!(self.buffer, None);
-        self.buffer = Some(token);
+        self.position -= 1;
+        if self.checkpoints > 0 {
+            self.kept.pop();
+            self.kept_from = self.kept_from.min(self.position);
+        }
+        self.lookahead.push_front((token, self.line));
+        self.line = self.previous_line;
+        self.after_close = false;
+    }
+
+    pub(crate) fn checkpoint(&mut self) -> Checkpoint {
+        if self.checkpoints == 0 {
+            self.kept_from = self.position;
+        }
+        self.checkpoints += 1;
+        Checkpoint {
+            position: self.position,
+            line: self.line,
+            previous_line: self.previous_line,
+            after_close: self.after_close,
+        }
+    }
+
+    /// Un-consumes every token taken since the checkpoint.
+    pub(crate) fn rewind(&mut self, checkpoint: Checkpoint) {
+        let kept = self.kept.drain(checkpoint.position - self.kept_from..);
+        for token in kept.rev() {
+            self.lookahead.push_front(token);
+        }
+        self.position = checkpoint.position;
+        self.line = checkpoint.line;
+        self.previous_line = checkpoint.previous_line;
+        self.after_close = checkpoint.after_close;
+        self.release(checkpoint);
+    }
+
+    /// Gives up on rewinding to the checkpoint. Once no checkpoint is left, consumed tokens are no
+    /// longer kept.
+    pub(crate) fn release(&mut self, _checkpoint: Checkpoint) {
+        self.checkpoints -= 1;
+        if self.checkpoints == 0 {
+            self.kept.clear();
+        }
+    }
+
+    /// Goes one level deeper into the template, failing if that exceeds the maximum depth.
+    pub(crate) fn descend(&mut self) -> Result<(), ParseError> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(ParseError::NestingTooDeep(self.max_depth));
+        }
+        self.lengthen()
+    }
+
+    /// Makes the tree one level taller without going deeper into the template, failing if that
+    /// exceeds the maximum height.
+    pub(crate) fn lengthen(&mut self) -> Result<(), ParseError> {
+        self.height += 1;
+        if self.height > self.max_height {
+            return Err(ParseError::TooTall(self.max_height));
+        }
+        Ok(())
+    }
+
+    /// Runs `parse` and then returns to the current depth and height, even if `parse` failed.
+    pub(crate) fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        let (depth, height) = (self.depth, self.height);
+        let result = parse(self);
+        (self.depth, self.height) = (depth, height);
+        result
+    }
+
+    /// While recovering, records the error and continues. Otherwise, returns the error.
+    pub(crate) fn record(&mut self, err: ParseError) -> Result<(), ParseError> {
+        if !self.recovering {
+            return Err(err);
+        }
+        // Every open tag runs into the end of the input; reporting it once is enough.
+        let is_repeated_eof = matches!(err, ParseError::UnexpectedEOF(_))
+            && self.errors.iter().any(|err| {
+                matches!(
+                    err,
+                    ParseError::UnexpectedEOF(_) | ParseError::UnclosedBlock(..)
+                )
+            });
+        if !is_repeated_eof {
+            self.errors.push(err);
+        }
+        Ok(())
+    }
+
+    /// Like [`Parser::record`], but also skips the rest of the tag the error happened in.
+    pub(crate) fn recover(&mut self, err: ParseError) -> Result<(), ParseError> {
+        self.record(err)?;
+        self.synchronize();
+        Ok(())
+    }
+
+    /// Skips to the `}}` that ends the current tag, leaving it to be consumed. If the error was
+    /// caused by the `}}` itself, it is un-consumed. Lexer errors along the way are recorded.
+    fn synchronize(&mut self) {
+        if self.after_close {
+            self.restore(Token::TemplateClose);
+            return;
+        }
+        loop {
+            match self.peek_nth_token(0) {
+                Ok(Some(Token::TemplateClose)) | Ok(None) => return,
+                Ok(Some(_)) => {
+                    let _ = self.next_token();
+                }
+                Err(err) => self.errors.push(err),
+            }
+        }
+    }
+
+    /// Like [`Parser::recover`], but also consumes the `}}`, so that parsing resumes after the tag.
+    pub(crate) fn skip_tag(&mut self, err: ParseError) -> Result<(), ParseError> {
+        self.recover(err)?;
+        if let Ok(Some(Token::TemplateClose)) = self.peek_nth_token(0) {
+            let _ = self.next_token();
+        }
+        Ok(())
+    }
+
+    /// Parses the rest of a tag with `parse`, followed by its `}}`. While recovering, a tag that
+    /// fails to parse is recorded and skipped, and `None` is returned.
+    pub(crate) fn parse_tag<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Option<T>, ParseError> {
+        let value = match parse(self) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.recover(err)?;
+                None
+            }
+        };
+        if let Err(err) = self.expect(Token::TemplateClose) {
+            self.skip_tag(err)?;
+        }
+        Ok(value)
     }
 }
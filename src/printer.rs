@@ -0,0 +1,575 @@
+use crate::{
+    environment::Environment,
+    error::PrintError,
+    lexer::Operator,
+    node::{Children, Node},
+    operators::{self, precedence},
+    value::OwnedValue,
+    visit,
+};
+
+/// The binding power of expressions that never need parentheses.
+const ATOM: u16 = u16::MAX;
+
+/// Closing tags that would be parsed as such if a variable with the same name were printed alone.
+const END_TAGS: [&str; 3] = ["end", "endif", "endfor"];
+
+/// The printer converts a syntax tree back into template source. Tags and expressions are printed
+/// in a canonical style: one space inside of `{{ }}` and around binary operators, single-quoted
+/// strings, and only the parentheses that the precedence of the operators requires. Text is
+/// printed as is, so parsing the output yields the same tree.
+///
+/// The tree doesn't tell string literals in tags apart from text, so `{{ 'x' }}` is printed as `x`.
+/// Values without a literal syntax, such as objects, are printed as strings. Booleans don't have
+/// literals either, since `true` and `false` are variable names, so they are printed as `0 == 0`
+/// and `0 != 0`, which parse back to operations rather than values. The parser never produces
+/// booleans, but [`Node::optimize`] folds operations into them.
+pub struct Printer<'a> {
+    indent: Option<String>,
+    environment: Option<&'a Environment>,
+}
+
+/// An expression printed without surrounding parentheses, along with how tightly it binds on each
+/// side. Binding powers are those of the Pratt parser; [`ATOM`] never needs parentheses.
+struct Printed {
+    source: String,
+    left: u16,
+    right: u16,
+}
+
+impl Printed {
+    fn atom(source: String) -> Self {
+        Self {
+            source,
+            left: ATOM,
+            right: ATOM,
+        }
+    }
+
+    fn parenthesized(self) -> Self {
+        Self::atom(format!("({})", self.source))
+    }
+}
+
+impl Default for Printer<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Printer<'a> {
+    pub fn new() -> Self {
+        Self {
+            indent: None,
+            environment: None,
+        }
+    }
+
+    /// Indents the lines of text inside of blocks with `indent` once per level of nesting. This
+    /// changes the whitespace of the rendered output, so the tree no longer round-trips.
+    pub fn with_indent(mut self, indent: impl Into<String>) -> Self {
+        self.indent = Some(indent.into());
+        self
+    }
+
+    /// Makes the printer aware of the precedence of the custom operators registered in the
+    /// environment. Without it, custom operations are always parenthesized.
+    pub fn with_environment(mut self, environment: &'a Environment) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    /// Prints a template. Trees from a recovering parse fail if they have syntax errors.
+    pub fn print(&self, node: &Node) -> Result<String, PrintError> {
+        if has_errors(node) {
+            return Err(PrintError::SyntaxError);
+        }
+        Ok(self.print_template(node))
+    }
+
+    fn print_template(&self, node: &Node) -> String {
+        let mut out = String::new();
+        self.print_body(&mut out, node, 0);
+        out
+    }
+
+    /// Prints the nodes of a template or block body. `depth` is the number of enclosing blocks.
+    fn print_body(&self, out: &mut String, body: &Node, depth: usize) {
+        let nodes = match body {
+            Node::Body(nodes) => nodes.as_slice(),
+            node => std::slice::from_ref(node),
+        };
+        let mut after_text = false;
+        for (i, node) in nodes.iter().enumerate() {
+            let is_last = i + 1 == nodes.len();
+            match node {
+                // Strings that could not be told apart from the surrounding text are printed as
+                // tags instead.
+                Node::Value(OwnedValue::String(text))
+                    if !after_text && is_plain_text(text, is_last && depth == 0) =>
+                {
+                    self.print_text(out, text, depth, is_last);
+                    after_text = true;
+                    continue;
+                }
+                Node::Body(_) => self.print_body(out, node, depth),
+                Node::IfThenElse(condition, then_node, else_node) => {
+                    out.push_str("{{ if ");
+                    out.push_str(&self.print_expr(condition).source);
+                    out.push_str(" }}");
+                    self.print_body(out, then_node, depth + 1);
+                    let mut else_node = else_node.as_deref();
+                    while let Some(node) = else_node {
+                        match node {
+                            Node::IfThenElse(condition, then_node, next) => {
+                                out.push_str("{{ elif ");
+                                out.push_str(&self.print_expr(condition).source);
+                                out.push_str(" }}");
+                                self.print_body(out, then_node, depth + 1);
+                                else_node = next.as_deref();
+                            }
+                            node => {
+                                out.push_str("{{ else }}");
+                                self.print_body(out, node, depth + 1);
+                                else_node = None;
+                            }
+                        }
+                    }
+                    out.push_str("{{ /if }}");
+                }
+                Node::ForIn(identifier, array, body, separator, filter) => {
+                    out.push_str("{{ for ");
+                    out.push_str(identifier);
+                    out.push_str(" in ");
+                    let mut header = vec![self.print_expr(array).source];
+                    if let Some(filter) = filter {
+                        header.push(format!("if {}", self.print_expr(filter).source));
+                    }
+                    if let Some(separator) = separator {
+                        let separator = self.print_expr(separator).source;
+                        // The separator must not continue the expression before it, which would
+                        // be parsed as a subscript (`[`), an operation (`-`), or a call (`(`).
+                        if separator.starts_with(|c: char| c.is_alphanumeric() || "'_!".contains(c))
+                        {
+                            header.push(separator);
+                        } else {
+                            if let Some(last) = header.last_mut() {
+                                if last.ends_with(|c: char| c.is_alphanumeric() || c == '_') {
+                                    *last = format!("({last})");
+                                }
+                            }
+                            header.push(format!("({separator})"));
+                        }
+                    }
+                    out.push_str(&header.join(" "));
+                    out.push_str(" }}");
+                    self.print_body(out, body, depth + 1);
+                    out.push_str("{{ /for }}");
+                }
+                Node::Break => out.push_str("{{ break }}"),
+                Node::Continue => out.push_str("{{ continue }}"),
+                // A lone `end` would be parsed as a closing tag.
+                Node::Variable(name) if END_TAGS.contains(&name.as_str()) => {
+                    out.push_str(&format!("{{{{ ({name}) }}}}"));
+                }
+                node => {
+                    out.push_str("{{ ");
+                    out.push_str(&self.print_expr(node).source);
+                    out.push_str(" }}");
+                }
+            }
+            after_text = false;
+        }
+    }
+
+    /// Prints text, re-indenting the lines inside of blocks if an indent is set. The last line of
+    /// a block body holds the tag that ends the block, so it is indented one level less.
+    fn print_text(&self, out: &mut String, text: &str, depth: usize, is_last: bool) {
+        let indent = match &self.indent {
+            Some(indent) if depth > 0 => indent,
+            _ => {
+                out.push_str(text);
+                return;
+            }
+        };
+        let mut lines = text.split('\n');
+        out.push_str(lines.next().unwrap_or_default());
+        let mut lines = lines.peekable();
+        while let Some(line) = lines.next() {
+            out.push('\n');
+            let line = line.trim_start_matches([' ', '\t']);
+            let level = match lines.peek() {
+                // Blank lines aren't indented.
+                Some(_) if line.is_empty() => continue,
+                None if line.is_empty() && is_last => depth - 1,
+                _ => depth,
+            };
+            out.push_str(&indent.repeat(level));
+            out.push_str(line);
+        }
+    }
+
+    /// Prints an expression. Chains of operators, subscripts, attributes, and tests are printed
+    /// from their first operand up in a loop, so that long chains don't recurse.
+    fn print_expr(&self, node: &Node) -> Printed {
+        let mut links = Vec::new();
+        let mut node = node;
+        while let Some(operand) = left_operand(node) {
+            links.push(node);
+            node = operand;
+        }
+        let mut printed = self.print_term(node);
+        for link in links.into_iter().rev() {
+            printed = self.print_link(link, printed);
+        }
+        printed
+    }
+
+    /// Prints the rest of a link of a chain, given its printed left operand.
+    fn print_link(&self, node: &Node, lhs: Printed) -> Printed {
+        // The dot in `1.name` would be lexed as part of the number.
+        let is_number = matches!(left_operand(node), Some(Node::Value(OwnedValue::Number(_))));
+        match node {
+            Node::Index(_, index) => self.print_postfix(
+                lhs,
+                is_number,
+                format!("[{}]", self.print_expr(index).source),
+            ),
+            Node::Slice(_, start, end, step) => {
+                let bound = |bound: &Option<Box<Node>>| match bound {
+                    Some(bound) => self.print_expr(bound).source,
+                    None => String::new(),
+                };
+                let mut subscript = format!("[{}:{}", bound(start), bound(end));
+                if step.is_some() {
+                    subscript += &format!(":{}", bound(step));
+                }
+                subscript.push(']');
+                self.print_postfix(lhs, is_number, subscript)
+            }
+            Node::OptionalIndex(_, index) => self.print_postfix(
+                lhs,
+                is_number,
+                format!("?[{}]", self.print_expr(index).source),
+            ),
+            Node::Attribute(_, attribute) => {
+                self.print_postfix(lhs, is_number, format!(".{attribute}"))
+            }
+            Node::OptionalAttribute(_, attribute) => {
+                self.print_postfix(lhs, is_number, format!("?.{attribute}"))
+            }
+            Node::Operation(_, operator, rhs) => self.print_operation(lhs, operator, rhs),
+            Node::Test(_, test, args, named_args) => {
+                self.print_test(lhs, "is", test, args, named_args)
+            }
+            // `x is not test` is parsed into `Not(Test(..))`.
+            Node::Not(test) => match &**test {
+                Node::Test(_, test, args, named_args) => {
+                    self.print_test(lhs, "is not", test, args, named_args)
+                }
+                _ => unreachable!("only a negated test continues a chain"),
+            },
+            node => unreachable!("{node:?} doesn't continue a chain"),
+        }
+    }
+
+    /// Prints an expression that doesn't continue a chain.
+    fn print_term(&self, node: &Node) -> Printed {
+        match node {
+            Node::Value(value) => self.print_value(value),
+            Node::Variable(name) => Printed::atom(name.clone()),
+            Node::FunctionCall(name, args, named_args) => Printed::atom(format!(
+                "{name}({})",
+                self.print_arguments(args, named_args)
+            )),
+            Node::Array(items) => Printed::atom(format!("[{}]", self.print_list(items))),
+            Node::Concat(parts) => Printed::atom(self.print_interpolated_string(parts)),
+            Node::Spread(value) => Printed::atom(format!("...{}", self.print_expr(value).source)),
+            Node::Comprehension(identifier, array, element, filter) => {
+                let mut source = format!(
+                    "[{} for {identifier} in {}",
+                    self.print_expr(element).source,
+                    self.print_expr(array).source,
+                );
+                if let Some(filter) = filter {
+                    source += &format!(" if {}", self.print_expr(filter).source);
+                }
+                source.push(']');
+                Printed::atom(source)
+            }
+            Node::Not(operand) => self.print_prefix("!", precedence::PREFIX, operand),
+            Node::Negate(operand) => self.print_prefix("-", precedence::PREFIX, operand),
+            Node::PrefixOperation(symbol, operand) => {
+                let precedence = self
+                    .environment
+                    .and_then(|environment| environment.prefix_operator(symbol))
+                    .map(|operator| operator.precedence);
+                let symbol = match operators::is_word(symbol) {
+                    true => format!("{symbol} "),
+                    false => symbol.clone(),
+                };
+                match precedence {
+                    Some(precedence) => self.print_prefix(&symbol, precedence, operand),
+                    None => Printed {
+                        left: 0,
+                        right: 0,
+                        ..self.print_prefix(&symbol, u8::MAX, operand)
+                    },
+                }
+            }
+            Node::Index(..)
+            | Node::Slice(..)
+            | Node::OptionalIndex(..)
+            | Node::Attribute(..)
+            | Node::OptionalAttribute(..)
+            | Node::Operation(..)
+            | Node::Test(..) => unreachable!("chains are printed by `print_expr`"),
+            Node::Lambda(params, body) => {
+                let params = match params.as_slice() {
+                    [param] => param.clone(),
+                    params => format!("({})", params.join(", ")),
+                };
+                // Lambdas take everything to their right, so they are parenthesized anywhere but
+                // at the start of an expression.
+                Printed {
+                    source: format!("{params} => {}", self.print_expr(body).source),
+                    left: 0,
+                    right: 0,
+                }
+            }
+            // These can't be part of an expression. They are printed as their tags would be.
+            Node::Body(_) | Node::IfThenElse(..) | Node::ForIn(..) => {
+                Printed::atom(self.print_template(node))
+            }
+            Node::Break => Printed::atom("break".into()),
+            Node::Continue => Printed::atom("continue".into()),
+            Node::Error => unreachable!("trees with syntax errors aren't printed"),
+        }
+    }
+
+    fn print_value(&self, value: &OwnedValue) -> Printed {
+        match value {
+            OwnedValue::String(string) => Printed::atom(quote(string)),
+            // Negative numbers are parsed as negations.
+            OwnedValue::Number(number) if *number < 0.0 => Printed {
+                source: number.to_string(),
+                left: ATOM,
+                right: u16::from(precedence::PREFIX) * 2 + 1,
+            },
+            OwnedValue::Number(number) => Printed::atom(number.to_string()),
+            OwnedValue::Null => Printed::atom("null".into()),
+            OwnedValue::Boolean(boolean) => {
+                let operator = match boolean {
+                    true => Operator::IsEqualTo,
+                    false => Operator::IsNotEqualTo,
+                };
+                let zero = Node::Value(OwnedValue::Number(0.0));
+                self.print_operation(self.print_expr(&zero), &operator, &zero)
+            }
+            OwnedValue::Array(items) => {
+                let items: Vec<_> = items
+                    .iter()
+                    .map(|item| self.print_value(item).source)
+                    .collect();
+                Printed::atom(format!("[{}]", items.join(", ")))
+            }
+            OwnedValue::Range(_) | OwnedValue::Object(_) => {
+                Printed::atom(quote(&value.to_string()))
+            }
+        }
+    }
+
+    fn print_operation(&self, lhs: Printed, operator: &Operator, rhs: &Node) -> Printed {
+        let symbol = match operator {
+            Operator::Custom(symbol) => symbol.as_str(),
+            operator => operator.symbol().unwrap_or_default().trim_matches('`'),
+        };
+        let precedence = match operator {
+            Operator::Custom(symbol) => self
+                .environment
+                .and_then(|environment| environment.infix_operator(symbol))
+                .map(|operator| (operator.precedence, operator.associativity)),
+            operator => operators::builtin_precedence(operator),
+        };
+        // Without a precedence, anything but an atom has to be parenthesized on both sides.
+        let (left, right) = match precedence {
+            Some((precedence, associativity)) => {
+                operators::binding_powers(precedence, associativity)
+            }
+            None => (ATOM - 1, ATOM),
+        };
+        let lhs = left_operand_of(lhs, left);
+        let rhs = self.print_operand(rhs, right);
+        let source = match operator {
+            Operator::Range | Operator::RangeInclusive => {
+                format!("{}{symbol}{}", lhs.source, rhs.source)
+            }
+            _ => format!("{} {symbol} {}", lhs.source, rhs.source),
+        };
+        match precedence {
+            Some(_) => Printed {
+                source,
+                left: left.min(lhs.left),
+                right: right.min(rhs.right),
+            },
+            None => Printed {
+                source,
+                left: 0,
+                right: 0,
+            },
+        }
+    }
+
+    fn print_prefix(&self, symbol: &str, precedence: u8, operand: &Node) -> Printed {
+        let binding_power = u16::from(precedence) * 2 + 1;
+        let operand = self.print_operand(operand, binding_power);
+        Printed {
+            source: format!("{symbol}{}", operand.source),
+            left: ATOM,
+            right: binding_power.min(operand.right),
+        }
+    }
+
+    /// Prints `value` followed by a subscript or attribute.
+    fn print_postfix(&self, value: Printed, is_number: bool, postfix: String) -> Printed {
+        let left = u16::from(precedence::POSTFIX) * 2;
+        let mut value = left_operand_of(value, left);
+        if is_number && postfix.starts_with('.') {
+            value = value.parenthesized();
+        }
+        Printed {
+            source: value.source + &postfix,
+            left: left.min(value.left),
+            right: ATOM,
+        }
+    }
+
+    fn print_test(
+        &self,
+        value: Printed,
+        is: &str,
+        test: &str,
+        args: &[Node],
+        named_args: &[(String, Node)],
+    ) -> Printed {
+        let left = u16::from(precedence::COMPARISON) * 2;
+        let value = left_operand_of(value, left);
+        let mut source = format!("{} {is} {test}", value.source);
+        if !args.is_empty() || !named_args.is_empty() {
+            source += &format!("({})", self.print_arguments(args, named_args));
+        }
+        Printed {
+            source,
+            left: left.min(value.left),
+            right: ATOM,
+        }
+    }
+
+    /// Prints an expression that is parsed as an operation with the given minimum binding power,
+    /// such as the right-hand side of an operator.
+    fn print_operand(&self, node: &Node, binding_power: u16) -> Printed {
+        let printed = self.print_expr(node);
+        match printed.left < binding_power {
+            true => printed.parenthesized(),
+            false => printed,
+        }
+    }
+
+    fn print_list(&self, nodes: &[Node]) -> String {
+        let items: Vec<_> = nodes
+            .iter()
+            .map(|node| self.print_expr(node).source)
+            .collect();
+        items.join(", ")
+    }
+
+    fn print_arguments(&self, args: &[Node], named_args: &[(String, Node)]) -> String {
+        let mut arguments = self.print_list(args);
+        for (name, value) in named_args {
+            if !arguments.is_empty() {
+                arguments += ", ";
+            }
+            arguments += &format!("{name} = {}", self.print_expr(value).source);
+        }
+        arguments
+    }
+
+    /// Prints `f'text {expression}'`. Braces in the text are doubled.
+    fn print_interpolated_string(&self, parts: &[Node]) -> String {
+        let mut source = "f'".to_owned();
+        let mut after_text = false;
+        for part in parts {
+            match part {
+                Node::Value(OwnedValue::String(text)) if !after_text && !text.is_empty() => {
+                    let text = escape(text).replace('{', "{{").replace('}', "}}");
+                    source += &text;
+                    after_text = true;
+                    continue;
+                }
+                part => source += &format!("{{{}}}", self.print_expr(part).source),
+            }
+            after_text = false;
+        }
+        source.push('\'');
+        source
+    }
+}
+
+/// The operand that a chain continues: the left-hand side of an operator, subscript, attribute, or
+/// test.
+fn left_operand(node: &Node) -> Option<&Node> {
+    match node {
+        Node::Index(value, _)
+        | Node::Slice(value, ..)
+        | Node::OptionalIndex(value, _)
+        | Node::Attribute(value, _)
+        | Node::OptionalAttribute(value, _)
+        | Node::Operation(value, ..)
+        | Node::Test(value, ..) => Some(value),
+        Node::Not(test) => match &**test {
+            Node::Test(value, ..) => Some(value),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Parenthesizes the printed left-hand side of an operator if it would bind to the right less
+/// tightly than the operator's left binding power.
+fn left_operand_of(printed: Printed, binding_power: u16) -> Printed {
+    match printed.right <= binding_power {
+        true => printed.parenthesized(),
+        false => printed,
+    }
+}
+
+/// Returns whether a string can be printed as text without changing how the template is parsed.
+/// Text that is followed by a tag can't end with a brace.
+fn is_plain_text(text: &str, is_end_of_template: bool) -> bool {
+    !text.is_empty() && !text.contains("{{") && (is_end_of_template || !text.ends_with('{'))
+}
+
+fn quote(string: &str) -> String {
+    format!("'{}'", escape(string))
+}
+
+fn escape(string: &str) -> String {
+    string
+        .replace('\\', "\\\\")
+        .replace('\'', "\\'")
+        .replace('\n', "\\n")
+}
+
+/// Whether a node contains [`Node::Error`].
+fn has_errors(node: &Node) -> bool {
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        if let Node::Error = node {
+            return true;
+        }
+        let mut children = Children(Vec::new());
+        visit::walk(&mut children, node);
+        stack.extend(children.0);
+    }
+    false
+}
@@ -0,0 +1,213 @@
+use std::{collections::HashMap, fmt, io};
+
+use crate::{
+    environment::Environment,
+    error::ValueError,
+    node::{interrupts, iterate, Interrupt, Node},
+    runtime::{text, write},
+    value::{OwnedValue, Value},
+    variables::Variables,
+};
+
+impl Node {
+    /// Renders a template into a writer instead of returning a string. Bodies, `if` nodes, and
+    /// `for` loops write their output as they go. Only the items of loops with a separator, loops
+    /// whose filter can `break`, and bodies that are evaluated as values are buffered.
+    ///
+    /// The output is the same as [`Node::evaluate`] returns, and so are the errors, though some of
+    /// the output may already have been written when an error occurs.
+    pub fn render_to<V: Variables, W: fmt::Write + ?Sized>(
+        &self,
+        variables: &V,
+        environment: &Environment,
+        out: &mut W,
+    ) -> Result<(), ValueError> {
+        let mut out = out;
+        let result = match self {
+            Node::Body(_) | Node::ForIn(..) => {
+                self._render(variables, environment, &HashMap::new(), 0, &mut out)
+            }
+            node => node
+                ._evaluate(variables, environment, &HashMap::new(), 0)
+                .and_then(|value| Ok(text(&mut out, &value.unwrap_string()?)?)),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(Interrupt::Error(err)) => Err(err),
+            Err(Interrupt::Break(_) | Interrupt::Continue(_)) => Err(ValueError::OperationError(
+                "`break` or `continue` outside of a for loop".into(),
+            )),
+        }
+    }
+
+    /// Like [`Node::render_to`], but for an [`io::Write`], whose errors are returned as
+    /// [`ValueError::IoError`]. Output is written in small pieces, so files and sockets should be
+    /// wrapped in a [`BufWriter`](io::BufWriter).
+    pub fn render_to_io<V: Variables, W: io::Write + ?Sized>(
+        &self,
+        variables: &V,
+        environment: &Environment,
+        out: &mut W,
+    ) -> Result<(), ValueError> {
+        let mut adapter = IoAdapter { out, error: None };
+        match self.render_to(variables, environment, &mut adapter) {
+            Err(ValueError::WriteError(err)) => match adapter.error {
+                Some(err) => Err(ValueError::IoError(err)),
+                None => Err(ValueError::WriteError(err)),
+            },
+            result => result,
+        }
+    }
+
+    fn _render<'a, V: Variables>(
+        &'a self,
+        variables: &'a V,
+        environment: &Environment,
+        local_vars: &HashMap<String, Value<'a>>,
+        depth: usize,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), Interrupt> {
+        if !matches!(self, Node::Body(_) | Node::IfThenElse(..) | Node::ForIn(..)) {
+            let value = self._evaluate_into(variables, environment, local_vars, depth, out)?;
+            return Ok(write(out, &value)?);
+        }
+        if depth > environment.max_depth() {
+            return Err(ValueError::NestingTooDeep(environment.max_depth()).into());
+        }
+        let depth = depth + 1;
+        match self {
+            Node::Body(nodes) => {
+                for node in nodes {
+                    node._render(variables, environment, local_vars, depth, out)?;
+                }
+            }
+            Node::IfThenElse(..) => {
+                // Like `_evaluate`, `elif`s are tried in a loop.
+                let mut node = self;
+                while let Node::IfThenElse(condition, then_node, else_node) = node {
+                    let condition =
+                        condition._evaluate_into(variables, environment, local_vars, depth, out)?;
+                    if condition.inner().is_truthy() {
+                        return then_node._render(variables, environment, local_vars, depth, out);
+                    }
+                    match else_node {
+                        Some(else_node) => node = else_node,
+                        None => return Ok(()),
+                    }
+                }
+                node._render(variables, environment, local_vars, depth, out)?;
+            }
+            Node::ForIn(identifier, array, body, separator, filter) => {
+                let evaluation =
+                    array._evaluate_into(variables, environment, local_vars, depth, out)?;
+                let items = iterate(evaluation.inner())?;
+                let mut local_vars = local_vars.clone();
+                let render_items = |out: &mut dyn fmt::Write| -> Result<(), Interrupt> {
+                    let mut is_first = true;
+                    for item in items {
+                        local_vars.insert(identifier.to_owned(), item);
+                        if let Some(filter) = filter {
+                            let evaluation =
+                                filter._evaluate(variables, environment, &local_vars, depth)?;
+                            if !evaluation.inner().is_truthy() {
+                                continue;
+                            }
+                        }
+                        // Whether an item gets a separator depends on whether it rendered
+                        // anything before it was interrupted, so those items are buffered.
+                        let Some(separator) = separator else {
+                            match body._render(variables, environment, &local_vars, depth, out) {
+                                Ok(()) | Err(Interrupt::Continue(_)) => continue,
+                                Err(Interrupt::Break(_)) => break,
+                                Err(err) => return Err(err),
+                            }
+                        };
+                        let separator =
+                            separator._evaluate(variables, environment, &local_vars, depth)?;
+                        let separator = match separator.inner() {
+                            OwnedValue::String(string) => string,
+                            value => &value.to_text()?,
+                        };
+                        let mut buffer = String::new();
+                        let (interrupted, is_break) = match body._render(
+                            variables,
+                            environment,
+                            &local_vars,
+                            depth,
+                            &mut buffer,
+                        ) {
+                            Ok(()) => (false, false),
+                            Err(Interrupt::Break(_)) => (true, true),
+                            Err(Interrupt::Continue(_)) => (true, false),
+                            Err(err) => return Err(err),
+                        };
+                        if !(interrupted && buffer.is_empty()) {
+                            if !is_first {
+                                text(out, separator)?;
+                            }
+                            text(out, &buffer)?;
+                            is_first = false;
+                        }
+                        if is_break {
+                            break;
+                        }
+                    }
+                    Ok(())
+                };
+                // `break` in the filter or the separator goes to an outer loop and drops what this
+                // loop rendered. The parser doesn't allow it there, but trees built by hand can.
+                let dropped = [filter, separator]
+                    .into_iter()
+                    .any(|node| node.as_deref().is_some_and(interrupts));
+                let result = if dropped {
+                    let mut buffer = String::new();
+                    render_items(&mut buffer).and_then(|()| Ok(text(out, &buffer)?))
+                } else {
+                    render_items(out)
+                };
+                result.map_err(|interrupt| carry(interrupt, out))?;
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Evaluates a node as a value while rendering. The output that `break` or `continue` carries
+    /// out of the node is written before the interrupt is passed on.
+    fn _evaluate_into<'a, V: Variables>(
+        &'a self,
+        variables: &'a V,
+        environment: &Environment,
+        local_vars: &HashMap<String, Value<'a>>,
+        depth: usize,
+        out: &mut dyn fmt::Write,
+    ) -> Result<Value<'a>, Interrupt> {
+        self._evaluate(variables, environment, local_vars, depth)
+            .map_err(|interrupt| carry(interrupt, out))
+    }
+}
+
+/// Writes the output that an interrupt carries, and returns the interrupt to pass on.
+fn carry(interrupt: Interrupt, out: &mut dyn fmt::Write) -> Interrupt {
+    let result = match interrupt {
+        Interrupt::Break(rest) => text(out, &rest).map(|()| Interrupt::Break(String::new())),
+        Interrupt::Continue(rest) => text(out, &rest).map(|()| Interrupt::Continue(String::new())),
+        err => Ok(err),
+    };
+    result.unwrap_or_else(Interrupt::from)
+}
+
+/// Keeps the error of an [`io::Write`], since [`fmt::Write`] can only report that it failed.
+struct IoAdapter<'a, W: ?Sized> {
+    out: &'a mut W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write + ?Sized> fmt::Write for IoAdapter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.out.write_all(s.as_bytes()).map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
+    }
+}
@@ -0,0 +1,228 @@
+//! The functions that code emitted by [`Generator`](crate::Generator) calls. They evaluate single
+//! nodes the way the tree-walker does, so that generated code renders the same output and errors.
+//! This module is not part of the public API.
+
+use std::fmt;
+
+use crate::{
+    arguments::Arguments,
+    environment::Environment,
+    error::ValueError,
+    lexer::Operator,
+    node,
+    operators::PrefixFn,
+    value::{Lambda, OwnedValue, Range, Value},
+    variables::Variables,
+    vm::Items,
+};
+
+/// How the body of a `for` loop with a separator ended.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Control {
+    Done,
+    Break,
+    Continue,
+}
+
+pub fn check_depth(environment: &Environment, depth: usize) -> Result<(), ValueError> {
+    if depth > environment.max_depth() {
+        return Err(ValueError::NestingTooDeep(environment.max_depth()));
+    }
+    Ok(())
+}
+
+pub fn fail(message: &str) -> ValueError {
+    ValueError::OperationError(message.into())
+}
+
+pub fn text<W: fmt::Write + ?Sized>(out: &mut W, text: &str) -> Result<(), ValueError> {
+    out.write_str(text).map_err(ValueError::WriteError)
+}
+
+/// Writes the string form of a value.
+pub fn write<W: fmt::Write + ?Sized>(out: &mut W, value: &Value) -> Result<(), ValueError> {
+    match value.inner() {
+        OwnedValue::String(string) => text(out, string),
+        value => text(out, &value.to_text()?),
+    }
+}
+
+/// Writes a template that isn't a body, which has to evaluate to a string.
+pub fn write_string<W: fmt::Write + ?Sized>(out: &mut W, value: Value) -> Result<(), ValueError> {
+    text(out, &value.unwrap_string()?)
+}
+
+pub fn to_string(value: &Value) -> Result<String, ValueError> {
+    match value.inner() {
+        OwnedValue::String(string) => Ok(string.clone()),
+        value => value.to_text(),
+    }
+}
+
+/// Adds the output of an item to the output of a loop with a separator. An item that was skipped
+/// before it rendered anything doesn't get a separator.
+pub fn add_item<W: fmt::Write + ?Sized>(
+    out: &mut W,
+    separator: &str,
+    is_first: &mut bool,
+    item: &str,
+    control: Control,
+) -> Result<(), ValueError> {
+    if control != Control::Done && item.is_empty() {
+        return Ok(());
+    }
+    if !*is_first {
+        text(out, separator)?;
+    }
+    *is_first = false;
+    text(out, item)
+}
+
+pub fn truthy(value: &Value) -> bool {
+    value.inner().is_truthy()
+}
+
+pub fn global<'a, V: Variables>(variables: &'a V, name: &str) -> Result<Value<'a>, ValueError> {
+    variables
+        .get(name)
+        .map(Value::Borrowed)
+        .ok_or_else(|| ValueError::UndefinedVariable(name.to_owned()))
+}
+
+pub fn global_defined<'a, V: Variables>(variables: &'a V, name: &str) -> Option<Value<'a>> {
+    variables.get(name).map(Value::Borrowed)
+}
+
+/// Treats null as missing, like `?.`, `?[]`, and `??` do.
+pub fn optional(value: Option<Value>) -> Option<Value> {
+    value.filter(|value| *value.inner() != OwnedValue::Null)
+}
+
+pub fn index<'a>(value: Value<'a>, index: &Value) -> Result<Value<'a>, ValueError> {
+    let item = match value {
+        Value::Borrowed(value) => value.index(index.inner())?,
+        value => value.inner().index(index.inner())?.to_owned_value().into(),
+    };
+    Ok(item)
+}
+
+pub fn index_defined<'a>(value: Value<'a>, index: &Value) -> Result<Option<Value<'a>>, ValueError> {
+    let item = match value {
+        Value::Borrowed(value) => value.get_index(index.inner())?,
+        value => value
+            .inner()
+            .get_index(index.inner())?
+            .map(|item| item.to_owned_value().into()),
+    };
+    Ok(item)
+}
+
+pub fn attribute<'a>(object: Value<'a>, attribute: &str) -> Result<Value<'a>, ValueError> {
+    attribute_defined(object, attribute)?
+        .ok_or_else(|| ValueError::OperationError(format!("Missing attribute {attribute:?}")))
+}
+
+pub fn attribute_defined<'a>(
+    object: Value<'a>,
+    attribute: &str,
+) -> Result<Option<Value<'a>>, ValueError> {
+    let value = match object {
+        Value::Borrowed(object) => object.get_attribute(attribute)?.map(Value::Borrowed),
+        object => object
+            .inner()
+            .get_attribute(attribute)?
+            .map(|value| value.clone().into()),
+    };
+    Ok(value)
+}
+
+/// Checks a slice bound, where null means no bound.
+pub fn bound(bound: Value) -> Result<Option<f64>, ValueError> {
+    match bound.inner() {
+        OwnedValue::Null => Ok(None),
+        _ => Ok(Some(bound.unwrap_f64()?)),
+    }
+}
+
+pub fn operate(
+    lhs: &Value,
+    op: &Operator,
+    rhs: &Value,
+    environment: &Environment,
+) -> Result<OwnedValue, ValueError> {
+    node::operate(lhs.inner(), op, rhs.inner(), environment)
+}
+
+pub fn custom_operation(
+    lhs: &Value,
+    symbol: &str,
+    rhs: &Value,
+    environment: &Environment,
+) -> Result<OwnedValue, ValueError> {
+    match environment.infix_operator(symbol) {
+        Some(operator) => (operator.evaluate)(lhs.inner(), rhs.inner()),
+        None => Err(ValueError::UndefinedOperator(symbol.to_owned())),
+    }
+}
+
+/// Looks up a prefix operator, which happens before its operand is evaluated.
+pub fn prefix_operator<'a>(
+    environment: &'a Environment,
+    symbol: &str,
+) -> Result<&'a PrefixFn, ValueError> {
+    match environment.prefix_operator(symbol) {
+        Some(operator) => Ok(&*operator.evaluate),
+        None => Err(ValueError::UndefinedOperator(symbol.to_owned())),
+    }
+}
+
+pub fn call(
+    identifier: &str,
+    args: Arguments,
+    environment: &Environment,
+) -> Result<OwnedValue, ValueError> {
+    node::call_function(identifier, args, environment)
+}
+
+pub fn test(
+    test: &str,
+    value: Option<Value>,
+    args: Arguments,
+    environment: &Environment,
+) -> Result<bool, ValueError> {
+    node::run_test(test, value, args, environment)
+}
+
+pub fn iterate<'a>(value: Value<'a>) -> Result<impl Iterator<Item = Value<'a>>, ValueError> {
+    Items::new(value)
+}
+
+pub fn spread<'a>(value: Value<'a>) -> Result<impl Iterator<Item = Value<'a>>, ValueError> {
+    Items::spread(value)
+}
+
+pub fn range(start: f64, end: f64, step: f64, inclusive: bool) -> Range {
+    Range::new(start, end, step, inclusive).expect("the range was valid when it was generated")
+}
+
+/// Gives a closure the signature of a lambda. Closures only get a signature that is generic over
+/// the lifetime of their arguments when they are passed to a function that asks for one.
+pub fn lambda_fn<F>(function: F) -> F
+where
+    F: for<'b> Fn(Vec<Value<'b>>) -> Result<OwnedValue, ValueError>,
+{
+    function
+}
+
+pub fn lambda<'a>(
+    function: &'a dyn for<'b> Fn(Vec<Value<'b>>) -> Result<OwnedValue, ValueError>,
+) -> Value<'a> {
+    Value::Lambda(Lambda { function })
+}
+
+/// Checks the number of arguments a lambda was called with.
+pub fn params<const N: usize>(args: Vec<Value>) -> Result<[Value; N], ValueError> {
+    let len = args.len();
+    args.try_into()
+        .map_err(|_| ValueError::OperationError(format!("Lambda takes {N} arguments, got {len}")))
+}
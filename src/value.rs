@@ -1,4 +1,8 @@
-use std::ops::{Add, Div, Mul, Sub};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::{Add, Div, Mul, Sub},
+};
 
 use crate::error::ValueError;
 
@@ -6,31 +10,103 @@ use crate::error::ValueError;
 pub enum Value<'a> {
     Owned(OwnedValue),
     Borrowed(&'a OwnedValue),
+    /// A lambda passed to a function, such as `x => x * 2` in `map(xs, x => x * 2)`.
+    Lambda(Lambda<'a>),
 }
 
+/// A lambda expression that captured the scope it was written in. Lambdas only exist for the
+/// duration of the function call they are passed to.
+#[derive(Clone, Copy)]
+pub struct Lambda<'a> {
+    pub(crate) function: &'a dyn for<'b> Fn(Vec<Value<'b>>) -> Result<OwnedValue, ValueError>,
+}
+
+/// What lambdas look like to code that expects data.
+static LAMBDA_PLACEHOLDER: OwnedValue = OwnedValue::Null;
+
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OwnedValue {
     String(String),
     Number(f64),
     Boolean(bool),
     Array(Vec<OwnedValue>),
+    Range(Range),
+    Object(BTreeMap<String, OwnedValue>),
+    Null,
+}
+
+/// A lazily evaluated sequence of numbers, produced by `a..b`, `a..=b`, and `range()`. Iterating
+/// over a range never materializes it into an array.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "RangeFields", into = "RangeFields")
+)]
+pub struct Range {
+    start: f64,
+    end: f64,
+    step: f64,
+    inclusive: bool,
+}
+
+/// The fields of a [`Range`], which are checked by [`Range::new`] when it is deserialized.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RangeFields {
+    start: f64,
+    end: f64,
+    step: f64,
+    inclusive: bool,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RangeFields> for Range {
+    type Error = ValueError;
+
+    fn try_from(fields: RangeFields) -> Result<Self, ValueError> {
+        Range::new(fields.start, fields.end, fields.step, fields.inclusive)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<Range> for RangeFields {
+    fn from(range: Range) -> Self {
+        Self {
+            start: range.start,
+            end: range.end,
+            step: range.step,
+            inclusive: range.inclusive,
+        }
+    }
 }
 
 impl<'a> Value<'a> {
+    /// Returns the data this value holds. Lambdas hold no data and read as null.
     pub fn inner(&self) -> &OwnedValue {
         match self {
             Value::Owned(val) => val,
-            Value::Borrowed(val) => *val,
+            Value::Borrowed(val) => val,
+            Value::Lambda(_) => &LAMBDA_PLACEHOLDER,
+        }
+    }
+
+    pub fn as_lambda(&self) -> Option<Lambda<'a>> {
+        match self {
+            Value::Lambda(lambda) => Some(*lambda),
+            _ => None,
         }
     }
 
-    pub fn unwrap_string(self) -> String {
+    pub fn unwrap_string(self) -> Result<String, ValueError> {
         match self {
-            Value::Owned(val) => match val {
-                OwnedValue::String(string) => string,
-                _ => unimplemented!(),
-            },
-            _ => unimplemented!(),
+            Value::Owned(OwnedValue::String(string)) => Ok(string),
+            Value::Borrowed(OwnedValue::String(string)) => Ok(string.clone()),
+            val => Err(ValueError::OperationError(format!(
+                "Cannot unwrap {:?} as string",
+                val.inner()
+            ))),
         }
     }
 
@@ -47,10 +123,18 @@ impl<'a> Value<'a> {
         match self {
             Value::Borrowed(value) => (*value).to_owned(),
             Value::Owned(value) => value.to_owned(),
+            Value::Lambda(_) => LAMBDA_PLACEHOLDER.clone(),
         }
     }
 }
 
+impl Lambda<'_> {
+    /// Evaluates the lambda's body with its parameters bound to `args`.
+    pub fn call(&self, args: Vec<Value>) -> Result<OwnedValue, ValueError> {
+        (self.function)(args)
+    }
+}
+
 impl OwnedValue {
     pub fn is_truthy(&self) -> bool {
         match self {
@@ -58,23 +142,370 @@ impl OwnedValue {
             OwnedValue::Number(number) => *number != 0.0,
             OwnedValue::Boolean(boolean) => *boolean,
             OwnedValue::Array(vec) => !vec.is_empty(),
+            OwnedValue::Range(range) => !range.is_empty(),
+            OwnedValue::Object(object) => !object.is_empty(),
+            OwnedValue::Null => false,
+        }
+    }
+
+    /// Implements `item in self`: array items, substrings, numbers in a range, and object keys.
+    pub fn contains(&self, item: &OwnedValue) -> Result<bool, ValueError> {
+        match (self, item) {
+            (OwnedValue::Array(array), item) => Ok(array.contains(item)),
+            (OwnedValue::String(string), OwnedValue::String(substring)) => {
+                Ok(string.contains(substring.as_str()))
+            }
+            (OwnedValue::Range(range), OwnedValue::Number(number)) => Ok(range.contains(*number)),
+            (OwnedValue::Range(_), _) => Ok(false),
+            (OwnedValue::Object(object), OwnedValue::String(key)) => Ok(object.contains_key(key)),
+            _ => Err(ValueError::OperationError(format!(
+                "Cannot check whether {item:?} is in {self:?}"
+            ))),
+        }
+    }
+
+    /// Builds the range `self..end` (or `self..=end`).
+    pub fn range_to(&self, end: &OwnedValue, inclusive: bool) -> Result<OwnedValue, ValueError> {
+        match (self, end) {
+            (OwnedValue::Number(start), OwnedValue::Number(end)) => {
+                Ok(OwnedValue::Range(Range::new(*start, *end, 1.0, inclusive)?))
+            }
+            _ => Err(ValueError::OperationError(format!(
+                "Cannot create a range from {self:?} to {end:?}"
+            ))),
+        }
+    }
+
+    /// Indexes into an array, string, range, or object. A number selects a single item, counting
+    /// from the end if it is negative, and a range selects a slice.
+    pub fn index(&self, index: &OwnedValue) -> Result<Value<'_>, ValueError> {
+        self.get_index(index)?.ok_or_else(|| match index {
+            OwnedValue::String(key) => ValueError::OperationError(format!("Missing key {key:?}")),
+            _ => ValueError::OperationError(format!("Index {index} is out of range")),
+        })
+    }
+
+    /// Like `index`, but returns `None` if the index is out of range or the key is missing.
+    pub fn get_index(&self, index: &OwnedValue) -> Result<Option<Value<'_>>, ValueError> {
+        let item = match (self, index) {
+            (OwnedValue::Array(array), OwnedValue::Number(i)) => {
+                resolve_index(array.len(), *i)?.map(|i| Value::Borrowed(&array[i]))
+            }
+            (OwnedValue::Array(array), OwnedValue::Range(range)) => {
+                let indices = range.slice_indices(array.len())?;
+                Some(
+                    indices
+                        .map(|i| array[i].clone())
+                        .collect::<Vec<OwnedValue>>()
+                        .into(),
+                )
+            }
+            (OwnedValue::String(string), OwnedValue::Number(i)) => {
+                let chars = string.chars().collect::<Vec<char>>();
+                resolve_index(chars.len(), *i)?.map(|i| chars[i].to_string().into())
+            }
+            (OwnedValue::String(string), OwnedValue::Range(range)) => {
+                let chars = string.chars().collect::<Vec<char>>();
+                let indices = range.slice_indices(chars.len())?;
+                Some(indices.map(|i| chars[i]).collect::<String>().into())
+            }
+            (OwnedValue::Range(range), OwnedValue::Number(i)) => {
+                resolve_index(range.len(), *i)?.map(|i| range.nth(i).into())
+            }
+            (OwnedValue::Range(range), OwnedValue::Range(slice)) => {
+                let indices = slice.slice_indices(range.len())?;
+                check_range_len(indices.len())?;
+                Some(
+                    indices
+                        .map(|i| OwnedValue::Number(range.nth(i)))
+                        .collect::<Vec<OwnedValue>>()
+                        .into(),
+                )
+            }
+            (OwnedValue::Object(object), OwnedValue::String(key)) => {
+                object.get(key).map(Value::Borrowed)
+            }
+            _ => {
+                return Err(ValueError::OperationError(format!(
+                    "Cannot index {self:?} with {index:?}"
+                )));
+            }
+        };
+        Ok(item)
+    }
+
+    /// Implements `self[start:end:step]` on arrays, strings, and ranges. Strings are sliced by
+    /// character, negative bounds count from the end, and out-of-range bounds are clamped.
+    pub fn slice(
+        &self,
+        start: Option<f64>,
+        end: Option<f64>,
+        step: Option<f64>,
+    ) -> Result<OwnedValue, ValueError> {
+        let slice = match self {
+            OwnedValue::Array(array) => OwnedValue::Array(
+                slice_indices(array.len(), start, end, step)?
+                    .map(|i| array[i].clone())
+                    .collect(),
+            ),
+            OwnedValue::String(string) => {
+                let chars = string.chars().collect::<Vec<char>>();
+                OwnedValue::String(
+                    slice_indices(chars.len(), start, end, step)?
+                        .map(|i| chars[i])
+                        .collect(),
+                )
+            }
+            OwnedValue::Range(range) => {
+                let indices = slice_indices(range.len(), start, end, step)?;
+                check_range_len(indices.len())?;
+                OwnedValue::Array(indices.map(|i| OwnedValue::Number(range.nth(i))).collect())
+            }
+            _ => {
+                return Err(ValueError::OperationError(format!("Cannot slice {self:?}")));
+            }
+        };
+        Ok(slice)
+    }
+
+    /// Returns the attribute `name` of an object, or `None` if the object doesn't have it.
+    pub fn get_attribute(&self, name: &str) -> Result<Option<&OwnedValue>, ValueError> {
+        match self {
+            OwnedValue::Object(object) => Ok(object.get(name)),
+            _ => Err(ValueError::OperationError(format!(
+                "Cannot access attribute {name:?} of {self:?}"
+            ))),
+        }
+    }
+
+    /// The string form of the value that templates write. Unlike `to_string`, it fails instead of
+    /// writing out a range with too many items.
+    pub(crate) fn to_text(&self) -> Result<String, ValueError> {
+        self.check_ranges()?;
+        Ok(self.to_string())
+    }
+
+    fn check_ranges(&self) -> Result<(), ValueError> {
+        match self {
+            OwnedValue::Range(range) => check_range_len(range.len()),
+            OwnedValue::Array(values) => values.iter().try_for_each(OwnedValue::check_ranges),
+            OwnedValue::Object(object) => object.values().try_for_each(OwnedValue::check_ranges),
+            _ => Ok(()),
         }
     }
 }
 
-impl ToString for OwnedValue {
-    fn to_string(&self) -> String {
+impl Range {
+    pub fn new(start: f64, end: f64, step: f64, inclusive: bool) -> Result<Self, ValueError> {
+        if step == 0.0 || !step.is_finite() {
+            return Err(ValueError::OperationError(format!(
+                "Invalid range step: {step}"
+            )));
+        }
+        if !start.is_finite() || !end.is_finite() {
+            return Err(ValueError::OperationError(format!(
+                "Invalid range bounds: {start} to {end}"
+            )));
+        }
+        Ok(Self {
+            start,
+            end,
+            step,
+            inclusive,
+        })
+    }
+
+    pub fn start(&self) -> f64 {
+        self.start
+    }
+
+    pub fn end(&self) -> f64 {
+        self.end
+    }
+
+    pub fn step(&self) -> f64 {
+        self.step
+    }
+
+    pub fn is_inclusive(&self) -> bool {
+        self.inclusive
+    }
+
+    pub fn len(&self) -> usize {
+        let steps = (self.end - self.start) / self.step;
+        // Float to int casts saturate, and NaN becomes 0.
+        if self.inclusive {
+            (steps.floor() + 1.0).max(0.0) as usize
+        } else {
+            steps.ceil().max(0.0) as usize
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = f64> + '_ {
+        (0..self.len()).map(|i| self.nth(i))
+    }
+
+    pub fn contains(&self, number: f64) -> bool {
+        let steps = (number - self.start) / self.step;
+        steps.fract() == 0.0 && steps >= 0.0 && (steps as usize) < self.len()
+    }
+
+    pub(crate) fn nth(&self, i: usize) -> f64 {
+        self.start + i as f64 * self.step
+    }
+
+    /// Interprets the range as a slice of a sequence with `len` items. Bounds may be negative to
+    /// count from the end and are clamped to the sequence.
+    fn slice_indices(
+        &self,
+        len: usize,
+    ) -> Result<impl ExactSizeIterator<Item = usize>, ValueError> {
+        let end = if self.inclusive {
+            // Resolve a negative end before making it exclusive, so that `-1` still means the
+            // last item rather than becoming `0`.
+            let end = if self.end < 0.0 {
+                self.end + len as f64
+            } else {
+                self.end
+            } + self.step.signum();
+            if end >= 0.0 {
+                Some(end)
+            } else if self.step > 0.0 {
+                Some(0.0)
+            } else {
+                // A descending slice that runs through the first item has no exclusive end.
+                None
+            }
+        } else {
+            Some(self.end)
+        };
+        slice_indices(len, Some(self.start), end, Some(self.step))
+    }
+}
+
+/// Computes the indices selected by the slice `start:end:step` of a sequence with `len` items.
+/// Negative bounds count from the end, and out-of-range bounds are clamped.
+pub(crate) fn slice_indices(
+    len: usize,
+    start: Option<f64>,
+    end: Option<f64>,
+    step: Option<f64>,
+) -> Result<impl ExactSizeIterator<Item = usize>, ValueError> {
+    let step = step.map_or(Ok(1), to_integer)?;
+    if step == 0 {
+        return Err(ValueError::OperationError(
+            "Slice step cannot be zero".into(),
+        ));
+    }
+    let len = i64::try_from(len).unwrap_or(i64::MAX);
+    let clamp = |bound: f64, lower: i64, upper: i64| -> Result<i64, ValueError> {
+        let bound = to_integer(bound)?;
+        let bound = if bound < 0 {
+            bound.saturating_add(len)
+        } else {
+            bound
+        };
+        Ok(bound.clamp(lower, upper))
+    };
+    let (start, end) = if step > 0 {
+        (
+            start.map_or(Ok(0), |start| clamp(start, 0, len))?,
+            end.map_or(Ok(len), |end| clamp(end, 0, len))?,
+        )
+    } else {
+        (
+            start.map_or(Ok(len - 1), |start| clamp(start, -1, len - 1))?,
+            end.map_or(Ok(-1), |end| clamp(end, -1, len - 1))?,
+        )
+    };
+    let span = if step > 0 { end - start } else { start - end };
+    let count = (span.max(0) as u64).div_ceil(step.unsigned_abs()) as usize;
+    Ok((0..count).map(move |i| (start + i as i64 * step) as usize))
+}
+
+/// The longest string that `"ab" * n` may produce, so that a short template can't ask for an
+/// arbitrarily large allocation.
+const MAX_REPEAT_LEN: usize = 1 << 24;
+
+/// The most items that slicing, spreading, or writing a range may produce. Ranges are lazy, so
+/// looping over `0..1000000000000` is fine, but turning it into an array or a string isn't.
+const MAX_RANGE_LEN: usize = 1 << 20;
+
+/// Fails if a range is turned into more items than [`MAX_RANGE_LEN`].
+pub(crate) fn check_range_len(len: usize) -> Result<(), ValueError> {
+    if len > MAX_RANGE_LEN {
+        return Err(ValueError::OperationError(format!(
+            "Cannot turn a range into {len} items"
+        )));
+    }
+    Ok(())
+}
+
+/// `"ab" * 3`. Fractional counts are truncated.
+fn repeat(string: &str, count: f64) -> Result<OwnedValue, ValueError> {
+    let len = count.trunc() * string.len() as f64;
+    if !count.is_finite() || count < 0.0 || len > MAX_REPEAT_LEN as f64 {
+        return Err(ValueError::OperationError(format!(
+            "Cannot repeat {string:?} {count} times"
+        )));
+    }
+    Ok(OwnedValue::String(string.repeat(count as usize)))
+}
+
+fn to_integer(number: f64) -> Result<i64, ValueError> {
+    if number.fract() != 0.0 || !number.is_finite() {
+        return Err(ValueError::OperationError(format!(
+            "Expected an integer, got {number}"
+        )));
+    }
+    Ok(number as i64)
+}
+
+/// Resolves a possibly negative index into a sequence with `len` items. Returns `None` if the
+/// index is out of range.
+fn resolve_index(len: usize, index: f64) -> Result<Option<usize>, ValueError> {
+    let i = to_integer(index)?;
+    let len = i64::try_from(len).unwrap_or(i64::MAX);
+    let resolved = if i < 0 { i.saturating_add(len) } else { i };
+    if resolved < 0 || resolved >= len {
+        return Ok(None);
+    }
+    Ok(Some(resolved as usize))
+}
+
+impl fmt::Display for OwnedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            OwnedValue::String(string) => string.to_owned(),
-            OwnedValue::Number(num) => num.to_string(),
-            OwnedValue::Boolean(boolean) => boolean.to_string(),
-            OwnedValue::Array(vec) => vec
-                .iter()
-                .map(|v| v.to_string())
-                .collect::<Vec<String>>()
-                .join(", "),
+            OwnedValue::String(string) => f.write_str(string),
+            OwnedValue::Number(num) => write!(f, "{num}"),
+            OwnedValue::Boolean(boolean) => write!(f, "{boolean}"),
+            OwnedValue::Array(vec) => write_list(f, vec.iter()),
+            OwnedValue::Range(range) => write_list(f, range.iter()),
+            OwnedValue::Object(object) => write_list(
+                f,
+                object.iter().map(|(key, value)| format!("{key}: {value}")),
+            ),
+            OwnedValue::Null => Ok(()),
+        }
+    }
+}
+
+/// Writes the items separated by commas. Ranges can be too long to collect first.
+fn write_list<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    items: impl Iterator<Item = T>,
+) -> fmt::Result {
+    for (i, item) in items.enumerate() {
+        if i != 0 {
+            f.write_str(", ")?;
         }
+        write!(f, "{item}")?;
     }
+    Ok(())
 }
 
 impl Add<&OwnedValue> for &OwnedValue {
@@ -121,12 +552,8 @@ impl Mul<&OwnedValue> for &OwnedValue {
     fn mul(self, rhs: &OwnedValue) -> Self::Output {
         match (self, rhs) {
             (OwnedValue::Number(lhs), OwnedValue::Number(rhs)) => Ok(OwnedValue::Number(lhs * rhs)),
-            (OwnedValue::String(lhs), OwnedValue::Number(rhs)) => {
-                Ok(OwnedValue::String(lhs.repeat(*rhs as usize)))
-            }
-            (OwnedValue::Number(lhs), OwnedValue::String(rhs)) => {
-                Ok(OwnedValue::String(rhs.repeat(*lhs as usize)))
-            }
+            (OwnedValue::String(lhs), OwnedValue::Number(rhs)) => repeat(lhs, *rhs),
+            (OwnedValue::Number(lhs), OwnedValue::String(rhs)) => repeat(rhs, *lhs),
             _ => Err(ValueError::OperationError(format!(
                 "Cannot multiply strings {self:?} with {rhs:?}"
             ))),
@@ -147,38 +574,38 @@ impl Div<&OwnedValue> for &OwnedValue {
     }
 }
 
-impl Into<Value<'_>> for OwnedValue {
-    fn into(self) -> Value<'static> {
-        Value::Owned(self)
+impl From<OwnedValue> for Value<'_> {
+    fn from(value: OwnedValue) -> Self {
+        Value::Owned(value)
     }
 }
 
-impl<'a> Into<Value<'a>> for &'a OwnedValue {
-    fn into(self) -> Value<'a> {
-        Value::Borrowed(self)
+impl<'a> From<&'a OwnedValue> for Value<'a> {
+    fn from(value: &'a OwnedValue) -> Self {
+        Value::Borrowed(value)
     }
 }
 
-impl Into<Value<'_>> for String {
-    fn into(self) -> Value<'static> {
-        Value::Owned(OwnedValue::String(self))
+impl From<String> for Value<'_> {
+    fn from(value: String) -> Self {
+        Value::Owned(OwnedValue::String(value))
     }
 }
 
-impl Into<Value<'_>> for bool {
-    fn into(self) -> Value<'static> {
-        Value::Owned(OwnedValue::Boolean(self))
+impl From<bool> for Value<'_> {
+    fn from(value: bool) -> Self {
+        Value::Owned(OwnedValue::Boolean(value))
     }
 }
 
-impl Into<Value<'_>> for f64 {
-    fn into(self) -> Value<'static> {
-        Value::Owned(OwnedValue::Number(self))
+impl From<f64> for Value<'_> {
+    fn from(value: f64) -> Self {
+        Value::Owned(OwnedValue::Number(value))
     }
 }
 
-impl Into<Value<'_>> for Vec<OwnedValue> {
-    fn into(self) -> Value<'static> {
-        Value::Owned(OwnedValue::Array(self))
+impl From<Vec<OwnedValue>> for Value<'_> {
+    fn from(value: Vec<OwnedValue>) -> Self {
+        Value::Owned(OwnedValue::Array(value))
     }
 }
@@ -0,0 +1,274 @@
+//! Traversals of the [`Node`] tree.
+//!
+//! Each trait has a single method that is called on every node. Its default implementation hands
+//! the node to the matching `walk` function, which calls the method again on each of the node's
+//! children in the order of its fields. An implementation handles the nodes it cares about and
+//! calls the `walk` function for the rest to keep descending:
+//!
+//! ```
+//! use ramon_templates::{visit::{self, Visitor}, Node, Parser};
+//!
+//! struct Calls(Vec<String>);
+//!
+//! impl<'ast> Visitor<'ast> for Calls {
+//!     fn visit_node(&mut self, node: &'ast Node) {
+//!         if let Node::FunctionCall(identifier, ..) = node {
+//!             self.0.push(identifier.clone());
+//!         }
+//!         visit::walk(self, node);
+//!     }
+//! }
+//!
+//! let template = Parser::parse_input("{{ upper(trim(name)) }}").unwrap();
+//! let mut calls = Calls(Vec::new());
+//! calls.visit_node(&template);
+//! assert_eq!(calls.0, ["upper", "trim"]);
+//! ```
+
+use crate::node::Node;
+
+/// Visits a tree by reference.
+pub trait Visitor<'ast> {
+    fn visit_node(&mut self, node: &'ast Node) {
+        walk(self, node);
+    }
+}
+
+/// Visits a tree by mutable reference, to edit it in place.
+pub trait VisitorMut {
+    fn visit_node_mut(&mut self, node: &mut Node) {
+        walk_mut(self, node);
+    }
+}
+
+/// Consumes a tree and builds a new one, to replace nodes with nodes of a different kind.
+pub trait Fold {
+    fn fold_node(&mut self, node: Node) -> Node {
+        walk_fold(self, node)
+    }
+}
+
+/// Calls [`Visitor::visit_node`] on each child of `node`.
+pub fn walk<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, node: &'ast Node) {
+    match node {
+        Node::Body(nodes) | Node::Array(nodes) | Node::Concat(nodes) => {
+            for node in nodes {
+                visitor.visit_node(node);
+            }
+        }
+        Node::FunctionCall(_, args, named_args) => {
+            for node in args {
+                visitor.visit_node(node);
+            }
+            for (_, node) in named_args {
+                visitor.visit_node(node);
+            }
+        }
+        Node::Test(value, _, args, named_args) => {
+            visitor.visit_node(value);
+            for node in args {
+                visitor.visit_node(node);
+            }
+            for (_, node) in named_args {
+                visitor.visit_node(node);
+            }
+        }
+        Node::Comprehension(_, array, element, filter) => {
+            visitor.visit_node(array);
+            visitor.visit_node(element);
+            if let Some(filter) = filter {
+                visitor.visit_node(filter);
+            }
+        }
+        Node::Index(value, index) | Node::OptionalIndex(value, index) => {
+            visitor.visit_node(value);
+            visitor.visit_node(index);
+        }
+        Node::Slice(value, start, end, step) => {
+            visitor.visit_node(value);
+            for bound in [start, end, step].into_iter().flatten() {
+                visitor.visit_node(bound);
+            }
+        }
+        Node::Operation(lhs, _, rhs) => {
+            visitor.visit_node(lhs);
+            visitor.visit_node(rhs);
+        }
+        Node::IfThenElse(condition, then_node, else_node) => {
+            visitor.visit_node(condition);
+            visitor.visit_node(then_node);
+            if let Some(else_node) = else_node {
+                visitor.visit_node(else_node);
+            }
+        }
+        Node::ForIn(_, array, body, separator, filter) => {
+            visitor.visit_node(array);
+            visitor.visit_node(body);
+            for node in [separator, filter].into_iter().flatten() {
+                visitor.visit_node(node);
+            }
+        }
+        Node::Spread(node)
+        | Node::Attribute(node, _)
+        | Node::OptionalAttribute(node, _)
+        | Node::Not(node)
+        | Node::Negate(node)
+        | Node::PrefixOperation(_, node)
+        | Node::Lambda(_, node) => visitor.visit_node(node),
+        Node::Value(_) | Node::Variable(_) | Node::Break | Node::Continue | Node::Error => {}
+    }
+}
+
+/// Calls [`VisitorMut::visit_node_mut`] on each child of `node`.
+pub fn walk_mut<V: VisitorMut + ?Sized>(visitor: &mut V, node: &mut Node) {
+    match node {
+        Node::Body(nodes) | Node::Array(nodes) | Node::Concat(nodes) => {
+            for node in nodes {
+                visitor.visit_node_mut(node);
+            }
+        }
+        Node::FunctionCall(_, args, named_args) => {
+            for node in args {
+                visitor.visit_node_mut(node);
+            }
+            for (_, node) in named_args {
+                visitor.visit_node_mut(node);
+            }
+        }
+        Node::Test(value, _, args, named_args) => {
+            visitor.visit_node_mut(value);
+            for node in args {
+                visitor.visit_node_mut(node);
+            }
+            for (_, node) in named_args {
+                visitor.visit_node_mut(node);
+            }
+        }
+        Node::Comprehension(_, array, element, filter) => {
+            visitor.visit_node_mut(array);
+            visitor.visit_node_mut(element);
+            if let Some(filter) = filter {
+                visitor.visit_node_mut(filter);
+            }
+        }
+        Node::Index(value, index) | Node::OptionalIndex(value, index) => {
+            visitor.visit_node_mut(value);
+            visitor.visit_node_mut(index);
+        }
+        Node::Slice(value, start, end, step) => {
+            visitor.visit_node_mut(value);
+            for bound in [start, end, step].into_iter().flatten() {
+                visitor.visit_node_mut(bound);
+            }
+        }
+        Node::Operation(lhs, _, rhs) => {
+            visitor.visit_node_mut(lhs);
+            visitor.visit_node_mut(rhs);
+        }
+        Node::IfThenElse(condition, then_node, else_node) => {
+            visitor.visit_node_mut(condition);
+            visitor.visit_node_mut(then_node);
+            if let Some(else_node) = else_node {
+                visitor.visit_node_mut(else_node);
+            }
+        }
+        Node::ForIn(_, array, body, separator, filter) => {
+            visitor.visit_node_mut(array);
+            visitor.visit_node_mut(body);
+            for node in [separator, filter].into_iter().flatten() {
+                visitor.visit_node_mut(node);
+            }
+        }
+        Node::Spread(node)
+        | Node::Attribute(node, _)
+        | Node::OptionalAttribute(node, _)
+        | Node::Not(node)
+        | Node::Negate(node)
+        | Node::PrefixOperation(_, node)
+        | Node::Lambda(_, node) => visitor.visit_node_mut(node),
+        Node::Value(_) | Node::Variable(_) | Node::Break | Node::Continue | Node::Error => {}
+    }
+}
+
+/// Rebuilds `node` from the results of calling [`Fold::fold_node`] on each of its children.
+pub fn walk_fold<F: Fold + ?Sized>(folder: &mut F, node: Node) -> Node {
+    let mut fold = |node: Box<Node>| Box::new(folder.fold_node(*node));
+    match node {
+        Node::Body(nodes) => Node::Body(fold_all(folder, nodes)),
+        Node::Array(nodes) => Node::Array(fold_all(folder, nodes)),
+        Node::Concat(nodes) => Node::Concat(fold_all(folder, nodes)),
+        Node::FunctionCall(identifier, args, named_args) => Node::FunctionCall(
+            identifier,
+            fold_all(folder, args),
+            fold_named(folder, named_args),
+        ),
+        Node::Test(value, test, args, named_args) => {
+            let value = Box::new(folder.fold_node(*value));
+            Node::Test(
+                value,
+                test,
+                fold_all(folder, args),
+                fold_named(folder, named_args),
+            )
+        }
+        Node::Comprehension(identifier, array, element, filter) => {
+            let array = fold(array);
+            let element = fold(element);
+            Node::Comprehension(identifier, array, element, filter.map(fold))
+        }
+        Node::Index(value, index) => {
+            let value = fold(value);
+            Node::Index(value, fold(index))
+        }
+        Node::OptionalIndex(value, index) => {
+            let value = fold(value);
+            Node::OptionalIndex(value, fold(index))
+        }
+        Node::Slice(value, start, end, step) => {
+            let value = fold(value);
+            let start = start.map(&mut fold);
+            let end = end.map(&mut fold);
+            Node::Slice(value, start, end, step.map(fold))
+        }
+        Node::Operation(lhs, operator, rhs) => {
+            let lhs = fold(lhs);
+            Node::Operation(lhs, operator, fold(rhs))
+        }
+        Node::IfThenElse(condition, then_node, else_node) => {
+            let condition = fold(condition);
+            let then_node = fold(then_node);
+            Node::IfThenElse(condition, then_node, else_node.map(fold))
+        }
+        Node::ForIn(identifier, array, body, separator, filter) => {
+            let array = fold(array);
+            let body = fold(body);
+            let separator = separator.map(&mut fold);
+            Node::ForIn(identifier, array, body, separator, filter.map(fold))
+        }
+        Node::Spread(node) => Node::Spread(fold(node)),
+        Node::Attribute(object, attribute) => Node::Attribute(fold(object), attribute),
+        Node::OptionalAttribute(object, attribute) => {
+            Node::OptionalAttribute(fold(object), attribute)
+        }
+        Node::Not(node) => Node::Not(fold(node)),
+        Node::Negate(node) => Node::Negate(fold(node)),
+        Node::PrefixOperation(symbol, node) => Node::PrefixOperation(symbol, fold(node)),
+        Node::Lambda(params, body) => Node::Lambda(params, fold(body)),
+        node
+        @ (Node::Value(_) | Node::Variable(_) | Node::Break | Node::Continue | Node::Error) => node,
+    }
+}
+
+fn fold_all<F: Fold + ?Sized>(folder: &mut F, nodes: Vec<Node>) -> Vec<Node> {
+    nodes
+        .into_iter()
+        .map(|node| folder.fold_node(node))
+        .collect()
+}
+
+fn fold_named<F: Fold + ?Sized>(folder: &mut F, nodes: Vec<(String, Node)>) -> Vec<(String, Node)> {
+    nodes
+        .into_iter()
+        .map(|(name, node)| (name, folder.fold_node(node)))
+        .collect()
+}
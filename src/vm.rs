@@ -0,0 +1,506 @@
+use crate::{
+    arguments::Arguments,
+    compile::{Instruction, Program},
+    environment::Environment,
+    error::ValueError,
+    node::{self, LambdaFn},
+    value::{check_range_len, Lambda, OwnedValue, Range},
+    variables::Variables,
+    Value,
+};
+
+impl Program {
+    /// Renders the program, like [`Node::evaluate`](crate::Node::evaluate) renders the tree it
+    /// was compiled from.
+    pub fn evaluate<V: Variables>(
+        &self,
+        variables: &V,
+        environment: &Environment,
+    ) -> Result<String, ValueError> {
+        let locals = vec![Value::Owned(OwnedValue::Null); self.slots];
+        Machine::new(self, variables, environment, locals)
+            .run(0)?
+            .unwrap_string()
+    }
+}
+
+/// The items of an array or range that is being iterated over.
+pub(crate) enum Items<'a> {
+    Borrowed(std::slice::Iter<'a, OwnedValue>),
+    Owned(std::vec::IntoIter<OwnedValue>),
+    Range(Range, std::ops::Range<usize>),
+}
+
+impl<'a> Items<'a> {
+    pub(crate) fn new(value: Value<'a>) -> Result<Self, ValueError> {
+        match value {
+            Value::Borrowed(OwnedValue::Array(array)) => Ok(Items::Borrowed(array.iter())),
+            Value::Owned(OwnedValue::Array(array)) => Ok(Items::Owned(array.into_iter())),
+            Value::Borrowed(OwnedValue::Range(range)) => {
+                Ok(Items::Range(range.clone(), 0..range.len()))
+            }
+            Value::Owned(OwnedValue::Range(range)) => {
+                let len = range.len();
+                Ok(Items::Range(range, 0..len))
+            }
+            value => Err(ValueError::IterateError(value.inner().clone())),
+        }
+    }
+
+    /// The items of an array or range that is spread into an array, which is only as long as a
+    /// range may be.
+    pub(crate) fn spread(value: Value<'a>) -> Result<Self, ValueError> {
+        if let OwnedValue::Range(range) = value.inner() {
+            check_range_len(range.len())?;
+        }
+        Self::new(value)
+    }
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = Value<'a>;
+
+    fn next(&mut self) -> Option<Value<'a>> {
+        match self {
+            Items::Borrowed(items) => items.next().map(Value::Borrowed),
+            Items::Owned(items) => items.next().map(Value::Owned),
+            Items::Range(range, indices) => indices
+                .next()
+                .map(|i| Value::Owned(OwnedValue::Number(range.nth(i)))),
+        }
+    }
+}
+
+/// A running `for` loop. The lengths are those of the machine's stacks when the loop started,
+/// which `break` and `continue` unwind to.
+struct Loop {
+    stack: usize,
+    buffers: usize,
+    items: usize,
+    separator: String,
+    output: String,
+    is_first: bool,
+    next: usize,
+    end: usize,
+}
+
+impl Loop {
+    /// Adds the output of an item. An item that was skipped before it rendered anything doesn't
+    /// get a separator.
+    fn add(&mut self, output: &str, interrupted: bool) {
+        if interrupted && output.is_empty() {
+            return;
+        }
+        if !self.is_first {
+            self.output += &self.separator;
+        }
+        self.output += output;
+        self.is_first = false;
+    }
+}
+
+/// Runs the instructions of one function: the template, or a lambda's body.
+struct Machine<'a, V> {
+    program: &'a Program,
+    variables: &'a V,
+    environment: &'a Environment,
+    locals: Vec<Value<'a>>,
+    stack: Vec<Value<'a>>,
+    buffers: Vec<String>,
+    items: Vec<Items<'a>>,
+    loops: Vec<Loop>,
+}
+
+impl<'a, V: Variables> Machine<'a, V> {
+    fn new(
+        program: &'a Program,
+        variables: &'a V,
+        environment: &'a Environment,
+        locals: Vec<Value<'a>>,
+    ) -> Self {
+        Self {
+            program,
+            variables,
+            environment,
+            locals,
+            stack: Vec::new(),
+            buffers: Vec::new(),
+            items: Vec::new(),
+            loops: Vec::new(),
+        }
+    }
+
+    fn pop(&mut self) -> Value<'a> {
+        self.stack.pop().expect("the stack is empty")
+    }
+
+    fn top(&mut self) -> &mut Value<'a> {
+        self.stack.last_mut().expect("the stack is empty")
+    }
+
+    fn buffer(&mut self) -> &mut String {
+        self.buffers.last_mut().expect("there is no output")
+    }
+
+    /// Runs from `pc` until [`Instruction::Return`] and returns the value on top of the stack.
+    fn run(mut self, mut pc: usize) -> Result<Value<'a>, ValueError> {
+        let program = self.program;
+        loop {
+            let instruction = program.instructions[pc];
+            pc += 1;
+            match instruction {
+                Instruction::Constant(constant) => {
+                    self.stack
+                        .push(Value::Borrowed(&program.constants[constant]));
+                }
+                Instruction::Null => self.stack.push(Value::Owned(OwnedValue::Null)),
+                Instruction::Local(slot) => self.stack.push(self.locals[slot].clone()),
+                Instruction::Global(name) => {
+                    let name = &program.names[name];
+                    let value = self
+                        .variables
+                        .get(name)
+                        .ok_or_else(|| ValueError::UndefinedVariable(name.clone()))?;
+                    self.stack.push(Value::Borrowed(value));
+                }
+                Instruction::GlobalDefined(name, missing) => {
+                    match self.variables.get(&program.names[name]) {
+                        Some(value) => self.stack.push(Value::Borrowed(value)),
+                        None => pc = missing,
+                    }
+                }
+                Instruction::Text(constant) => {
+                    let OwnedValue::String(text) = &program.constants[constant] else {
+                        unreachable!("text is always a string");
+                    };
+                    self.buffer().push_str(text);
+                }
+                Instruction::Write => {
+                    let value = self.pop();
+                    match value.inner() {
+                        OwnedValue::String(string) => self.buffer().push_str(string),
+                        value => *self.buffer() += &value.to_text()?,
+                    }
+                }
+                Instruction::PushBuffer => self.buffers.push(String::new()),
+                Instruction::PopBuffer => {
+                    let buffer = self.buffers.pop().expect("there is no output");
+                    self.stack.push(buffer.into());
+                }
+                Instruction::NewString => self.stack.push(String::new().into()),
+                Instruction::Append => {
+                    let value = self.pop();
+                    let Value::Owned(OwnedValue::String(string)) = self.top() else {
+                        unreachable!("appending to a string that isn't being built");
+                    };
+                    match value.inner() {
+                        OwnedValue::String(part) => string.push_str(part),
+                        value => *string += &value.to_text()?,
+                    }
+                }
+                Instruction::NewArray => self.stack.push(Vec::new().into()),
+                Instruction::Push => {
+                    let value = self.pop().to_owned_value();
+                    self.array().push(value);
+                }
+                Instruction::Extend => {
+                    let items = Items::spread(self.pop())?;
+                    let array = self.array();
+                    for item in items {
+                        array.push(item.to_owned_value());
+                    }
+                }
+                Instruction::Jump(target) => pc = target,
+                Instruction::JumpIfFalse(target) => {
+                    if !self.pop().inner().is_truthy() {
+                        pc = target;
+                    }
+                }
+                Instruction::JumpIfNull(target) => {
+                    if *self.top().inner() == OwnedValue::Null {
+                        self.stack.pop();
+                        pc = target;
+                    }
+                }
+                Instruction::ShortCircuit(is_or, end) => {
+                    let lhs = self.pop().inner().is_truthy();
+                    if lhs == is_or {
+                        self.stack.push(lhs.into());
+                        pc = end;
+                    }
+                }
+                Instruction::Truthy => {
+                    let value = self.pop().inner().is_truthy();
+                    self.stack.push(value.into());
+                }
+                Instruction::Operation(op) => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    let value = node::operate(
+                        lhs.inner(),
+                        &program.operators[op],
+                        rhs.inner(),
+                        self.environment,
+                    )?;
+                    self.stack.push(value.into());
+                }
+                Instruction::Not => {
+                    let value = !self.pop().inner().is_truthy();
+                    self.stack.push(value.into());
+                }
+                Instruction::Negate => {
+                    let value = -self.pop().unwrap_f64()?;
+                    self.stack.push(value.into());
+                }
+                Instruction::CheckPrefix(symbol) | Instruction::Prefix(symbol) => {
+                    let symbol = &program.names[symbol];
+                    let operator = self
+                        .environment
+                        .prefix_operator(symbol)
+                        .ok_or_else(|| ValueError::UndefinedOperator(symbol.clone()))?;
+                    if let Instruction::Prefix(_) = instruction {
+                        let value = (operator.evaluate)(self.pop().inner())?;
+                        self.stack.push(value.into());
+                    }
+                }
+                Instruction::Index => {
+                    let index = self.pop();
+                    let item = match self.pop() {
+                        Value::Borrowed(value) => value.index(index.inner())?,
+                        value => value.inner().index(index.inner())?.to_owned_value().into(),
+                    };
+                    self.stack.push(item);
+                }
+                Instruction::IndexDefined(missing) => {
+                    let index = self.pop();
+                    let item = match self.pop() {
+                        Value::Borrowed(value) => value.get_index(index.inner())?,
+                        value => value
+                            .inner()
+                            .get_index(index.inner())?
+                            .map(|item| item.to_owned_value().into()),
+                    };
+                    match item {
+                        Some(item) => self.stack.push(item),
+                        None => pc = missing,
+                    }
+                }
+                Instruction::Attribute(attribute) => {
+                    let attribute = &program.names[attribute];
+                    let value = self.attribute(attribute)?.ok_or_else(|| {
+                        ValueError::OperationError(format!("Missing attribute {attribute:?}"))
+                    })?;
+                    self.stack.push(value);
+                }
+                Instruction::AttributeDefined(attribute, missing) => {
+                    match self.attribute(&program.names[attribute])? {
+                        Some(value) => self.stack.push(value),
+                        None => pc = missing,
+                    }
+                }
+                Instruction::Bound => {
+                    let bound = self.pop();
+                    if *bound.inner() != OwnedValue::Null {
+                        let bound = bound.unwrap_f64()?;
+                        self.stack.push(bound.into());
+                    } else {
+                        self.stack.push(bound);
+                    }
+                }
+                Instruction::Slice(start, end, step) => {
+                    let mut bounds = [None; 3];
+                    for (bound, present) in bounds.iter_mut().zip([start, end, step]).rev() {
+                        if present {
+                            *bound = match self.pop().inner() {
+                                OwnedValue::Number(number) => Some(*number),
+                                _ => None,
+                            };
+                        }
+                    }
+                    let value = self.pop();
+                    let slice = value.inner().slice(bounds[0], bounds[1], bounds[2])?;
+                    self.stack.push(slice.into());
+                }
+                Instruction::Call(call) => {
+                    let value = self.call(call)?;
+                    self.stack.push(value.into());
+                }
+                Instruction::Test(call) => {
+                    let call = &program.calls[call];
+                    let args = self.arguments(call.args.len(), &call.named);
+                    let defined = self.pop().inner().is_truthy();
+                    let value = self.pop();
+                    let value = defined.then_some(value);
+                    let passed = node::run_test(&call.name, value, args, self.environment)?;
+                    self.stack.push(passed.into());
+                }
+                Instruction::Iterate(collect) => {
+                    let items = match collect {
+                        true => Items::spread(self.pop())?,
+                        false => Items::new(self.pop())?,
+                    };
+                    self.items.push(items);
+                }
+                Instruction::Next(slot, end) => {
+                    match self
+                        .items
+                        .last_mut()
+                        .expect("nothing is being iterated over")
+                        .next()
+                    {
+                        Some(item) => self.locals[slot] = item,
+                        None => pc = end,
+                    }
+                }
+                Instruction::EndIterate => {
+                    self.items.pop();
+                }
+                Instruction::EnterLoop(end) => {
+                    self.loops.push(Loop {
+                        stack: self.stack.len(),
+                        buffers: self.buffers.len(),
+                        items: self.items.len(),
+                        separator: String::new(),
+                        output: String::new(),
+                        is_first: true,
+                        next: pc,
+                        end,
+                    });
+                }
+                Instruction::Separator => {
+                    let separator = self.pop();
+                    let current = self.loops.last_mut().expect("there is no loop");
+                    current.separator.clear();
+                    match separator.inner() {
+                        OwnedValue::String(string) => current.separator += string,
+                        value => current.separator += &value.to_text()?,
+                    }
+                }
+                Instruction::EndIteration => {
+                    let output = self.buffers.pop().expect("there is no output");
+                    let current = self.loops.last_mut().expect("there is no loop");
+                    current.add(&output, false);
+                    pc = current.next;
+                }
+                Instruction::ExitLoop => {
+                    let current = self.loops.pop().expect("there is no loop");
+                    self.items.pop();
+                    self.stack.push(current.output.into());
+                }
+                Instruction::Break(target) | Instruction::Continue(target) => {
+                    self.loops.truncate(target + 1);
+                    let current = &mut self.loops[target];
+                    // Everything rendered since the item started is kept, like the tree-walker
+                    // carries it out of the bodies that it unwinds through.
+                    let output = self.buffers.drain(current.buffers..).collect::<String>();
+                    self.stack.truncate(current.stack);
+                    self.items.truncate(current.items);
+                    current.add(&output, true);
+                    pc = match instruction {
+                        Instruction::Break(_) => current.end,
+                        _ => current.next,
+                    };
+                }
+                Instruction::Depth(depth) => {
+                    if depth > self.environment.max_depth() {
+                        return Err(ValueError::NestingTooDeep(self.environment.max_depth()));
+                    }
+                }
+                Instruction::Fail(message) => {
+                    return Err(ValueError::OperationError(message.into()));
+                }
+                Instruction::Return => return Ok(self.pop()),
+            }
+        }
+    }
+
+    /// The array being built on top of the stack.
+    fn array(&mut self) -> &mut Vec<OwnedValue> {
+        match self.top() {
+            Value::Owned(OwnedValue::Array(array)) => array,
+            _ => unreachable!("pushing to an array that isn't being built"),
+        }
+    }
+
+    /// Pops an object and returns its attribute, if it has it.
+    fn attribute(&mut self, attribute: &str) -> Result<Option<Value<'a>>, ValueError> {
+        let value = match self.pop() {
+            Value::Borrowed(object) => object.get_attribute(attribute)?.map(Value::Borrowed),
+            object => object
+                .inner()
+                .get_attribute(attribute)?
+                .map(|value| value.clone().into()),
+        };
+        Ok(value)
+    }
+
+    /// Pops the arguments of a test, where the last of them are named.
+    fn arguments(&mut self, count: usize, names: &[String]) -> Arguments<'a> {
+        let mut positional = self.stack.split_off(self.stack.len() - count);
+        let named = names
+            .iter()
+            .cloned()
+            .zip(positional.split_off(count - names.len()))
+            .collect();
+        Arguments { positional, named }
+    }
+
+    fn call(&mut self, call: usize) -> Result<OwnedValue, ValueError> {
+        let program = self.program;
+        let call = &program.calls[call];
+        let evaluated = call.args.iter().filter(|lambda| lambda.is_none()).count();
+        let mut values = self
+            .stack
+            .split_off(self.stack.len() - evaluated)
+            .into_iter();
+        // Lambdas borrow the current scope, so they are built here to outlive the arguments that
+        // refer to them.
+        let lambdas = call
+            .args
+            .iter()
+            .map(|lambda| lambda.map(|lambda| self.lambda(lambda)))
+            .collect::<Vec<Option<Box<LambdaFn>>>>();
+        let mut positional = lambdas
+            .iter()
+            .map(|lambda| match lambda {
+                Some(function) => Value::Lambda(Lambda {
+                    function: &**function,
+                }),
+                None => values.next().expect("an argument is missing"),
+            })
+            .collect::<Vec<Value>>();
+        let named = call
+            .named
+            .iter()
+            .cloned()
+            .zip(positional.split_off(call.args.len() - call.named.len()))
+            .collect();
+        node::call_function(
+            &call.name,
+            Arguments { positional, named },
+            self.environment,
+        )
+    }
+
+    /// Turns a lambda into a closure that runs its body with a copy of the current locals.
+    fn lambda(&self, lambda: usize) -> Box<LambdaFn<'a>> {
+        let (program, variables, environment) = (self.program, self.variables, self.environment);
+        let code = &program.lambdas[lambda];
+        let locals = self.locals.clone();
+        Box::new(move |args: Vec<Value>| {
+            if args.len() != code.params.len() {
+                return Err(ValueError::OperationError(format!(
+                    "Lambda takes {} arguments, got {}",
+                    code.params.len(),
+                    args.len()
+                )));
+            }
+            let mut locals: Vec<Value> = locals.clone();
+            for (&slot, arg) in code.params.iter().zip(args) {
+                locals[slot] = arg;
+            }
+            let value = Machine::new(program, variables, environment, locals).run(code.start)?;
+            Ok(value.to_owned_value())
+        })
+    }
+}
@@ -1,24 +1,51 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, io,
+};
 
-use ramon_templates::{OwnedValue, Parser, Value};
+use ramon_templates::{
+    visit, DecodeError, Environment, Fold, Lexer, Node, Operator, OwnedValue, ParseError, Parser,
+    PrintError, Printer, ValueError, VisitorMut,
+};
+
+#[path = "../codegen-tests/src/environment.rs"]
+mod environment;
+
+use environment::environment;
 
 const _A: f64 = 4.0;
 const A: f64 = 8.2;
 const B: f64 = 16.0;
 
-fn eval<'a>(input: &'a str) -> String {
-    let template = Parser::parse_input(input).unwrap();
+fn eval(input: &str) -> String {
+    let environment = environment();
+    let template = Parser::parse_input_with(input, &environment).unwrap();
     let mut vars = HashMap::new();
     vars.insert("_a".into(), OwnedValue::Number(_A));
     vars.insert("a".into(), OwnedValue::Number(A));
     vars.insert("b".into(), OwnedValue::Number(B));
     vars.insert("world".into(), OwnedValue::String("world".into()));
+    vars.insert(
+        "host".into(),
+        OwnedValue::Object(BTreeMap::from([
+            ("name".into(), OwnedValue::String("web".into())),
+            ("down".into(), OwnedValue::Boolean(true)),
+        ])),
+    );
+    let out = template.evaluate(&vars, &environment).unwrap();
+    // The tree-walker is the reference for the compiled program and for streaming.
+    let program = template.compile();
+    assert_eq!(
+        program.evaluate(&vars, &environment).unwrap(),
+        out,
+        "{input}"
+    );
+    let mut streamed = String::new();
     template
-        .evaluate(
-            &vars,
-            &HashMap::<String, Box<dyn Fn(Vec<Value>) -> OwnedValue>>::new(),
-        )
-        .unwrap()
+        .render_to(&vars, &environment, &mut streamed)
+        .unwrap();
+    assert_eq!(streamed, out, "{input}");
+    out
 }
 
 #[test]
@@ -78,16 +105,1070 @@ fn logic() {
     assert_eq!(out, "else");
 }
 
+#[test]
+fn end_tags() {
+    let out = eval("{{ if a }}a{{ end }}{{ for n in [1, 2] }}{{ n }}{{ end }}");
+    assert_eq!(out, "a12");
+
+    let out = eval("{{ for n in [1, 2] }}{{ if n == 2 }}{{ n }}{{ endif }}{{ endfor }}");
+    assert_eq!(out, "2");
+
+    let out = eval("{{ if b }}{{ b }}{{ else }}{{ b }}{{ endif }}");
+    assert_eq!(out, "16");
+
+    // `end` is still a variable outside of a lone tag.
+    let template = Parser::parse_input("{{ end + 1 }}").unwrap();
+    let vars = HashMap::from([("end".to_owned(), OwnedValue::Number(1.0))]);
+    assert_eq!(template.evaluate(&vars, &Environment::new()).unwrap(), "2");
+
+    let err =
+        Parser::parse_input("{{ for x in xs }}\n{{ if x }}\n{{ x }}\n{{ /for }}").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`/for` does not close the `if` opened at line 2"
+    );
+    let err = Parser::parse_input("{{ if x }}\n{{ elif y }}{{ endfor }}").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`endfor` does not close the `if` opened at line 1"
+    );
+    let err = Parser::parse_input("\n\n{{ for x in xs }}{{ x }}").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The `for` opened at line 3 is never closed"
+    );
+    let err = Parser::parse_input("{{ x }}{{ end }}").unwrap_err();
+    assert_eq!(err.to_string(), "`end` does not close any block");
+}
+
 #[test]
 fn for_loops() {
     let out = eval(
         "{{ for arr in [[1, 2], [3, 4,],] ' ' }}{{ for n in arr ',' }}{{ n }}{{ /for }}{{ /for }}",
     );
     assert_eq!(out, "1,2 3,4");
+
+    let out = eval("{{ for n in [1, 2] ', ' + world + ' ' }}{{ n }}{{ /for }}");
+    assert_eq!(out, "1, world 2");
+
+    let out = eval("{{ for n in [1, 2, 3] 0 }}{{ n }}{{ /for }}");
+    assert_eq!(out, "10203");
+
+    // The separator is evaluated with the item that comes after it.
+    let out = eval("{{ for n in [1, 2, 3] if n != 2 f' <{n}> ' }}{{ n }}{{ /for }}");
+    assert_eq!(out, "1 <3> 3");
+}
+
+#[test]
+fn loop_control() {
+    let out =
+        eval("{{ for n in [1, 2, 3, 4] ',' }}{{ if n == 3 }}{{ break }}{{ /if }}{{ n }}{{ /for }}");
+    assert_eq!(out, "1,2");
+
+    let out =
+        eval("{{ for n in [1, 2, 3] ',' }}{{ if n == 2 }}{{ continue }}{{ /if }}{{ n }}{{ /for }}");
+    assert_eq!(out, "1,3");
+
+    let out =
+        eval("{{ for n in [1, 2, 3] ',' }}{{ n }}{{ if n == 2 }}{{ break }}{{ /if }}!{{ /for }}");
+    assert_eq!(out, "1!,2");
+
+    let out = eval("{{ for n in [1, 2, 3, 4] if n != 2 && n != 4 ', ' }}{{ n }}{{ /for }}");
+    assert_eq!(out, "1, 3");
+
+    assert!(Parser::parse_input("{{ if 1 }}{{ break }}{{ /if }}").is_err());
+
+    // A `for` that fails to parse doesn't leave `break` allowed after it.
+    let mut lexer = Lexer::new("{{ for x in xs }}{{ 1 + }}{{ /for }}{{ break }}");
+    let mut parser = Parser::new(&mut lexer);
+    let results: Vec<_> = std::iter::from_fn(|| parser.next_node().transpose()).collect();
+    assert!(results
+        .iter()
+        .any(|result| matches!(result, Err(ParseError::OutsideLoop(_)))));
+}
+
+#[test]
+fn ranges() {
+    let out = eval("{{ for i in 0..3 ',' }}{{ i }}{{ /for }}");
+    assert_eq!(out, "0,1,2");
+
+    let out = eval("{{ for i in 1..=b / 4 ',' }}{{ i }}{{ /for }}");
+    assert_eq!(out, "1,2,3,4");
+
+    let out = eval("{{ for i in range(10, 0, -3) ',' }}{{ i }}{{ /for }}");
+    assert_eq!(out, "10,7,4,1");
+
+    let out = eval("{{ [1, 2, 3, 4][1..3] }} {{ [1, 2, 3][-1] }} {{ world[1..=3] }}");
+    assert_eq!(out, "2, 3 3 orl");
+
+    // Ranges have no open start, so `0..=-1` is how a template writes `..=-1`.
+    let out = eval("{{ [1, 2, 3, 4][-3..=-1] }} {{ [1, 2, 3, 4][0..=-1] }} {{ world[-2..=-1] }}");
+    assert_eq!(out, "2, 3, 4 1, 2, 3, 4 ld");
+
+    let out = eval("[{{ [1, 2, 3][0..=-4] }}] [{{ [1, 2, 3][-9..=-5] }}] {{ (0..5)[-2..=-1] }}");
+    assert_eq!(out, "[] [] 3, 4");
+
+    let out = eval("{{ (0..1000000000)[5] }}");
+    assert_eq!(out, "5");
+}
+
+#[test]
+fn named_arguments() {
+    let out = eval("[{{ pad(world) }}]");
+    assert_eq!(out, "[     world]");
+
+    let out = eval("[{{ pad(world, fill = '-', width = 2 * 4) }}]");
+    assert_eq!(out, "[---world]");
+
+    assert!(Parser::parse_input("{{ pad(width = 1, world) }}").is_err());
+    assert!(Parser::parse_input("{{ pad(world, width = 1, width = 2) }}").is_err());
+}
+
+#[test]
+fn lambdas() {
+    let out = eval("{{ map([1, 2, 3], x => x * a) }}");
+    assert_eq!(out, format!("{}, {}, {}", A, 2.0 * A, 3.0 * A));
+
+    let out = eval("{{ for n in [1, 2] }}{{ filter([1, 2, 3], x => x != n) }};{{ /for }}");
+    assert_eq!(out, "2, 3;1, 3;");
+
+    let out = eval("{{ map([[1, 2], [3]], xs => map(xs, x => x + 1)) }}");
+    assert_eq!(out, "2, 3, 4");
+
+    let out = eval("{{ reduce([1, 2, 3], 0, (sum, x) => sum + x) }} {{ map([1], (x) => (x)) }}");
+    assert_eq!(out, "6 1");
+
+    let out = eval("{{ (a) + (b) }}");
+    assert_eq!(out, (A + B).to_string());
+
+    assert!(Parser::parse_input("{{ x => x }}")
+        .unwrap()
+        .evaluate(&HashMap::<String, OwnedValue>::new(), &Environment::new())
+        .is_err());
+
+    // Errors in a lambda are passed on by the function that called it.
+    let environment = environment();
+    let vars = HashMap::<String, OwnedValue>::new();
+    for input in [
+        "{{ map([1, 2], x => x.missing) }}",
+        "{{ reduce([1], 0, (sum, x) => sum + undefined) }}",
+        "{{ map(1, x => x) }}",
+    ] {
+        let template = Parser::parse_input_with(input, &environment).unwrap();
+        let err = template.evaluate(&vars, &environment).unwrap_err();
+        let compiled = template.compile().evaluate(&vars, &environment);
+        assert_eq!(compiled.unwrap_err().to_string(), err.to_string());
+        let streamed = template.render_to(&vars, &environment, &mut String::new());
+        assert_eq!(streamed.unwrap_err().to_string(), err.to_string());
+    }
+}
+
+#[test]
+fn membership() {
+    let out = eval("{{ 2 in [1, 2] }} {{ 3 in [1, 2] }} {{ 3 not in [1, 2] }}");
+    assert_eq!(out, "true false true");
+
+    let out = eval("{{ 'orl' in world }} {{ 'name' in host }} {{ 'port' not in host }}");
+    assert_eq!(out, "true true true");
+
+    let out = eval("{{ 4 in 0..10 }} {{ 4 in range(1, 10, 2) }} {{ a in 0..10 }}");
+    assert_eq!(out, "true false false");
+
+    let out = eval("{{ for n in 0..6 if n in [1, 3] || n == 5 ',' }}{{ n }}{{ /for }}");
+    assert_eq!(out, "1,3,5");
+
+    let out = eval("{{ if 1 + 1 in [2] && !(1 in []) }}yes{{ /if }}");
+    assert_eq!(out, "yes");
+}
+
+#[test]
+fn optional_access() {
+    let out = eval("{{ host.name }} {{ host?.name }} {{ missing?.name }}!");
+    assert_eq!(out, "web web !");
+
+    let out = eval("{{ missing ?? 'default' }} {{ host.port ?? 80 }} {{ host.name ?? 'x' }}");
+    assert_eq!(out, "default 80 web");
+
+    let out = eval("{{ [1, 2]?[5] ?? 'none' }} {{ missing?[0] ?? null ?? 3 }}");
+    assert_eq!(out, "none 3");
+
+    let out = eval("{{ if missing?.down }}down{{ else }}up{{ /if }}");
+    assert_eq!(out, "up");
+
+    let template = Parser::parse_input("{{ missing.name }}").unwrap();
+    assert!(template
+        .evaluate(&HashMap::<String, OwnedValue>::new(), &Environment::new())
+        .is_err());
+}
+
+#[test]
+fn tests() {
+    let out = eval("{{ a is defined }} {{ missing is defined }} {{ missing.x is not defined }}");
+    assert_eq!(out, "true false true");
+
+    let out =
+        eval("{{ world is string }} {{ a is number }} {{ [] is array }} {{ host is object }}");
+    assert_eq!(out, "true true true true");
+
+    let out = eval("{{ [] is empty }} {{ world is not empty }} {{ null is null }}");
+    assert_eq!(out, "true true true");
+
+    let out = eval("{{ 9 is divisibleby(3) }} {{ b is odd }} {{ 7 is prime }} {{ 8 is prime }}");
+    assert_eq!(out, "true false true false");
+
+    let out = eval("{{ for n in 0..10 if n is prime ',' }}{{ n }}{{ /for }}");
+    assert_eq!(out, "2,3,5,7");
+
+    let out = eval("{{ if missing is defined && missing }}yes{{ else }}no{{ /if }}");
+    assert_eq!(out, "no");
+
+    // Errors from a test fail the render, like those of the builtin tests.
+    let environment = environment();
+    let vars = HashMap::<String, OwnedValue>::new();
+    for input in ["{{ 'seven' is prime }}", "{{ 'seven' is odd }}"] {
+        let template = Parser::parse_input_with(input, &environment).unwrap();
+        let err = template.evaluate(&vars, &environment).unwrap_err();
+        let compiled = template.compile().evaluate(&vars, &environment);
+        assert_eq!(compiled.unwrap_err().to_string(), err.to_string());
+        let streamed = template.render_to(&vars, &environment, &mut String::new());
+        assert_eq!(streamed.unwrap_err().to_string(), err.to_string());
+    }
+}
+
+#[test]
+fn slices() {
+    let out = eval("{{ [1, 2, 3, 4][1:3] }};{{ [1, 2, 3, 4][:2] }};{{ [1, 2, 3, 4][::-2] }}");
+    assert_eq!(out, "2, 3;1, 2;4, 2");
+
+    let out = eval("{{ 'deadbeefcafe'[-4:] }} {{ 'héllo'[1:3] }} {{ world[::2] }}");
+    assert_eq!(out, "cafe él wrd");
+
+    let out = eval("{{ [1, 2][-10:10] }};{{ [1, 2][5:] }};{{ (0..10)[7:] }}");
+    assert_eq!(out, "1, 2;;7, 8, 9");
+
+    assert!(Parser::parse_input("{{ [1][] }}").is_err());
+}
+
+#[test]
+fn interpolated_strings() {
+    let out = eval("{{ f\"hello {world}: {a * 2}\" }}");
+    assert_eq!(out, format!("hello world: {}", A * 2.0));
+
+    let out = eval("{{ pad(f'{host.name}!', width = 6) }}");
+    assert_eq!(out, "  web!");
+
+    let out = eval("{{ for n in [1, 2, 3] f' {world[0]} ' }}{{ n }}{{ /for }}");
+    assert_eq!(out, "1 w 2 w 3");
+
+    let out = eval("{{ f'{{{ [1][0] ?? 2 }}}' }}");
+    assert_eq!(out, "{1}");
+
+    let out = eval("{{ f'{\"}\"}' + f'' }}");
+    assert_eq!(out, "}");
+
+    assert!(Parser::parse_input("{{ f'{}' }}").is_err());
+    assert!(Parser::parse_input("{{ f'{a b}' }}").is_err());
 }
 
 #[test]
 fn arrays() {
     let out = eval("{{ [1, 2] + [3, 4] }}");
     assert_eq!(out, "1, 2, 3, 4");
+
+    let out = eval("{{ [0, ...[1, 2], ...3..5, 5] }}");
+    assert_eq!(out, "0, 1, 2, 3, 4, 5");
+
+    let out = eval("{{ [n * 2 for n in 0..10 if n is odd] }}");
+    assert_eq!(out, "2, 6, 10, 14, 18");
+
+    let out = eval("{{ [x.name for x in [host, host] if x.down] }}");
+    assert_eq!(out, "web, web");
+
+    let out = eval("{{ for xs in [[n for n in 0..i] for i in 1..4] ';' }}{{ xs }}{{ /for }}");
+    assert_eq!(out, "0;0, 1;0, 1, 2");
+}
+
+#[test]
+fn long_ranges() {
+    let out = eval(
+        "{{ (0..1000000000000)[:3] }};{{ (0..1000000000000)[-1] }};{{ 7 in 0..1000000000000 }}",
+    );
+    assert_eq!(out, "0, 1, 2;999999999999;true");
+
+    let out =
+        eval("{{ for i in 0..1000000000000 }}{{ if i == 3 }}{{ break }}{{ /if }}{{ i }}{{ /for }}");
+    assert_eq!(out, "012");
+
+    // Ranges are lazy, but slicing, spreading, writing, or collecting one in a comprehension turns
+    // it into all of its items.
+    let vars = HashMap::<String, OwnedValue>::new();
+    let environment = Environment::new();
+    for input in [
+        "{{ (0..1000000000000)[:] }}",
+        "{{ (0..1000000000000)[0..1000000000000] }}",
+        "{{ [...0..1000000000000] }}",
+        "{{ 0..1000000000000000 }}",
+        "{{ [0..1000000000000000] }}",
+        "{{ for i in [1] 0..1000000000000000 }}{{ i }}{{ /for }}",
+        "{{ [x for x in 0..1000000000000000] }}",
+        "{{ [x for x in 0..1000000000000000 if false] }}",
+    ] {
+        let template = Parser::parse_input(input).unwrap();
+        let err = template.evaluate(&vars, &environment).unwrap_err();
+        assert!(
+            matches!(err, ValueError::OperationError(_)),
+            "{input}: {err}"
+        );
+        let compiled = template.compile().evaluate(&vars, &environment);
+        assert_eq!(
+            compiled.unwrap_err().to_string(),
+            err.to_string(),
+            "{input}"
+        );
+        let streamed = template.render_to(&vars, &environment, &mut String::new());
+        assert_eq!(
+            streamed.unwrap_err().to_string(),
+            err.to_string(),
+            "{input}"
+        );
+        let optimized = Parser::parse_input(input).unwrap().optimize();
+        let optimized = optimized.evaluate(&vars, &environment);
+        assert_eq!(
+            optimized.unwrap_err().to_string(),
+            err.to_string(),
+            "{input}"
+        );
+    }
+}
+
+#[test]
+fn precedence() {
+    let out = eval("{{ 1 + 2 * 3 - 4 / 2 }}");
+    assert_eq!(out, "5");
+
+    let out = eval("{{ -1 + 2 == 1 && !host.down || a == a }}");
+    assert_eq!(out, "true");
+
+    let out = eval("{{ 0..2 + 1 }}");
+    assert_eq!(out, "0, 1, 2");
+
+    assert!(Parser::parse_input("{{ 0..1..2 }}").is_err());
+}
+
+#[test]
+fn custom_operators() {
+    let out = eval("{{ 2 ** 3 ** 2 }}");
+    assert_eq!(out, "512");
+
+    let out = eval("{{ -2 ** 2 }}");
+    assert_eq!(out, "-4");
+
+    let out = eval("{{ 2 * 3 ** 2 }}");
+    assert_eq!(out, "18");
+
+    let out = eval("{{ 7 mod 4 * 2 }}");
+    assert_eq!(out, "6");
+
+    let out = eval("{{ 1 + 10 mod 4 }}");
+    assert_eq!(out, "3");
+
+    let out = eval("{{ ~world[1:] }}");
+    assert_eq!(out, "dlro");
+
+    let out = eval("{{ f'{~world}' }}");
+    assert_eq!(out, "dlrow");
+
+    let out = eval("{{ [n ** 2 for n in 1..4] }}");
+    assert_eq!(out, "1, 4, 9");
+
+    // Without the environment, `**` and `mod` are not operators.
+    assert!(Parser::parse_input("{{ 2 ** 2 }}").is_err());
+    assert!(Parser::parse_input("{{ 7 mod 4 }}").is_err());
+}
+
+#[test]
+fn nesting_limits() {
+    let too_deep = [
+        format!("{{{{ {}1{} }}}}", "(".repeat(100_000), ")".repeat(100_000)),
+        format!("{{{{ {}1{} }}}}", "[".repeat(100_000), "]".repeat(100_000)),
+        format!("{{{{ {}a }}}}", "!".repeat(100_000)),
+        format!("{{{{ {}a }}}}", "x => ".repeat(100_000)),
+        format!(
+            "{}{}",
+            "{{ if a }}".repeat(100_000),
+            "{{ /if }}".repeat(100_000)
+        ),
+        format!(
+            "{}{}",
+            "{{ for x in a }}".repeat(100_000),
+            "{{ /for }}".repeat(100_000)
+        ),
+        format!(
+            "{{{{ f'{{{}1{}}}' }}}}",
+            "(".repeat(100_000),
+            ")".repeat(100_000)
+        ),
+    ];
+    for input in too_deep {
+        assert!(matches!(
+            Parser::parse_input(&input),
+            Err(ParseError::NestingTooDeep(64))
+        ));
+        let (_, errors) = Parser::parse_input_recovering(&input);
+        assert!(matches!(errors[0], ParseError::NestingTooDeep(64)));
+    }
+
+    // Chains of operators and `elif`s don't nest, but they make the tree taller, which is limited
+    // separately.
+    let too_tall = [
+        format!("{{{{ 1{} }}}}", " + 1".repeat(100_000)),
+        format!("{{{{ host{} }}}}", ".name".repeat(100_000)),
+        format!(
+            "{{{{ if a }}}}{}{{{{ /if }}}}",
+            "{{ elif a }}".repeat(100_000)
+        ),
+    ];
+    for input in too_tall {
+        assert!(matches!(
+            Parser::parse_input(&input),
+            Err(ParseError::TooTall(1024))
+        ));
+    }
+    let out = eval(&format!("{{{{ 0{} }}}}", " + 1".repeat(1000)));
+    assert_eq!(out, "1000");
+    // Long chains pass through every other pass over the tree as well.
+    let input = format!("{{{{ host{} }}}}", "?.name".repeat(1000));
+    let template = Parser::parse_input(&input).unwrap();
+    assert_eq!(Printer::new().print(&template).unwrap(), input);
+    assert_eq!(Node::from_bytes(&template.to_bytes()).unwrap(), template);
+    assert_eq!(
+        template.referenced_vars(),
+        HashSet::from([&"host".to_string()])
+    );
+    assert_eq!(template.optimize(), Parser::parse_input(&input).unwrap());
+    let mut lexer = Lexer::new("{{ 1 + 1 + 1 }}");
+    let parser = Parser::new(&mut lexer).with_max_height(3);
+    assert!(matches!(parser.parse_all(), Err(ParseError::TooTall(3))));
+    // They also render with the default limits, in every backend.
+    let mut variables = HashMap::new();
+    variables.insert("n".to_string(), OwnedValue::Number(199.0));
+    let ladder = format!(
+        "{{{{ if n == 0 }}}}0{}{{{{ /if }}}}",
+        (1..200)
+            .map(|n| format!("{{{{ elif n == {n} }}}}{n}"))
+            .collect::<String>()
+    );
+    let renders = [
+        (format!("{{{{ 0{} }}}}", " + 1".repeat(199)), "199"),
+        (format!("{{{{ n{} }}}}", " ?? missing".repeat(199)), "199"),
+        (
+            format!("{{{{ {}n }}}}", "missing?.a ?? ".repeat(199)),
+            "199",
+        ),
+        (ladder, "199"),
+    ];
+    let environment = Environment::new();
+    for (input, expected) in renders {
+        let template = Parser::parse_input(&input).unwrap();
+        let out = template.evaluate(&variables, &environment);
+        assert_eq!(out.unwrap(), expected);
+        let out = template.compile().evaluate(&variables, &environment);
+        assert_eq!(out.unwrap(), expected);
+        let mut out = String::new();
+        template
+            .render_to(&variables, &environment, &mut out)
+            .unwrap();
+        assert_eq!(out, expected);
+    }
+
+    // Templates just below the limit parse and render without overflowing the stack.
+    let out = eval(&format!("{{{{ {}1{} }}}}", "(".repeat(60), ")".repeat(60)));
+    assert_eq!(out, "1");
+    let out = eval(&format!("{{{{ 0{} }}}}", " + 1".repeat(60)));
+    assert_eq!(out, "60");
+    let out = eval(&format!(
+        "{}1{}",
+        "{{ if a }}".repeat(30),
+        "{{ /if }}".repeat(30)
+    ));
+    assert_eq!(out, "1");
+
+    let template = Parser::parse_input("{{ [[[1]]] }}").unwrap();
+    let mut environment = Environment::new();
+    environment.set_max_depth(3);
+    let out = template.evaluate(&HashMap::<String, OwnedValue>::new(), &environment);
+    assert!(matches!(out, Err(ValueError::NestingTooDeep(3))));
+
+    let mut lexer = Lexer::new("{{ [[1]] }}");
+    let parser = Parser::new(&mut lexer).with_max_depth(3);
+    assert!(matches!(
+        parser.parse_all(),
+        Err(ParseError::NestingTooDeep(3))
+    ));
+}
+
+#[test]
+fn error_recovery() {
+    let input =
+        "{{ a + }}ok{{ if b ! }}{{ c @ d }}{{ /for }}{{ for in xs }}{{ x }}{{ /for }}{{ e ]]] }}";
+    let (template, errors) = Parser::parse_input_recovering(input);
+    assert_eq!(errors.len(), 6, "{errors:?}");
+    assert!(matches!(errors[2], ParseError::LexerError(_)));
+    assert!(matches!(
+        errors[3],
+        ParseError::MismatchedEndTag("/for", "if", 1)
+    ));
+    let Node::Body(nodes) = &template else {
+        panic!("{template:?}");
+    };
+    assert!(matches!(
+        nodes[..],
+        [
+            Node::Error,
+            Node::Value(_),
+            Node::IfThenElse(..),
+            Node::ForIn(..),
+            Node::Variable(_),
+        ]
+    ));
+    assert!(template
+        .evaluate(&HashMap::<String, OwnedValue>::new(), &environment())
+        .is_err());
+
+    // An `else` or `elif` can't close a loop inside of an `if`. While recovering, it closes the
+    // loop and continues the `if`.
+    for input in [
+        "{{ if 1 }}A{{ for y in xs }}B{{ else }}C{{ /if }}",
+        "{{ if 1 }}A\n{{ for y in xs }}B{{ elif 2 }}C{{ /if }}",
+    ] {
+        let line = input.lines().count();
+        assert!(matches!(
+            Parser::parse_input(input),
+            Err(ParseError::UnclosedBlock("for", l)) if l == line
+        ));
+        let (template, errors) = Parser::parse_input_recovering(input);
+        assert!(matches!(errors[..], [ParseError::UnclosedBlock("for", l)] if l == line));
+        let Node::Body(nodes) = &template else {
+            panic!("{template:?}");
+        };
+        assert!(matches!(&nodes[..], [Node::IfThenElse(_, _, Some(_))]));
+    }
+
+    // Unclosed blocks are closed at the end of the input.
+    let (template, errors) = Parser::parse_input_recovering("{{ for x in xs }}{{ if x }}{{ x");
+    assert!(matches!(
+        errors[..],
+        [
+            ParseError::UnexpectedEOF(_),
+            ParseError::UnclosedBlock("if", 1),
+            ParseError::UnclosedBlock("for", 1),
+        ]
+    ));
+    assert!(matches!(&template, Node::Body(nodes) if matches!(nodes[..], [Node::ForIn(..)])));
+
+    // Templates without errors parse the same as with `parse_all`.
+    let (template, errors) = Parser::parse_input_recovering("{{ for x in xs }}{{ x }}{{ /for }}");
+    assert!(errors.is_empty());
+    assert!(matches!(&template, Node::Body(nodes) if matches!(nodes[..], [Node::ForIn(..)])));
+}
+
+#[test]
+fn error_messages() {
+    let messages = [
+        (
+            "{{ pad(a b) }}",
+            "Unexpected identifier \"b\", expected `)`, `,`, or an operator",
+        ),
+        ("{{ a / }}", "Unexpected `}}`, expected an expression"),
+        ("{{ a", "Unexpected end of input, expected `}}`"),
+        (
+            "{{ xs[] }}",
+            "Unexpected `]`, expected an expression or `:`",
+        ),
+        ("{{ a is }}", "Unexpected `}}`, expected a test name"),
+        (
+            "{{ for 1 in xs }}",
+            "Unexpected number 1, expected an identifier",
+        ),
+        (
+            "{{ 0..1..2 }}",
+            "`..` cannot be chained without parentheses",
+        ),
+        ("{{ break }}", "`break` outside of a for loop"),
+    ];
+    for (input, message) in messages {
+        let err = Parser::parse_input(input).unwrap_err();
+        assert_eq!(err.to_string(), message, "{input}");
+    }
+}
+
+#[test]
+fn printer() {
+    let environment = environment();
+    let templates = [
+        "Hello {{world}}! {single} braces } and 'quotes'",
+        "{{ if a }}a{{ elif b }}b{{ else }}{{ if c }}c{{ /if }}{{ end }}",
+        "{{ for x in xs if x != 0 ', ' + world }}{{ if x == 9 }}{{ break }}{{ endif }}{{ x }}{{ /for }}",
+        "{{ for x in xs -1 }}{{ x }}{{ /for }}{{ for x in xs [1] }}{{ x }}{{ /for }}",
+        "{{ (1 + 2) * 3 - (4 - 5) - 6 }}{{ -(a + 1) }}{{ !(a && b) || c }}{{ (a ?? b)?.c }}",
+        "{{ 2 ** 3 ** 2 }}{{ (2 ** 3) ** 2 }}{{ -2 ** 2 }}{{ (-2) ** 2 }}{{ i mod 3 mod 2 }}{{ ~'ab' + 'c' }}",
+        "{{ (0..n)[1] }}{{ 0..=n }}{{ items[1:] }}{{ items[::2] }}{{ items[:-1:] }}{{ h?[0] }}",
+        "{{ x is not divisibleby(3) }}{{ (x is even) == (y is null) }}{{ x not in xs }}",
+        "{{ map(xs, x => x * 2) }}{{ reduce(xs, 0, (sum, x) => sum + x) }}{{ [x => x, 1] }}",
+        "{{ pad(world, width = 10, fill = '-') }}{{ [...xs, 1, [2]] }}{{ [h.name for h in hosts if h.down] }}",
+        "{{ f\"a {b} {'c'}{{d}} \\\"e\\\"\" }}{{ 'it\\'s\\n\\\\' }}{{ (end) }}{{ end + 1 }}",
+    ];
+    for template in templates {
+        let parsed = Parser::parse_input_with(template, &environment).unwrap();
+        let printed = Printer::new()
+            .with_environment(&environment)
+            .print(&parsed)
+            .unwrap();
+        let reparsed = Parser::parse_input_with(&printed, &environment).unwrap();
+        assert_eq!(reparsed, parsed, "{printed}");
+    }
+
+    let printed = Printer::new()
+        .print(&Parser::parse_input("{{a+b*c}}{{ 'x' }}").unwrap())
+        .unwrap();
+    assert_eq!(printed, "{{ a + b * c }}x");
+
+    // A separator that would continue the array is parenthesized.
+    let template = Node::Body(vec![Node::ForIn(
+        "x".into(),
+        Node::Variable("xs".into()).into(),
+        Node::Body(Vec::new()).into(),
+        Some(Node::Negate(Node::Value(OwnedValue::Number(1.0)).into()).into()),
+        None,
+    )]);
+    let printed = Printer::new().print(&template).unwrap();
+    assert_eq!(printed, "{{ for x in (xs) (-1) }}{{ /for }}");
+    assert_eq!(Parser::parse_input(&printed).unwrap(), template);
+
+    let template = "<ul>\n{{ for x in xs }}\n<li>\n{{ if x }}\n{{ x }}\n   {{ /if }}\n</li>\n{{ /for }}\n</ul>";
+    let printed = Printer::new()
+        .with_indent("  ")
+        .print(&Parser::parse_input(template).unwrap())
+        .unwrap();
+    assert_eq!(
+        printed,
+        "<ul>\n{{ for x in xs }}\n  <li>\n  {{ if x }}\n    {{ x }}\n  {{ /if }}\n  </li>\n{{ /for }}\n</ul>"
+    );
+
+    // Booleans have no literals, so they are printed as comparisons that evaluate to them.
+    let template = Node::Body(vec![Node::Array(vec![
+        Node::Value(OwnedValue::Boolean(true)),
+        Node::Value(OwnedValue::Boolean(false)),
+    ])]);
+    let printed = Printer::new().print(&template).unwrap();
+    assert_eq!(printed, "{{ [0 == 0, 0 != 0] }}");
+    let vars = HashMap::<String, OwnedValue>::new();
+    assert_eq!(
+        Parser::parse_input(&printed)
+            .unwrap()
+            .evaluate(&vars, &Environment::new())
+            .unwrap(),
+        template.evaluate(&vars, &Environment::new()).unwrap()
+    );
+
+    // The errors of a recovering parse have no source.
+    let (template, _) = Parser::parse_input_recovering("ok{{ if a }}{{ b + }}{{ /if }}");
+    assert!(matches!(
+        Printer::new().print(&template),
+        Err(PrintError::SyntaxError)
+    ));
+}
+
+#[test]
+fn visitors() {
+    let template = Parser::parse_input(
+        "{{ for x in x if x != y }}{{ x.a }}{{ /for }}{{ [h for h in hosts if h == z] }}{{ map(xs, (x, i) => x + i + w) }}",
+    )
+    .unwrap();
+    let vars = template.referenced_vars();
+    let expected = ["x", "y", "hosts", "z", "xs", "w"].map(String::from);
+    assert_eq!(vars, expected.iter().collect::<HashSet<_>>());
+
+    struct Rename;
+    impl VisitorMut for Rename {
+        fn visit_node_mut(&mut self, node: &mut Node) {
+            if let Node::Variable(identifier) = node {
+                identifier.insert_str(0, "page.");
+            }
+            visit::walk_mut(self, node);
+        }
+    }
+    let mut template =
+        Parser::parse_input("{{ if a }}{{ b[c] }}{{ else }}{{ -d }}{{ /if }}").unwrap();
+    Rename.visit_node_mut(&mut template);
+    let printed = Printer::new().print(&template).unwrap();
+    assert_eq!(
+        printed,
+        "{{ if page.a }}{{ page.b[page.c] }}{{ else }}{{ -page.d }}{{ /if }}"
+    );
+
+    /// Replaces `-n` with the negative number.
+    struct Negate;
+    impl Fold for Negate {
+        fn fold_node(&mut self, node: Node) -> Node {
+            match visit::walk_fold(self, node) {
+                Node::Negate(node) => match *node {
+                    Node::Value(OwnedValue::Number(n)) => Node::Value(OwnedValue::Number(-n)),
+                    node => Node::Negate(node.into()),
+                },
+                node => node,
+            }
+        }
+    }
+    let template = Parser::parse_input("{{ [-1, -(-2), -x] }}").unwrap();
+    let expected = Node::Body(vec![Node::Array(vec![
+        Node::Value(OwnedValue::Number(-1.0)),
+        Node::Value(OwnedValue::Number(2.0)),
+        Node::Negate(Node::Variable("x".into()).into()),
+    ])]);
+    assert_eq!(Negate.fold_node(template), expected);
+}
+
+#[test]
+fn optimize() {
+    let template = Parser::parse_input(
+        "<hr>{{ '-' * 4 }}{{ if 1 == 1 }}<b>{{ x }}</b>{{ else }}no{{ /if }}{{ if !1 }}no{{ /if }}",
+    )
+    .unwrap();
+    let expected = Node::Body(vec![
+        Node::Value(OwnedValue::String("<hr>----<b>".into())),
+        Node::Variable("x".into()),
+        Node::Value(OwnedValue::String("</b>".into())),
+    ]);
+    assert_eq!(template.optimize(), expected);
+
+    let template = Parser::parse_input("{{ [1 + 2, ...[3]] }}{{ x + 1 * 2 }}").unwrap();
+    let expected = Parser::parse_input("3, 3{{ x + 2 }}").unwrap();
+    assert_eq!(template.optimize(), expected);
+
+    // Branches that are never taken are dropped without folding what they compute.
+    let template =
+        Parser::parse_input("a{{ if !1 }}{{ 'x' * 1000000000000000000 }}{{ else }}b{{ /if }}c")
+            .unwrap();
+    let expected = Parser::parse_input("abc").unwrap();
+    assert_eq!(template.optimize(), expected);
+    let template = Parser::parse_input(
+        "{{ if 1 == 1 }}{{ x }}{{ else }}{{ 'y' * 1000000000000000000 }}{{ /if }}",
+    )
+    .unwrap()
+    .optimize();
+    assert_eq!(template, Node::Body(vec![Node::Variable("x".into())]));
+
+    // Failing expressions, custom operators, and ranges are kept for render time.
+    let environment = environment();
+    for input in [
+        "{{ if x }}{{ -'a' }}{{ /if }}",
+        "{{ 2 ** 3 }}",
+        "{{ [...0..3] }}",
+    ] {
+        let template = Parser::parse_input_with(input, &environment).unwrap();
+        let optimized = Parser::parse_input_with(input, &environment)
+            .unwrap()
+            .optimize();
+        assert!(matches!(optimized, Node::Body(ref nodes) if !matches!(nodes[0], Node::Value(_))));
+        assert_eq!(
+            optimized
+                .evaluate(&HashMap::<String, OwnedValue>::new(), &environment)
+                .map_err(|err| err.to_string()),
+            template
+                .evaluate(&HashMap::<String, OwnedValue>::new(), &environment)
+                .map_err(|err| err.to_string()),
+        );
+    }
+}
+
+#[test]
+fn compile() {
+    let environment = environment();
+    let vars = HashMap::from([
+        (
+            "xs".to_owned(),
+            OwnedValue::Array(vec![OwnedValue::Number(1.0), OwnedValue::Number(2.0)]),
+        ),
+        ("s".to_owned(), OwnedValue::String("abc".into())),
+    ]);
+    let templates = [
+        "{{ for x in xs ', ' }}{{ for y in xs if x == y }}{{ if y == 2 }}{{ break }}{{ /if }}{{ y }}{{ /for }}{{ /for }}",
+        "{{ for x in xs if x != 1 }}{{ x }}{{ /for }}",
+        "{{ map(xs, x => [y * x for y in xs if y != x]) }}{{ reduce(xs, 0, (sum, x) => sum + x) }}",
+        "{{ xs[5] ?? 'none' }}{{ missing?.a.b ?? 'none' }}{{ xs?[-1] }}{{ s[::-1] }}{{ s['a':] }}",
+        "{{ missing is defined }}{{ xs[9] is undefined }}{{ 7 is prime }}{{ 1 is frobnicated }}",
+        "{{ [...xs, ...0..2] }}{{ f\"{s}{xs[0] + 1}\" }}{{ ~s }}{{ 2 ** 3 mod 5 }}",
+        "{{ -s }}",
+        "{{ xs.name }}",
+        "{{ undefined_function(missing) }}",
+        "{{ for x in s }}{{ x }}{{ /for }}",
+    ];
+    for input in templates {
+        let template = Parser::parse_input_with(input, &environment).unwrap();
+        let program = template.compile();
+        assert_eq!(
+            program
+                .evaluate(&vars, &environment)
+                .map_err(|err| err.to_string()),
+            template
+                .evaluate(&vars, &environment)
+                .map_err(|err| err.to_string()),
+            "{input}"
+        );
+    }
+
+    // `break` in a filter ends the outer loop, and the output before it is kept.
+    let template = Node::Body(vec![Node::ForIn(
+        "x".into(),
+        Node::Variable("xs".into()).into(),
+        Node::Body(vec![
+            Node::Variable("x".into()),
+            Node::ForIn(
+                "y".into(),
+                Node::Variable("xs".into()).into(),
+                Node::Body(Vec::new()).into(),
+                None,
+                Some(Node::Break.into()),
+            ),
+        ])
+        .into(),
+        Some(Node::Value(OwnedValue::String(", ".into())).into()),
+        None,
+    )]);
+    let out = template.compile().evaluate(&vars, &environment).unwrap();
+    assert_eq!(out, "1");
+    assert_eq!(template.evaluate(&vars, &environment).unwrap(), out);
+    let out = Node::Break.compile().evaluate(&vars, &environment);
+    assert!(matches!(out, Err(ValueError::OperationError(_))));
+
+    // The arguments of a test are compiled once, so nesting tests doesn't blow up the program.
+    let nested = (0..40).fold("1".to_owned(), |arg, _| format!("2 is divisibleby({arg})"));
+    let template = Parser::parse_input(&format!("{{{{ {nested} }}}}")).unwrap();
+    let start = std::time::Instant::now();
+    let program = template.compile();
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    assert_eq!(
+        program
+            .evaluate(&vars, &environment)
+            .map_err(|err| err.to_string()),
+        template
+            .evaluate(&vars, &environment)
+            .map_err(|err| err.to_string()),
+    );
+
+    let mut environment = Environment::new();
+    environment.set_max_depth(3);
+    let program = Parser::parse_input("{{ [[[1]]] }}").unwrap().compile();
+    let out = program.evaluate(&HashMap::<String, OwnedValue>::new(), &environment);
+    assert!(matches!(out, Err(ValueError::NestingTooDeep(3))));
+}
+
+#[test]
+fn compile_nesting_limit() {
+    let vars = HashMap::from([
+        (
+            "xs".to_owned(),
+            OwnedValue::Array(vec![OwnedValue::Number(1.0), OwnedValue::Number(2.0)]),
+        ),
+        ("s".to_owned(), OwnedValue::String("abc".into())),
+    ]);
+    // The nesting limit is checked as the program runs, so only the parts that are reached fail,
+    // and whatever fails first fails the render.
+    let templates = [
+        "{{ if xs == [] }}{{ [[[[1]]]] }}{{ else }}ok{{ /if }}",
+        "{{ missing }}{{ [[[[1]]]] }}",
+        "{{ [1, [missing, [[1]]]] }}",
+        "{{ for x in xs }}{{ if x == 2 }}{{ [[[x]]] }}{{ /if }}{{ x }}{{ /for }}",
+        "{{ [y for y in xs if [[y]] != [[3]]] }}{{ xs[-(-(1))] ?? [[0]] }}",
+        "{{ map(xs, x => [[[x]]]) }}{{ map([], x => [[[[[x]]]]]) }}",
+        "{{ s + -(-(-1)) }}{{ missing?.b[[[1]][0][0]] }}{{ 4 is divisibleby(-(-(1))) }}",
+        "{{ !!!s }}{{ s || [[[s]]] }}{{ ~s[-(1)] }}{{ f\"{f\"{[[s]]}\"}\" }}",
+    ];
+    let mut environment = environment();
+    for input in templates {
+        let template = Parser::parse_input_with(input, &environment).unwrap();
+        let program = template.compile();
+        for max_depth in 0..8 {
+            environment.set_max_depth(max_depth);
+            let expected = template
+                .evaluate(&vars, &environment)
+                .map_err(|err| err.to_string());
+            let out = program
+                .evaluate(&vars, &environment)
+                .map_err(|err| err.to_string());
+            assert_eq!(out, expected, "{input} at depth {max_depth}");
+        }
+    }
+}
+
+#[test]
+fn render_to() {
+    let environment = environment();
+    let vars = HashMap::from([(
+        "xs".to_owned(),
+        OwnedValue::Array(vec![OwnedValue::Number(1.0), OwnedValue::Number(2.0)]),
+    )]);
+    let input = "<{{ for x in xs ', ' }}{{ for y in xs if x == y }}{{ y }}{{ break }}{{ /for }}!{{ /for }}>";
+    let template = Parser::parse_input(input).unwrap();
+    let mut out = Vec::new();
+    template
+        .render_to_io(&vars, &environment, &mut out)
+        .unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "<1!, 2!>");
+
+    // `break` in a filter drops what the inner loop rendered, even though it wasn't buffered by
+    // a separator.
+    let template = Node::Body(vec![Node::ForIn(
+        "x".into(),
+        Node::Variable("xs".into()).into(),
+        Node::Body(vec![
+            Node::Variable("x".into()),
+            Node::ForIn(
+                "y".into(),
+                Node::Variable("xs".into()).into(),
+                Node::Variable("y".into()).into(),
+                None,
+                Some(
+                    Node::IfThenElse(
+                        Node::Operation(
+                            Node::Variable("y".into()).into(),
+                            Operator::IsEqualTo,
+                            Node::Value(OwnedValue::Number(2.0)).into(),
+                        )
+                        .into(),
+                        Node::Break.into(),
+                        Some(Node::Value(OwnedValue::Boolean(true)).into()),
+                    )
+                    .into(),
+                ),
+            ),
+        ])
+        .into(),
+        None,
+        None,
+    )]);
+    let mut out = String::new();
+    template.render_to(&vars, &environment, &mut out).unwrap();
+    assert_eq!(out, "1");
+    assert_eq!(template.evaluate(&vars, &environment).unwrap(), out);
+
+    // Output is written as it's rendered, so it stops at the error.
+    let template = Parser::parse_input("a{{ for x in xs }}{{ x }}{{ oops }}{{ /for }}").unwrap();
+    let mut out = String::new();
+    let result = template.render_to(&vars, &environment, &mut out);
+    assert!(matches!(result, Err(ValueError::UndefinedVariable(_))));
+    assert_eq!(out, "a1");
+
+    struct Full;
+
+    impl fmt::Write for Full {
+        fn write_str(&mut self, _: &str) -> fmt::Result {
+            Err(fmt::Error)
+        }
+    }
+
+    impl io::Write for Full {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let template = Parser::parse_input("a").unwrap();
+    let result = template.render_to(&vars, &environment, &mut Full);
+    assert!(matches!(result, Err(ValueError::WriteError(_))));
+    let result = template.render_to_io(&vars, &environment, &mut Full);
+    assert!(
+        matches!(result, Err(ValueError::IoError(err)) if err.kind() == io::ErrorKind::StorageFull)
+    );
+}
+
+#[test]
+fn binary_cache() {
+    let environment = environment();
+    let input = "<ul>{{ for x in xs if x is defined ', ' }}{{ x?.name ?? f\"#{x}\" }}{{ break }}{{ /for }}</ul>\
+        {{ map(xs, (x, i) => x[1:-1:2] ** i) }}{{ [...0..=3, -1.5, 'é', pad(w, width = 2)] }}";
+    let template = Parser::parse_input_with(input, &environment).unwrap();
+    let bytes = template.to_bytes();
+    assert_eq!(Node::from_bytes(&bytes).unwrap(), template);
+    // Folded constants are values that have no literal syntax.
+    let template = Parser::parse_input("{{ for x in 0..3 }}{{ f([1, [x]], [2, [3]]) }}{{ /for }}");
+    let template = template.unwrap().optimize();
+    assert_eq!(Node::from_bytes(&template.to_bytes()).unwrap(), template);
+
+    assert!(matches!(
+        Node::from_bytes(b"{{ x }}"),
+        Err(DecodeError::NotACache)
+    ));
+    assert!(matches!(
+        Node::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(DecodeError::UnexpectedEnd)
+    ));
+    assert!(matches!(
+        Node::from_bytes(&[&bytes[..], &[0]].concat()),
+        Err(DecodeError::Invalid(_))
+    ));
+
+    // The header is the magic number, the format version, and the crate version.
+    let header = [b"RTPL", &[1][..], &[5], b"999.0"].concat();
+    let cache = [&header[..], &[19]].concat();
+    assert!(matches!(
+        Node::from_bytes(&cache),
+        Err(DecodeError::IncompatibleVersion(version, 1)) if version == "999.0"
+    ));
+
+    // The tallest trees that the parser builds are cached, but corrupt caches can't overflow the
+    // stack.
+    let tall = Parser::parse_input(&format!("{{{{ 0{} }}}}", " + 1".repeat(1000))).unwrap();
+    assert_eq!(Node::from_bytes(&tall.to_bytes()).unwrap(), tall);
+    let mut deep = Node::Variable("x".into());
+    for _ in 0..1100 {
+        deep = Node::Not(deep.into());
+    }
+    assert!(matches!(
+        Node::from_bytes(&deep.to_bytes()),
+        Err(DecodeError::NestingTooDeep(1024))
+    ));
+
+    // Trees nested deeper than the default are as tall as the parser allows by default.
+    let input = format!("{{{{ {}1 }}}}", "!".repeat(100));
+    let mut lexer = Lexer::new(&input);
+    let nested = Parser::new(&mut lexer)
+        .with_max_depth(128)
+        .parse_all()
+        .unwrap();
+    assert_eq!(Node::from_bytes(&nested.to_bytes()).unwrap(), nested);
+
+    // Trees parsed with a higher height are decoded with the same height.
+    let input = format!("{{{{ 0{} }}}}", " + 1".repeat(1100));
+    let mut lexer = Lexer::new(&input);
+    let taller = Parser::new(&mut lexer)
+        .with_max_height(1200)
+        .parse_all()
+        .unwrap();
+    let bytes = taller.to_bytes();
+    assert!(matches!(
+        Node::from_bytes(&bytes),
+        Err(DecodeError::NestingTooDeep(1024))
+    ));
+    assert_eq!(
+        Node::from_bytes_with_max_height(&bytes, 1200).unwrap(),
+        taller
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde() {
+    let input = "{{ for x in 0..3 }}{{ x ?? 'a' }}{{ /for }}{{ [y for y in xs if !y] }}";
+    let template = Parser::parse_input(input).unwrap();
+    let json = serde_json::to_string(&template).unwrap();
+    assert_eq!(serde_json::from_str::<Node>(&json).unwrap(), template);
+
+    let range = r#"{"Value":{"Range":{"start":0.0,"end":1.0,"step":0.0,"inclusive":false}}}"#;
+    assert!(serde_json::from_str::<Node>(range).is_err());
 }
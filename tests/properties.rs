@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, HashMap};
+
+use proptest::prelude::*;
+use ramon_templates::{Environment, Node, OwnedValue, Parser, Printer};
+
+/// Pieces of template syntax, so that generated inputs get past the lexer more often than random
+/// strings do. Numbers are kept small so that loops over generated ranges stay fast.
+const FRAGMENTS: &[&str] = &[
+    "{{",
+    "}}",
+    "{{ /if }}",
+    "{{ /for }}",
+    "{{ end }}",
+    "{{ endfor }}",
+    "{{ else }}",
+    "{{ break }}",
+    "{{ continue }}",
+    "if",
+    "elif",
+    "for",
+    "in",
+    "not",
+    "is",
+    "defined",
+    "even",
+    "x",
+    "xs",
+    "host",
+    "name",
+    "range",
+    "0",
+    "1",
+    "2",
+    "-1",
+    "0.5",
+    "null",
+    "'a'",
+    "\"b\"",
+    "f'{x}'",
+    "f\"{",
+    "(",
+    ")",
+    "[",
+    "]",
+    ",",
+    ":",
+    ".",
+    "?.",
+    "?[",
+    "...",
+    "..",
+    "..=",
+    "=>",
+    "=",
+    "==",
+    "!=",
+    "!",
+    "&&",
+    "||",
+    "??",
+    "+",
+    "-",
+    "*",
+    "/",
+    "text",
+    " ",
+    "\n",
+    "{",
+    "}",
+];
+
+fn fragments() -> impl Strategy<Value = String> {
+    prop::collection::vec(prop::sample::select(FRAGMENTS), 0..40)
+        .prop_map(|fragments| fragments.join(" "))
+}
+
+/// Well-formed expressions, most of which parse, with redundant and missing parentheses.
+fn expressions() -> impl Strategy<Value = String> {
+    let leaf = prop::sample::select(&["x", "xs", "1", "0.5", "'a'", "null", "f'{x}!'"][..])
+        .prop_map(str::to_owned);
+    leaf.prop_recursive(4, 32, 3, |inner| {
+        let binary = prop::sample::select(
+            &[
+                "+", "-", "*", "/", "==", "!=", "&&", "||", "??", "..", "..=", "in", "not in",
+            ][..],
+        );
+        prop_oneof![
+            (inner.clone(), binary, inner.clone()).prop_map(|(a, op, b)| format!("{a} {op} {b}")),
+            inner.clone().prop_map(|a| format!("({a})")),
+            inner.clone().prop_map(|a| format!("!{a}")),
+            inner.clone().prop_map(|a| format!("-{a}")),
+            (inner.clone(), inner.clone()).prop_map(|(a, b)| format!("{a}[{b}]")),
+            (inner.clone(), inner.clone()).prop_map(|(a, b)| format!("{a}?[:{b}:]")),
+            inner.clone().prop_map(|a| format!("{a}.name")),
+            inner
+                .clone()
+                .prop_map(|a| format!("{a} is not divisibleby(2)")),
+            (inner.clone(), inner.clone()).prop_map(|(a, b)| format!("f({a}, k = {b})")),
+            (inner.clone(), inner.clone()).prop_map(|(a, b)| format!("[{a}, ...{b}]")),
+            (inner.clone(), inner.clone()).prop_map(|(a, b)| format!("[{a} for x in {b}]")),
+            inner.clone().prop_map(|a| format!("map(xs, x => {a})")),
+        ]
+    })
+}
+
+fn variables() -> HashMap<String, OwnedValue> {
+    HashMap::from([
+        ("x".to_owned(), OwnedValue::Number(2.0)),
+        (
+            "xs".to_owned(),
+            OwnedValue::Array(vec![OwnedValue::Number(1.0), OwnedValue::Null]),
+        ),
+        (
+            "host".to_owned(),
+            OwnedValue::Object(BTreeMap::from([(
+                "name".to_owned(),
+                OwnedValue::String("web".into()),
+            )])),
+        ),
+    ])
+}
+
+/// Functions that show what they were called with, so that the arguments and lambdas passed to
+/// them are compared too.
+fn environment() -> Environment {
+    let mut environment = Environment::new();
+    environment.add_function("f", |args| {
+        let positional = args.positional.iter().map(|arg| arg.inner().clone());
+        let named = args.named.iter().map(|(name, arg)| (name, arg.inner()));
+        Ok(OwnedValue::String(format!(
+            "{:?} {:?}",
+            positional.collect::<Vec<_>>(),
+            named.collect::<Vec<_>>()
+        )))
+    });
+    environment.add_function("map", |args| {
+        let items = args.get(0).map(|items| items.inner().clone());
+        let (Some(OwnedValue::Array(items)), Some(lambda)) =
+            (items, args.get(1).and_then(|lambda| lambda.as_lambda()))
+        else {
+            return Ok(OwnedValue::Null);
+        };
+        let items = items.iter().map(|item| lambda.call(vec![item.into()]));
+        Ok(OwnedValue::Array(items.collect::<Result<_, _>>()?))
+    });
+    environment
+}
+
+fn render(input: &str) {
+    // Recovery has to make progress on any input, or this hangs.
+    let _ = Parser::parse_input_recovering(input);
+    let Ok(template) = Parser::parse_input(input) else {
+        return;
+    };
+    let vars = variables();
+    let environment = environment();
+    // The tree-walker is the reference for the compiled program.
+    let evaluation = template
+        .evaluate(&vars, &environment)
+        .map_err(|err| err.to_string());
+    let program = template.compile();
+    assert_eq!(
+        program
+            .evaluate(&vars, &environment)
+            .map_err(|err| err.to_string()),
+        evaluation,
+        "{input:?} was compiled into {program:?}"
+    );
+    // And for streaming.
+    let mut out = String::new();
+    let streamed = template.render_to(&vars, &environment, &mut out);
+    assert_eq!(
+        streamed.map(|()| out).map_err(|err| err.to_string()),
+        evaluation,
+        "{input:?}"
+    );
+}
+
+/// Checks that optimizing a template doesn't change its output or its error.
+fn optimize(input: &str) {
+    let (Ok(template), Ok(optimized)) = (Parser::parse_input(input), Parser::parse_input(input))
+    else {
+        return;
+    };
+    let optimized = optimized.optimize();
+    let vars = variables();
+    let environment = Environment::new();
+    assert_eq!(
+        optimized
+            .evaluate(&vars, &environment)
+            .map_err(|err| err.to_string()),
+        template
+            .evaluate(&vars, &environment)
+            .map_err(|err| err.to_string()),
+        "{input:?} was optimized into {optimized:?}"
+    );
+}
+
+/// Checks that printing a template and parsing it again yields the same tree.
+fn round_trip(input: &str) {
+    let Ok(template) = Parser::parse_input(input) else {
+        return;
+    };
+    let cached = Node::from_bytes(&template.to_bytes());
+    assert_eq!(
+        cached.ok().as_ref(),
+        Some(&template),
+        "{input:?} was cached wrong"
+    );
+    let printed = Printer::new().print(&template).unwrap();
+    let reparsed = Parser::parse_input(&printed);
+    assert_eq!(
+        reparsed.ok(),
+        Some(template),
+        "{input:?} was printed as {printed:?}"
+    );
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_strings_never_panic(input in any::<String>()) {
+        render(&input);
+    }
+
+    #[test]
+    fn arbitrary_templates_never_panic(input in fragments()) {
+        render(&input);
+    }
+
+    #[test]
+    fn arbitrary_expressions_never_panic(input in fragments()) {
+        render(&format!("{{{{ {input} }}}}"));
+    }
+
+    #[test]
+    fn printed_templates_parse_the_same(input in fragments()) {
+        round_trip(&input);
+        round_trip(&format!("{{{{ {input} }}}}"));
+    }
+
+    #[test]
+    fn printed_expressions_parse_the_same(input in expressions()) {
+        round_trip(&format!("{{{{ {input} }}}}{{{{ if {input} }}}}a{{{{ /if }}}}"));
+    }
+
+    #[test]
+    fn optimized_templates_render_the_same(input in fragments()) {
+        optimize(&input);
+        optimize(&format!("{{{{ {input} }}}}"));
+    }
+
+    #[test]
+    fn optimized_expressions_render_the_same(input in expressions()) {
+        optimize(&format!("{{{{ {input} }}}}{{{{ if {input} }}}}a{{{{ else }}}}b{{{{ /if }}}}"));
+    }
+
+    #[test]
+    fn compiled_expressions_render_the_same(input in expressions()) {
+        render(&format!(
+            "{{{{ {input} }}}}{{{{ for x in xs if {input} ', ' }}}}{{{{ if x == null }}}}{{{{ break }}}}\
+             {{{{ elif {input} }}}}{{{{ continue }}}}{{{{ /if }}}}{{{{ {input} }}}}{{{{ /for }}}}"
+        ));
+    }
+
+    /// Corrupt caches are rejected instead of panicking or allocating whatever length they claim.
+    #[test]
+    fn arbitrary_caches_never_panic(input in prop::collection::vec(any::<u8>(), 0..64)) {
+        let mut cache = Node::Body(Vec::new()).to_bytes();
+        cache.pop();
+        cache.extend(&input);
+        let _ = Node::from_bytes(&cache);
+    }
+
+    /// Huge, negative, and non-finite counts used to abort with a capacity overflow.
+    #[test]
+    fn extreme_repetitions_never_panic(count in prop_oneof![Just(1e19), any::<f64>()]) {
+        let input = format!("{{{{ 'ab' * {count} }}}}{{{{ {count} * 'a' }}}}{{{{ 'a' * ({count} / 0) }}}}");
+        render(&input);
+        optimize(&input);
+    }
+
+    /// Slicing, spreading, writing, and collecting long ranges used to allocate every item.
+    #[test]
+    fn long_ranges_never_exhaust_memory(end in 2e6..1e18f64, step in 1..1000) {
+        let end = end.trunc();
+        for input in [
+            format!("{{{{ (0..{end})[::{step}] }}}}"),
+            format!("{{{{ [...range(0, {end}, {step})] }}}}"),
+            format!("{{{{ range(0, {end}, {step}) }}}}"),
+            format!("{{{{ [x for x in range(0, {end}, {step})] }}}}"),
+            format!("{{{{ [x for x in 0..{end} if x < 0] }}}}"),
+        ] {
+            render(&input);
+            optimize(&input);
+        }
+    }
+
+    /// Huge and negative bounds used to overflow the index arithmetic.
+    #[test]
+    fn extreme_subscripts_never_panic(
+        numbers in prop::collection::vec(-1e30..1e30f64, 3),
+        integers in prop::collection::vec(any::<i64>(), 3),
+    ) {
+        let [a, b, c] = [numbers[0].trunc(), numbers[1].trunc(), numbers[2].trunc()];
+        let [i, j, k] = [integers[0], integers[1], integers[2]];
+        for sequence in ["[1, 2, 3]", "'abc'"] {
+            render(&format!("{{{{ {sequence}[{a}] }}}}{{{{ {sequence}[{a}:{b}:{c}] }}}}"));
+            render(&format!("{{{{ {sequence}[{i}] }}}}{{{{ {sequence}[{i}:{j}:{k}] }}}}"));
+        }
+        render(&format!("{{{{ (0..{a})[{b}] }}}}{{{{ {c} in range({a}, {b}, {c}) }}}}"));
+        render(&format!("{{{{ (0..{i})[{j}] }}}}{{{{ range({i}, {j}, {k})[{a}] }}}}"));
+    }
+}